@@ -6,25 +6,36 @@
 
 #[macro_use]
 extern crate log;
-#[macro_use]
 extern crate alloc;
 #[macro_use]
 extern crate bitfield_struct;
 
-use alloc::{collections::VecDeque, string::String, vec::Vec};
+use alloc::{collections::VecDeque, string::String, sync::Arc};
+use core::time::Duration;
 
 use fireworkkit::{
+    dma::{CacheType, DmaBuffer},
+    endpoint::Endpoint,
     msg::Message,
-    osdtentry::{OSDTEntry, FKEXT_PROC_KEY},
+    osdtentry::{OSDTEntry, INTERRUPTS_KEY},
     osvalue::OSValue,
-    syscall::SystemCall,
-    userspace::port::Port,
+    syscall::{IRQMode, SystemCall},
+    userspace::{port::Port, thread},
 };
 use hashbrown::HashMap;
 use pcikit::{PCIAddress, PCICfgOffset, PCICommand, PCIDevice};
 
 mod regs;
 
+const CHUNK_SIZE: usize = 0xFFFE * 2;
+const MAX_QUEUED: usize = CHUNK_SIZE * 4;
+
+#[derive(Default)]
+struct Stream {
+    data: VecDeque<u8>,
+    finished: bool,
+}
+
 struct AC97 {
     pub _mixer: Port<u16, u16>,
     pub _audio_bus: Port<u32, u32>,
@@ -32,16 +43,21 @@ struct AC97 {
     pcm_out_bdl_addr: Port<u32, u32>,
     pcm_out_transf_ctl: Port<u8, regs::RegBoxTransfer>,
     pub pcm_out_transf_status: Port<u16, u16>,
-    buf: VecDeque<u8>,
-    bdl: Vec<regs::BufferDescriptor>,
+    stream: Arc<spin::Mutex<Stream>>,
+    buf: DmaBuffer,
+    bdl: DmaBuffer,
     playing: bool,
 }
 
 impl AC97 {
     #[inline]
     #[must_use]
-    pub fn new(dev: &PCIDevice) -> Self {
-        let irq: u8 = unsafe {
+    pub fn new(
+        dev: &PCIDevice,
+        irq: Option<(u32, IRQMode)>,
+        stream: Arc<spin::Mutex<Stream>>,
+    ) -> Self {
+        let line: u8 = unsafe {
             dev.cfg_write16(
                 PCICfgOffset::Command,
                 dev.cfg_read16::<_, PCICommand>(PCICfgOffset::Command)
@@ -52,8 +68,14 @@ impl AC97 {
             );
             dev.cfg_read8(PCICfgOffset::InterruptLine)
         };
-        debug!("IRQ: {irq:#X?}");
-        unsafe { SystemCall::register_irq_handler(irq) }
+        // The interrupt line register only holds the legacy PIC line, prefer what the `_PRT` says.
+        if let Some((gsi, mode)) = irq {
+            debug!("GSI: {gsi} ({mode:?})");
+            unsafe { SystemCall::register_gsi_handler(gsi, mode).unwrap() };
+        } else {
+            debug!("IRQ: {line:#X?}");
+            unsafe { SystemCall::register_irq_handler(line).unwrap() }
+        }
         let audio_bus = unsafe { dev.cfg_read16::<_, u16>(PCICfgOffset::BaseAddr1) & !1u16 };
         let pcm_out_bdl_last_ent = Port::new(audio_bus + regs::AudioBusReg::PCMOutLastEnt as u16);
         let pcm_out_bdl_addr = Port::new(audio_bus + regs::AudioBusReg::PCMOutBDLAddr as u16);
@@ -98,14 +120,30 @@ impl AC97 {
             mixer.write_off(48000u16, regs::MixerReg::SampleRate);
         }
 
-        let buf = VecDeque::with_capacity(4);
-        let bdl = vec![regs::BufferDescriptor {
-            samples: 0xFFFE,
-            ctl: regs::BufferDescCtl::new()
-                .with_last(true)
-                .with_fire_interrupt(true),
-            ..Default::default()
-        }];
+        // The controller only takes 32-bit physical addresses.
+        let (buf, mut bdl) = unsafe {
+            (
+                DmaBuffer::new(CHUNK_SIZE, u32::MAX.into(), 0, CacheType::WriteCombining).unwrap(),
+                DmaBuffer::new(
+                    core::mem::size_of::<regs::BufferDescriptor>(),
+                    u32::MAX.into(),
+                    0,
+                    CacheType::WriteBack,
+                )
+                .unwrap(),
+            )
+        };
+        unsafe {
+            bdl.as_mut_ptr()
+                .cast::<regs::BufferDescriptor>()
+                .write(regs::BufferDescriptor {
+                    addr: buf.phys() as _,
+                    samples: 0xFFFE,
+                    ctl: regs::BufferDescCtl::new()
+                        .with_last(true)
+                        .with_fire_interrupt(true),
+                });
+        }
 
         Self {
             _mixer: mixer,
@@ -114,6 +152,7 @@ impl AC97 {
             pcm_out_bdl_last_ent,
             pcm_out_transf_ctl,
             pcm_out_transf_status,
+            stream,
             buf,
             bdl,
             playing: false,
@@ -124,18 +163,14 @@ impl AC97 {
         self.pcm_out_transf_ctl
             .write(self.pcm_out_transf_ctl.read().with_reset(true));
         while self.pcm_out_transf_ctl.read().reset() {
-            SystemCall::r#yield();
+            thread::sleep(Duration::from_millis(1));
         }
         self.pcm_out_transf_ctl
             .write(self.pcm_out_transf_ctl.read().with_last_ent_fire_intr(true));
     }
 
     pub unsafe fn set_bdl(&mut self) {
-        self.buf.make_contiguous();
-        self.bdl[0].addr =
-            (self.buf.as_slices().0.as_ptr() as u64 - fireworkkit::USER_VIRT_OFFSET) as _;
-        self.pcm_out_bdl_addr
-            .write((self.bdl.as_ptr() as u64 - fireworkkit::USER_VIRT_OFFSET) as _);
+        self.pcm_out_bdl_addr.write(self.bdl.phys() as _);
         self.pcm_out_bdl_last_ent.write(0);
     }
 
@@ -144,8 +179,22 @@ impl AC97 {
             .write(self.pcm_out_transf_ctl.read().with_transfer_data(true));
     }
 
-    pub fn start_playback(&mut self) {
-        if !self.playing && !self.buf.is_empty() {
+    pub fn fill_buf(&mut self) -> bool {
+        let mut stream = self.stream.lock();
+        if stream.data.is_empty() {
+            return false;
+        }
+
+        let len = stream.data.len().min(CHUNK_SIZE);
+        for (dst, src) in self.buf.iter_mut().zip(stream.data.drain(..len)) {
+            *dst = src;
+        }
+        self.buf[len..].fill(0);
+        true
+    }
+
+    pub fn start_playback(&mut self) -> bool {
+        if !self.playing && self.fill_buf() {
             self.playing = true;
 
             unsafe {
@@ -154,25 +203,51 @@ impl AC97 {
                 self.begin_transfer();
             }
         }
+        self.playing
     }
 
-    pub fn play_audio(&mut self, data: &[u8]) {
-        self.buf.extend(data.iter().copied());
-        self.start_playback();
+    pub fn next_buffer(&mut self) {
+        unsafe { self.pcm_out_transf_status.write(0x1C) }
+
+        if !self.fill_buf() {
+            self.playing = false;
+            unsafe { self.reset() }
+            return;
+        }
+
+        unsafe {
+            self.reset();
+            self.set_bdl();
+            self.begin_transfer();
+        }
     }
 }
 
+fn feed(stream: &spin::Mutex<Stream>, data: &[u8]) {
+    for chunk in data.chunks(CHUNK_SIZE) {
+        loop {
+            let mut stream = stream.lock();
+            if stream.data.len() < MAX_QUEUED {
+                stream.data.extend(chunk);
+                break;
+            }
+            drop(stream);
+            // A chunk plays for well over half a second, there's no need to check more often than this.
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+    stream.lock().finished = true;
+}
+
 #[no_mangle]
 extern "C" fn _start(instance: OSDTEntry) -> ! {
     fireworkkit::userspace::logger::init();
 
-    let ent = instance.parent().unwrap();
-    let mut addr = ent.get_property("Address");
+    let ent = instance.parent().unwrap().unwrap();
+    let mut addr = ent.get_property("Address").unwrap();
     while addr.is_none() {
-        addr = ent.get_property("Address");
-        unsafe {
-            SystemCall::r#yield();
-        }
+        addr = ent.get_property("Address").unwrap();
+        thread::sleep(Duration::from_millis(1));
     }
     let addr: HashMap<String, OSValue> = addr.unwrap().try_into().unwrap();
     let addr: PCIAddress = {
@@ -182,41 +257,38 @@ extern "C" fn _start(instance: OSDTEntry) -> ! {
         let func: u8 = addr.get("Function").cloned().unwrap().try_into().unwrap();
         PCIAddress::new(segment, bus, slot, func)
     };
-    let pid: u64 = ent
-        .parent()
-        .unwrap()
-        .get_property(FKEXT_PROC_KEY)
-        .unwrap()
-        .try_into()
-        .unwrap();
-
-    let dev = PCIDevice::new(pid, addr);
-    let mut this = AC97::new(&dev);
-    this.play_audio(include_bytes!("test.dat"));
+    let irq = match ent.get_property(INTERRUPTS_KEY).unwrap() {
+        Some(OSValue::Vec(v)) => v
+            .first()
+            .and_then(|v| <(&u32, &u64)>::try_from(v).ok())
+            .and_then(|(&gsi, &mode)| Some((gsi, IRQMode::try_from(mode).ok()?))),
+        _ => None,
+    };
+    let pcikit = unsafe { Endpoint::lookup(pcikit::ENDPOINT_NAME) }.unwrap();
+
+    let dev = PCIDevice::new(pcikit, addr);
+    let stream = Arc::new(spin::Mutex::new(Stream::default()));
+    let mut this = AC97::new(&dev, irq, stream.clone());
+    let mut feeder = Some(thread::spawn(move || {
+        feed(&stream, include_bytes!("test.dat"));
+    }));
 
     loop {
-        let msg = unsafe { Message::recv() };
-        if msg.pid != 0 {
-            continue;
+        if !this.start_playback() {
+            if !this.stream.lock().finished {
+                unsafe { SystemCall::r#yield() }
+                continue;
+            }
+            if let Some(feeder) = feeder.take() {
+                feeder.join();
+            }
         }
 
-        if this.buf.is_empty() || !this.playing {
-            this.playing = false;
-            unsafe {
-                this.reset();
-            }
+        let msg = unsafe { Message::recv() };
+        if msg.pid != 0 || !this.playing {
             continue;
         }
 
-        if this.buf.len() < 0xFFFE * 2 {
-            this.buf.resize(0xFFFE * 2, 0);
-        }
-        this.buf.drain(0..0xFFFE * 2);
-        unsafe {
-            this.pcm_out_transf_status.write(0x1C);
-            this.reset();
-            this.set_bdl();
-            this.begin_transfer();
-        }
+        this.next_buffer();
     }
 }
@@ -6,7 +6,6 @@
 
 // #[macro_use]
 // extern crate log;
-#[macro_use]
 extern crate alloc;
 #[macro_use]
 extern crate bitfield_struct;
@@ -15,10 +14,11 @@ use alloc::string::String;
 use core::fmt::Write;
 
 use fireworkkit::{
-    msg::Message,
-    osdtentry::{OSDTEntry, FKEXT_PROC_KEY, OSDTENTRY_NAME_KEY},
+    endpoint::Endpoint,
+    msg::{KernelMessage, Message},
+    osdtentry::{OSDTEntry, OSDTENTRY_NAME_KEY},
     osvalue::OSValue,
-    syscall::SystemCall,
+    syscall::{PowerAction, SystemCall},
     userspace::{logger::KWriter, port::Port},
 };
 use num_enum::IntoPrimitive;
@@ -105,7 +105,7 @@ impl PS2Ctl {
                 .with_port2_intr(false)
                 .with_port1_translation(true)
         };
-        unsafe { SystemCall::register_irq_handler(1) }
+        unsafe { SystemCall::register_irq_handler(1).unwrap() }
         self.send_cmd(PS2CtlCmd::WriteControllerCfg, false);
         unsafe { self.data_port.write(cfg.into()) }
         while self.input_full() {}
@@ -116,7 +116,9 @@ fn print_ent(ent: OSDTEntry, ident: usize) {
     let spacing = " ".repeat(ident);
 
     let id: u64 = ent.into();
-    let props = ent.properties();
+    let Ok(props) = ent.properties() else {
+        return;
+    };
     writeln!(
         KWriter,
         "{spacing}+ {} <{}>",
@@ -133,13 +135,13 @@ fn print_ent(ent: OSDTEntry, ident: usize) {
         writeln!(KWriter, "{spacing}|- {k}: {v:X?}").unwrap();
     }
 
-    for child in ent.children() {
+    for child in ent.children().unwrap_or_default() {
         print_ent(child, ident + 2);
     }
 }
 
 #[no_mangle]
-extern "C" fn _start(instance: OSDTEntry) -> ! {
+extern "C" fn _start(_instance: OSDTEntry) -> ! {
     fireworkkit::userspace::logger::init();
 
     let this = PS2Ctl::new();
@@ -151,6 +153,15 @@ extern "C" fn _start(instance: OSDTEntry) -> ! {
         if msg.pid != 0 {
             continue;
         }
+        if matches!(
+            postcard::from_bytes(msg.data),
+            Ok(KernelMessage::PowerButton)
+        ) {
+            if let Err(e) = unsafe { SystemCall::power(PowerAction::Shutdown) } {
+                writeln!(KWriter, "Failed to shutdown: {e:?}").unwrap();
+            }
+            continue;
+        }
 
         while this.output_full() {
             let event = match unsafe { this.data_port.read() } {
@@ -185,42 +196,40 @@ extern "C" fn _start(instance: OSDTEntry) -> ! {
 
             match s.as_str() {
                 "osdt" => print_ent(OSDTEntry::default(), 0),
-                "msgparent" => {
-                    let pid: u64 = instance
-                        .parent()
-                        .unwrap()
-                        .parent()
-                        .unwrap()
-                        .get_property(FKEXT_PROC_KEY)
-                        .unwrap()
-                        .try_into()
-                        .unwrap();
-
-                    unsafe {
-                        Message::new(pid, vec![1, 2, 3, 4].leak()).send();
-                    }
-                }
                 "accessinvalid" => unsafe {
-                    core::arch::asm!(
-                        "int 249",
-                        in("rdi") SystemCall::KPrint as u64,
-                        in("rsi") 0u64,
-                        in("rdx") 0u64,
-                        options(nostack),
-                    );
+                    SystemCall::KPrint.invoke(0, 0, 0, 0);
                 },
+                v @ ("shutdown" | "reboot") => {
+                    let action = if v == "shutdown" {
+                        PowerAction::Shutdown
+                    } else {
+                        PowerAction::Reboot
+                    };
+                    if let Err(e) = unsafe { SystemCall::power(action) } {
+                        writeln!(KWriter, "Failed to {v}: {e:?}").unwrap();
+                    }
+                }
                 v if v.split_whitespace().next() == Some("msg") => 'a: {
                     let mut v = v.split_whitespace().skip(1);
-                    let Some(pid) = v.next().and_then(|v| v.parse().ok()) else {
-                        writeln!(KWriter, "Expected PID").unwrap();
+                    let Some(name) = v.next() else {
+                        writeln!(KWriter, "Expected endpoint name").unwrap();
                         break 'a;
                     };
+                    let endpoint = match unsafe { Endpoint::lookup(name) } {
+                        Ok(v) => v,
+                        Err(e) => {
+                            writeln!(KWriter, "Failed to look up endpoint: {e:?}").unwrap();
+                            break 'a;
+                        }
+                    };
                     let Some(data) = v.next().and_then(|v| v.parse::<u64>().ok()) else {
                         writeln!(KWriter, "Expected data").unwrap();
                         break 'a;
                     };
-                    unsafe {
-                        Message::new(pid, data.to_be_bytes().to_vec().leak()).send();
+                    if let Err(e) =
+                        unsafe { endpoint.send(data.to_be_bytes().to_vec().leak(), None) }
+                    {
+                        writeln!(KWriter, "Failed to send message: {e:?}").unwrap();
                     }
                 }
                 _ => writeln!(KWriter, "{s}").unwrap(),
@@ -4,15 +4,18 @@
 #![deny(warnings, clippy::cargo, clippy::nursery, unused_extern_crates)]
 #![allow(clippy::missing_safety_doc)]
 
+use fireworkkit::endpoint::Endpoint;
 #[cfg(feature = "ext")]
-use fireworkkit::msg::Message;
+use fireworkkit::{msg::Message, Error};
 use num_enum::IntoPrimitive;
 use serde::{Deserialize, Serialize};
 
 #[macro_use]
 extern crate bitfield_struct;
 
-#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize)]
+pub const ENDPOINT_NAME: &str = "com.ChefKiss.PCIKit";
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub struct PCIAddress {
     pub segment: u16,
     pub bus: u8,
@@ -82,6 +85,49 @@ pub enum PCICfgOffset {
     MaximumLatency = 0x3F,
 }
 
+pub const PCI_CAP_MSI: u8 = 0x05;
+pub const PCI_CAP_MSIX: u8 = 0x11;
+
+// The list is bounded, so a malformed one that loops can't hang the caller.
+pub fn find_capability(read8: impl Fn(u8) -> u8, id: u8) -> Option<u8> {
+    if read8(PCICfgOffset::Status as u8) & (1 << 4) == 0 {
+        return None;
+    }
+    let mut off = read8(PCICfgOffset::CapabilitiesPtr as u8) & !0b11;
+    for _ in 0..48 {
+        if off == 0 {
+            return None;
+        }
+        if read8(off) == id {
+            return Some(off);
+        }
+        off = read8(off + 1) & !0b11;
+    }
+    None
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct MSIXTable {
+    pub cap: u8,
+    pub bar: u8,
+    pub offset: u32,
+    pub size: u16,
+}
+
+impl MSIXTable {
+    // `base` is where the BAR holding the table is mapped.
+    pub unsafe fn set_entry(&self, base: *mut u8, index: u16, addr: u64, data: u32) {
+        assert!(index < self.size);
+        let ent = base
+            .add(self.offset as usize + usize::from(index) * 16)
+            .cast::<u32>();
+        ent.write_volatile(addr as u32);
+        ent.add(1).write_volatile((addr >> 32) as u32);
+        ent.add(2).write_volatile(data);
+        ent.add(3).write_volatile(0);
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub enum PCIRequest {
     Read8(PCIAddress, u8),
@@ -94,22 +140,26 @@ pub enum PCIRequest {
 
 #[cfg(feature = "ext")]
 impl PCIRequest {
-    pub unsafe fn send(self, pid: u64) {
-        Message::new(pid, postcard::to_allocvec(&self).unwrap().leak()).send();
+    pub unsafe fn send(self, endpoint: Endpoint) -> Result<(), Error> {
+        endpoint.send(postcard::to_allocvec(&self).unwrap().leak(), None)
+    }
+
+    pub unsafe fn call(self, endpoint: Endpoint) -> Result<Message, Error> {
+        endpoint.call(postcard::to_allocvec(&self).unwrap().leak(), None)
     }
 }
 
-#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 pub struct PCIDevice {
-    pid: u64,
+    endpoint: Endpoint,
     addr: PCIAddress,
 }
 
 impl PCIDevice {
     #[must_use]
     #[inline]
-    pub const fn new(pid: u64, addr: PCIAddress) -> Self {
-        Self { pid, addr }
+    pub const fn new(endpoint: Endpoint, addr: PCIAddress) -> Self {
+        Self { endpoint, addr }
     }
 }
 
@@ -122,31 +172,90 @@ impl PCIDevice {
 
     #[must_use]
     pub unsafe fn cfg_read8<A: Into<u8>, R: From<u8>>(&self, off: A) -> R {
-        PCIRequest::Read8(self.addr, off.into()).send(self.pid);
-        Message::recv().data[0].into()
+        PCIRequest::Read8(self.addr, off.into())
+            .call(self.endpoint)
+            .unwrap()
+            .data[0]
+            .into()
     }
 
     #[must_use]
     pub unsafe fn cfg_read16<A: Into<u8>, R: From<u16>>(&self, off: A) -> R {
-        PCIRequest::Read16(self.addr, off.into()).send(self.pid);
-        u16::from_le_bytes(Message::recv().data.try_into().unwrap()).into()
+        let reply = PCIRequest::Read16(self.addr, off.into())
+            .call(self.endpoint)
+            .unwrap();
+        u16::from_le_bytes(reply.data.try_into().unwrap()).into()
     }
 
     #[must_use]
     pub unsafe fn cfg_read32<A: Into<u8>, R: From<u32>>(&self, off: A) -> R {
-        PCIRequest::Read32(self.addr, off.into()).send(self.pid);
-        u32::from_le_bytes(Message::recv().data.try_into().unwrap()).into()
+        let reply = PCIRequest::Read32(self.addr, off.into())
+            .call(self.endpoint)
+            .unwrap();
+        u32::from_le_bytes(reply.data.try_into().unwrap()).into()
     }
 
     pub unsafe fn cfg_write8<A: Into<u8>, R: Into<u8>>(&self, off: A, value: R) {
-        PCIRequest::Write8(self.addr, off.into(), value.into()).send(self.pid);
+        PCIRequest::Write8(self.addr, off.into(), value.into())
+            .send(self.endpoint)
+            .unwrap();
     }
 
     pub unsafe fn cfg_write16<A: Into<u8>, R: Into<u16>>(&self, off: A, value: R) {
-        PCIRequest::Write16(self.addr, off.into(), value.into()).send(self.pid);
+        PCIRequest::Write16(self.addr, off.into(), value.into())
+            .send(self.endpoint)
+            .unwrap();
     }
 
     pub unsafe fn cfg_write32<A: Into<u8>, R: Into<u32>>(&self, off: A, value: R) {
-        PCIRequest::Write32(self.addr, off.into(), value.into()).send(self.pid);
+        PCIRequest::Write32(self.addr, off.into(), value.into())
+            .send(self.endpoint)
+            .unwrap();
+    }
+
+    #[must_use]
+    pub unsafe fn capability(&self, id: u8) -> Option<u8> {
+        find_capability(|off| unsafe { self.cfg_read8(off) }, id)
+    }
+
+    unsafe fn disable_intx(&self) {
+        let cmd = PCICommand::from(self.cfg_read16::<_, u16>(PCICfgOffset::Command));
+        self.cfg_write16(PCICfgOffset::Command, cmd.with_disable_intrs(true));
+    }
+
+    // Only a single message is enabled, multiple message MSI needs aligned vector blocks.
+    pub unsafe fn enable_msi(&self, addr: u64, data: u32) -> Result<(), Error> {
+        let cap = self.capability(PCI_CAP_MSI).ok_or(Error::Unsupported)?;
+        let ctl = self.cfg_read16::<_, u16>(cap + 2);
+        self.cfg_write32(cap + 4, addr as u32);
+        if ctl & (1 << 7) == 0 {
+            self.cfg_write16(cap + 8, data as u16);
+        } else {
+            self.cfg_write32(cap + 8, (addr >> 32) as u32);
+            self.cfg_write16(cap + 12, data as u16);
+        }
+        self.cfg_write16(cap + 2, (ctl & !(0b111 << 4)) | 1);
+        self.disable_intx();
+        Ok(())
+    }
+
+    #[must_use]
+    pub unsafe fn msix_table(&self) -> Option<MSIXTable> {
+        let cap = self.capability(PCI_CAP_MSIX)?;
+        let ctl = self.cfg_read16::<_, u16>(cap + 2);
+        let table = self.cfg_read32::<_, u32>(cap + 4);
+        Some(MSIXTable {
+            cap,
+            bar: (table & 0b111) as u8,
+            offset: table & !0b111,
+            size: (ctl & 0x7FF) + 1,
+        })
+    }
+
+    // Entries have to be programmed through `MSIXTable::set_entry` first.
+    pub unsafe fn enable_msix(&self, table: &MSIXTable) {
+        let ctl = self.cfg_read16::<_, u16>(table.cap + 2);
+        self.cfg_write16(table.cap + 2, (ctl | (1 << 15)) & !(1 << 14));
+        self.disable_intx();
     }
 }
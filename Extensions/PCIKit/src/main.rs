@@ -11,11 +11,20 @@ extern crate alloc;
 #[macro_use]
 extern crate itertools;
 
-use alloc::{boxed::Box, string::String};
+use alloc::{boxed::Box, string::String, vec::Vec};
 
-use fireworkkit::{msg::Message, osdtentry::OSDTEntry, osvalue::OSValue, userspace::port::Port};
+use fireworkkit::{
+    endpoint::Endpoint,
+    msg::Message,
+    osdtentry::{
+        OSDTEntry, FKEXT_PROC_KEY, INTERRUPTS_KEY, MMIO_RANGES_KEY, PCI_ROUTES_KEY, PORT_RANGES_KEY,
+    },
+    osvalue::OSValue,
+    syscall::IRQMode,
+    userspace::port::Port,
+};
 use hashbrown::HashMap;
-use pcikit::{PCIAddress, PCICfgOffset, PCIRequest};
+use pcikit::{PCIAddress, PCICfgOffset, PCICommand, PCIRequest, PCI_CAP_MSI, PCI_CAP_MSIX};
 
 trait PCIControllerIO: Sync {
     unsafe fn read8(&self, addr: PCIAddress, off: u8) -> u8;
@@ -115,11 +124,99 @@ impl PCIControllerIO for PCIPortIO {
     }
 }
 
+// Decoding is turned off while sizing, so the probe value never ends up on the bus.
+fn bars(controller: &PCIController, addr: PCIAddress) -> (Vec<OSValue>, Vec<OSValue>) {
+    let (mut mmio, mut ports) = (Vec::new(), Vec::new());
+    if controller.read8(addr, PCICfgOffset::HeaderType as u8) & 0x7F != 0 {
+        return (mmio, ports);
+    }
+
+    let cmd = controller.read16(addr, PCICfgOffset::Command as u8);
+    let disabled = PCICommand::from(cmd).with_pio(false).with_mmio(false);
+    controller.write16(addr, PCICfgOffset::Command as u8, disabled.into());
+
+    let mut off = PCICfgOffset::BaseAddr0 as u8;
+    while off <= PCICfgOffset::BaseAddr5 as u8 {
+        let bar = controller.read32(addr, off);
+        controller.write32(addr, off, !0);
+        let probe = controller.read32(addr, off);
+        controller.write32(addr, off, bar);
+
+        if bar & 1 != 0 {
+            let base = u64::from(bar & !0b11);
+            if base != 0 {
+                ports.push((base, u64::from(!(probe & !0b11) & 0xFFFF) + 1).into());
+            }
+            off += 4;
+            continue;
+        }
+
+        let is_64bit = bar & 0b110 == 0b100;
+        let mut base = u64::from(bar & !0xF);
+        let mut mask = u64::from(probe & !0xF) | (0xFFFF_FFFF << 32);
+        if is_64bit {
+            let high = controller.read32(addr, off + 4);
+            controller.write32(addr, off + 4, !0);
+            mask = (mask & 0xFFFF_FFFF) | (u64::from(controller.read32(addr, off + 4)) << 32);
+            controller.write32(addr, off + 4, high);
+            base |= u64::from(high) << 32;
+        }
+        if base != 0 {
+            mmio.push((base, !mask + 1).into());
+        }
+        off += if is_64bit { 8 } else { 4 };
+    }
+
+    controller.write16(addr, PCICfgOffset::Command as u8, cmd);
+    (mmio, ports)
+}
+
+// The root bridge's INTx routing as evaluated from ACPI by the kernel, it only covers devices on its own bus.
+fn pci_routes(instance: &OSDTEntry) -> (u8, Vec<HashMap<String, OSValue>>) {
+    let mut pending = instance
+        .parent()
+        .ok()
+        .flatten()
+        .map_or_else(Vec::new, |v| v.children().unwrap_or_default());
+    while let Some(ent) = pending.pop() {
+        let Ok(props) = ent.properties() else {
+            continue;
+        };
+        if let Some(OSValue::Vec(routes)) = props.get(PCI_ROUTES_KEY) {
+            let bus = props
+                .get("_BBN")
+                .and_then(|v| <&u64>::try_from(v).ok())
+                .map_or(0, |&v| v as u8);
+            return (
+                bus,
+                routes
+                    .iter()
+                    .filter_map(|v| v.clone().try_into().ok())
+                    .collect(),
+            );
+        }
+        pending.extend(ent.children().unwrap_or_default());
+    }
+    (0, Vec::new())
+}
+
+// The kernel records the process of a driver on the entry it attaches below the device.
+fn is_owner(devices: &[(PCIAddress, OSDTEntry)], addr: PCIAddress, pid: u64) -> bool {
+    devices
+        .iter()
+        .filter(|(v, _)| *v == addr)
+        .flat_map(|(_, ent)| ent.children().unwrap_or_default())
+        .any(|v| v.get_property(FKEXT_PROC_KEY).ok().flatten() == Some(pid.into()))
+}
+
 #[no_mangle]
 extern "C" fn _start(instance: OSDTEntry) -> ! {
     fireworkkit::userspace::logger::init();
 
+    let _endpoint = unsafe { Endpoint::create(Some(pcikit::ENDPOINT_NAME)) }.unwrap();
     let controller = Box::new(PCIController);
+    let (routed_bus, routes) = pci_routes(&instance);
+    let mut devices = Vec::new();
     for (bus, slot) in iproduct!(0..=255, 0..32) {
         for func in 0..8 {
             let addr = PCIAddress::new(0, bus, slot, func);
@@ -135,7 +232,34 @@ extern "C" fn _start(instance: OSDTEntry) -> ! {
 
             let device_id = controller.read16(addr, PCICfgOffset::DeviceID as u8);
             let class_code = controller.read16(addr, PCICfgOffset::ClassCode as u8);
+            let (mmio, ports) = bars(&controller, addr);
+            let caps: Vec<_> = [("MSI", PCI_CAP_MSI), ("MSIX", PCI_CAP_MSIX)]
+                .into_iter()
+                .filter_map(|(name, id)| {
+                    pcikit::find_capability(|v| controller.read8(addr, v), id).map(|v| (name, v))
+                })
+                .collect();
+            let pin = controller.read8(addr, PCICfgOffset::InterruptPin as u8);
+            let irq = routes
+                .iter()
+                .filter(|_| bus == routed_bus && pin != 0)
+                .find(|v| {
+                    v.get("Slot") == Some(&slot.into()) && v.get("Pin") == Some(&(pin - 1).into())
+                })
+                .and_then(|v| {
+                    Some(OSValue::from((
+                        v.get("GSI")?.clone(),
+                        v.get("Mode")?.clone(),
+                    )))
+                })
+                .or_else(|| {
+                    // Without a `_PRT` entry, all there is to go by is what the firmware put in the interrupt line.
+                    let line = controller.read8(addr, PCICfgOffset::InterruptLine as u8);
+                    (pin != 0 && line != 0xFF)
+                        .then(|| OSValue::from((u32::from(line), IRQMode::Legacy as u64)))
+                });
 
+            let pci_addr = addr;
             let addr: HashMap<String, OSValue> = HashMap::from([
                 ("Segment".into(), 0u16.into()),
                 ("Bus".into(), bus.into()),
@@ -143,11 +267,21 @@ extern "C" fn _start(instance: OSDTEntry) -> ! {
                 ("Function".into(), func.into()),
             ]);
 
-            let ent = instance.new_child(None);
-            ent.set_property("VendorID", vendor_id.into());
-            ent.set_property("DeviceID", device_id.into());
-            ent.set_property("ClassCode", class_code.into());
-            ent.set_property("Address", addr.into());
+            let ent = instance.new_child(None).unwrap();
+            // Published first, as setting the other properties can already match a driver.
+            ent.set_property(MMIO_RANGES_KEY, mmio.into()).unwrap();
+            ent.set_property(PORT_RANGES_KEY, ports.into()).unwrap();
+            if let Some(irq) = irq {
+                ent.set_property(INTERRUPTS_KEY, vec![irq].into()).unwrap();
+            }
+            for (name, off) in caps {
+                ent.set_property(name, off.into()).unwrap();
+            }
+            ent.set_property("VendorID", vendor_id.into()).unwrap();
+            ent.set_property("DeviceID", device_id.into()).unwrap();
+            ent.set_property("ClassCode", class_code.into()).unwrap();
+            ent.set_property("Address", addr.into()).unwrap();
+            devices.push((pci_addr, ent));
 
             if !multifunction {
                 break;
@@ -164,6 +298,15 @@ extern "C" fn _start(instance: OSDTEntry) -> ! {
         let Ok(req) = postcard::from_bytes::<PCIRequest>(msg.data) else {
             continue;
         };
+        // Anyone may read configuration space, only the driver of a device may write to it.
+        if let PCIRequest::Write8(addr, ..)
+        | PCIRequest::Write16(addr, ..)
+        | PCIRequest::Write32(addr, ..) = req
+        {
+            if !is_owner(&devices, addr, msg.pid) {
+                continue;
+            }
+        }
         let data = match req {
             PCIRequest::Read8(addr, off) => vec![controller.read8(addr, off)],
             PCIRequest::Read16(addr, off) => controller.read16(addr, off).to_le_bytes().to_vec(),
@@ -181,8 +324,7 @@ extern "C" fn _start(instance: OSDTEntry) -> ! {
                 continue;
             }
         };
-        unsafe {
-            Message::new(msg.pid, data.leak()).send();
-        }
+        // Requests that were sent rather than called have nobody to reply to.
+        let _ = unsafe { msg.reply(data.leak(), None) };
     }
 }
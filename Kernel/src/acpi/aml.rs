@@ -0,0 +1,128 @@
+// Copyright (c) ChefKiss Inc 2021-2023. Licensed under the Thou Shalt Not Profit License version 1.5. See LICENSE for details.
+
+use amd64::{
+    io::port::PortIO,
+    paging::{PageTableFlags, PAGE_SIZE, PHYS_VIRT_OFFSET},
+};
+
+use super::tables::SDTHeader;
+use crate::timer::Timer;
+
+pub use aml::{name, resource, value, AMLError, Interpreter};
+
+pub struct KernelHandler;
+
+impl aml::Handler for KernelHandler {
+    fn memory_access(&mut self, addr: u64, width: u64, v: Option<u64>) -> u64 {
+        unsafe {
+            let state = &*crate::system::state::SYS_STATE.get();
+            let page = addr & !(PAGE_SIZE - 1);
+            state.pml4.as_ref().unwrap().lock().map_mmio(
+                page + PHYS_VIRT_OFFSET,
+                page,
+                (addr + width - page).div_ceil(PAGE_SIZE),
+                PageTableFlags::new_present().with_writable(true),
+            );
+            let ptr = addr + PHYS_VIRT_OFFSET;
+            match (width, v) {
+                (1, None) => (ptr as *const u8).read_volatile().into(),
+                (2, None) => (ptr as *const u16).read_volatile().into(),
+                (4, None) => (ptr as *const u32).read_volatile().into(),
+                (_, None) => (ptr as *const u64).read_volatile(),
+                (1, Some(v)) => {
+                    (ptr as *mut u8).write_volatile(v as _);
+                    0
+                }
+                (2, Some(v)) => {
+                    (ptr as *mut u16).write_volatile(v as _);
+                    0
+                }
+                (4, Some(v)) => {
+                    (ptr as *mut u32).write_volatile(v as _);
+                    0
+                }
+                (_, Some(v)) => {
+                    (ptr as *mut u64).write_volatile(v);
+                    0
+                }
+            }
+        }
+    }
+
+    fn port_access(&mut self, port: u16, width: u64, v: Option<u64>) -> u64 {
+        unsafe {
+            match (width, v) {
+                (1, None) => u8::read(port).into(),
+                (2, None) => u16::read(port).into(),
+                (1, Some(v)) => {
+                    u8::write(port, v as _);
+                    0
+                }
+                (2, Some(v)) => {
+                    u16::write(port, v as _);
+                    0
+                }
+                (4, Some(v)) => {
+                    u32::write(port, v as _);
+                    0
+                }
+                (4, None) => u32::read(port).into(),
+                // There are no 64-bit port accesses, split them up.
+                (_, None) => u64::from(u32::read(port)) | (u64::from(u32::read(port + 4)) << 32),
+                (_, Some(v)) => {
+                    u32::write(port, v as _);
+                    u32::write(port + 4, (v >> 32) as _);
+                    0
+                }
+            }
+        }
+    }
+
+    fn pci_access(
+        &mut self,
+        bus: u8,
+        slot: u8,
+        func: u8,
+        offset: u8,
+        width: u64,
+        v: Option<u64>,
+    ) -> u64 {
+        if width == 8 {
+            let lo = self.pci_access(bus, slot, func, offset, 4, v);
+            let hi = self.pci_access(bus, slot, func, offset + 4, 4, v.map(|v| v >> 32));
+            return lo | (hi << 32);
+        }
+        let addr = crate::system::pci::config_address(bus, slot, func, offset);
+        unsafe { crate::system::pci::config_access(addr, width, v) }
+    }
+
+    fn sleep(&mut self, ms: u64) {
+        let state = unsafe { &*crate::system::state::SYS_STATE.get() };
+        state.hpet.as_ref().unwrap().sleep(ms);
+    }
+
+    fn stall(&mut self, us: u64) {
+        let state = unsafe { &*crate::system::state::SYS_STATE.get() };
+        let hpet = state.hpet.as_ref().unwrap();
+        let target = hpet.time_ns() + us * 1000;
+        while hpet.time_ns() < target {
+            core::hint::spin_loop();
+        }
+    }
+
+    fn time_ns(&self) -> u64 {
+        let state = unsafe { &*crate::system::state::SYS_STATE.get() };
+        state.hpet.as_ref().unwrap().time_ns()
+    }
+}
+
+pub fn load_table(aml: &mut Interpreter, table: &'static SDTHeader) -> Result<(), AMLError> {
+    let header = core::mem::size_of::<SDTHeader>();
+    let data = unsafe {
+        core::slice::from_raw_parts(
+            (table as *const SDTHeader).cast::<u8>().add(header),
+            table.length() - header,
+        )
+    };
+    aml.load_table(table.signature(), table.revision, data)
+}
@@ -12,6 +12,7 @@ pub mod lvt;
 
 pub struct LocalAPIC {
     addr: u64,
+    timer_ticks: u32,
 }
 
 #[derive(Debug, IntoPrimitive)]
@@ -175,7 +176,10 @@ pub struct LocalAPICVer {
 impl LocalAPIC {
     #[inline]
     pub const fn new(addr: u64) -> Self {
-        Self { addr }
+        Self {
+            addr,
+            timer_ticks: 0,
+        }
     }
 
     pub fn write_reg<T: Into<u64>, V: Into<u32>>(&self, reg: T, value: V) {
@@ -190,6 +194,10 @@ impl LocalAPIC {
         self.read_reg(LocalAPICReg::Ver)
     }
 
+    pub fn id(&self) -> u8 {
+        (self.read_reg::<_, u32>(LocalAPICReg::ID) >> 24) as u8
+    }
+
     pub fn send_eoi(&self) {
         self.write_reg(LocalAPICReg::EndOfInterrupt, 0u32);
     }
@@ -253,7 +261,20 @@ impl LocalAPIC {
         );
     }
 
-    pub fn setup_timer(&self, timer: &impl crate::timer::Timer) {
+    pub fn send_ipi(&self, cmd: InterruptCommand) {
+        let cmd = u64::from(cmd);
+        self.write_reg(LocalAPICReg::InterruptCommand2, (cmd >> 32) as u32);
+        self.write_reg(LocalAPICReg::InterruptCommand, cmd as u32);
+        while InterruptCommand::from(u64::from(
+            self.read_reg::<_, u32>(LocalAPICReg::InterruptCommand),
+        ))
+        .delivery_pending()
+        {
+            core::hint::spin_loop();
+        }
+    }
+
+    pub fn setup_timer(&mut self, timer: &impl crate::timer::Timer) {
         self.set_timer_divide(0x3);
         self.set_timer_init_count(0xFFFF_FFFF);
 
@@ -261,7 +282,11 @@ impl LocalAPIC {
         timer.sleep(10);
         self.write_timer(self.read_timer().with_mask(true));
 
-        let ticks_per_ms = (0xFFFF_FFFF - self.read_timer_counter()) / 10;
+        self.timer_ticks = (0xFFFF_FFFF - self.read_timer_counter()) / 10;
+        self.start_timer();
+    }
+
+    pub fn start_timer(&self) {
         self.write_timer(
             lvt::TimerLVT::new()
                 .with_vector(128)
@@ -269,7 +294,55 @@ impl LocalAPIC {
                 .with_mode(lvt::TimerMode::Periodic),
         );
         self.set_timer_divide(0x3);
-        self.set_timer_init_count(ticks_per_ms);
+        self.set_timer_init_count(self.timer_ticks);
+    }
+
+    pub fn init(&self) {
+        let ver = self.read_ver();
+
+        // Do not trust LAPIC to be empty at boot
+        if ver.max_lvt_entry() > 2 {
+            self.write_reg(
+                LocalAPICReg::LVTError,
+                lvt::LocalVectorTable::new().with_mask(true),
+            );
+        }
+
+        self.write_timer(self.read_timer().with_mask(true));
+        self.write_lint(false, self.read_lint(false).with_mask(true));
+        self.write_lint(true, self.read_lint(true).with_mask(true));
+        if ver.max_lvt_entry() > 3 {
+            self.write_reg(
+                LocalAPICReg::LVTPerfCounter,
+                lvt::LocalVectorTable::new().with_mask(true),
+            );
+        }
+
+        if ver.max_lvt_entry() > 4 {
+            self.write_reg(
+                LocalAPICReg::LVTThermalSensor,
+                lvt::LocalVectorTable::new().with_mask(true),
+            );
+        }
+
+        self.enable();
+
+        // Set up virtual wire
+        self.write_lint(
+            false,
+            lvt::LocalVectorTable::new().with_delivery_mode(DeliveryMode::ExtInt),
+        );
+        self.write_lint(
+            true,
+            lvt::LocalVectorTable::new().with_delivery_mode(DeliveryMode::Nmi),
+        );
+
+        if ver.max_lvt_entry() > 2 {
+            self.write_reg(
+                LocalAPICReg::LVTError,
+                lvt::LocalVectorTable::new().with_vector(0xFE),
+            );
+        }
     }
 }
 
@@ -322,12 +395,7 @@ pub fn setup(state: &mut crate::system::state::SystemState) {
     let ver = lapic.read_ver();
     debug!("LAPIC version is {ver:#X?}");
 
-    // Do not trust LAPIC to be empty at boot
     if ver.max_lvt_entry() > 2 {
-        lapic.write_reg(
-            LocalAPICReg::LVTError,
-            lvt::LocalVectorTable::new().with_mask(true),
-        );
         crate::interrupts::idt::set_handler(
             0xFE,
             0,
@@ -338,25 +406,6 @@ pub fn setup(state: &mut crate::system::state::SystemState) {
         );
     }
 
-    lapic.write_timer(lapic.read_timer().with_mask(true));
-    lapic.write_lint(false, lapic.read_lint(false).with_mask(true));
-    lapic.write_lint(true, lapic.read_lint(true).with_mask(true));
-    if ver.max_lvt_entry() > 3 {
-        lapic.write_reg(
-            LocalAPICReg::LVTPerfCounter,
-            lvt::LocalVectorTable::new().with_mask(true),
-        );
-    }
-
-    if ver.max_lvt_entry() > 4 {
-        lapic.write_reg(
-            LocalAPICReg::LVTThermalSensor,
-            lvt::LocalVectorTable::new().with_mask(true),
-        );
-    }
-
-    lapic.enable();
-
     crate::interrupts::idt::set_handler(
         0xFD,
         0,
@@ -366,22 +415,7 @@ pub fn setup(state: &mut crate::system::state::SystemState) {
         true,
     );
 
-    // Set up virtual wire
-    lapic.write_lint(
-        false,
-        lvt::LocalVectorTable::new().with_delivery_mode(DeliveryMode::ExtInt),
-    );
-    lapic.write_lint(
-        true,
-        lvt::LocalVectorTable::new().with_delivery_mode(DeliveryMode::Nmi),
-    );
-
-    if ver.max_lvt_entry() > 2 {
-        lapic.write_reg(
-            LocalAPICReg::LVTError,
-            lvt::LocalVectorTable::new().with_vector(0xFE),
-        );
-    }
+    lapic.init();
 
     state.lapic = Some(lapic);
 }
@@ -0,0 +1,215 @@
+// Copyright (c) ChefKiss Inc 2021-2023. Licensed under the Thou Shalt Not Profit License version 1.5. See LICENSE for details.
+
+use alloc::{boxed::Box, string::String, vec::Vec};
+
+use fireworkkit::{
+    osdtentry::{
+        INTERRUPTS_KEY, MMIO_RANGES_KEY, OSDTENTRY_NAME_KEY, PCI_ROUTES_KEY, PORT_RANGES_KEY,
+    },
+    osvalue::OSValue,
+    syscall::IRQMode,
+};
+use hashbrown::{HashMap, HashSet};
+
+use super::{
+    aml::{name, resource::Resource, value::Value, Interpreter},
+    ioapic::GSIRoute,
+    tables::fadt::FixedACPIDescTable,
+};
+use crate::system::state::OSDTEntry;
+
+const fn irq_mode(route: GSIRoute) -> IRQMode {
+    match (route.level_triggered, route.active_low) {
+        (false, false) => IRQMode::EdgeHigh,
+        (false, true) => IRQMode::EdgeLow,
+        (true, false) => IRQMode::LevelHigh,
+        (true, true) => IRQMode::LevelLow,
+    }
+}
+
+fn properties(aml: &mut Interpreter, path: &str) -> HashMap<String, OSValue> {
+    let mut props = HashMap::from([
+        (
+            OSDTENTRY_NAME_KEY.into(),
+            name::last_seg(path).trim_end_matches('_').into(),
+        ),
+        ("_Path".into(), path.into()),
+    ]);
+    if let Some(hid) = aml.hardware_id(path) {
+        props.insert("_HID".into(), hid.into());
+    }
+    let cids = aml.compatible_ids(path);
+    if !cids.is_empty() {
+        props.insert(
+            "_CID".into(),
+            cids.into_iter()
+                .map(OSValue::from)
+                .collect::<Vec<_>>()
+                .into(),
+        );
+    }
+    for key in ["_UID", "_ADR", "_BBN"] {
+        match aml.evaluate_child(path, key) {
+            Ok(Some(Value::Integer(v))) => {
+                props.insert(key.into(), v.into());
+            }
+            Ok(Some(Value::String(v))) => {
+                props.insert(key.into(), v.into());
+            }
+            _ => {}
+        }
+    }
+
+    let (mut mmio, mut ports, mut irqs) = (Vec::new(), Vec::new(), Vec::new());
+    match aml.resources(path) {
+        Ok(resources) => {
+            for v in resources {
+                match v {
+                    Resource::Memory { base, len } => mmio.push((base, len).into()),
+                    Resource::Io { base, len } => ports.push((base, len).into()),
+                    Resource::Irq {
+                        lines, isa: true, ..
+                    } => irqs.extend(
+                        lines
+                            .into_iter()
+                            .map(|v| OSValue::from((v, IRQMode::Legacy as u64))),
+                    ),
+                    Resource::Irq {
+                        lines,
+                        level_triggered,
+                        active_low,
+                        ..
+                    } => irqs.extend(lines.into_iter().map(|gsi| {
+                        let mode = irq_mode(GSIRoute {
+                            gsi,
+                            active_low,
+                            level_triggered,
+                        });
+                        OSValue::from((gsi, mode as u64))
+                    })),
+                }
+            }
+        }
+        Err(e) => warn!("AML: Failed to evaluate {path}._CRS: {e:?}"),
+    }
+    for (key, v) in [
+        (MMIO_RANGES_KEY, mmio),
+        (PORT_RANGES_KEY, ports),
+        (INTERRUPTS_KEY, irqs),
+    ] {
+        if !v.is_empty() {
+            props.insert(key.into(), v.into());
+        }
+    }
+
+    if aml.is_root_bridge(path) {
+        match aml.pci_routes(path) {
+            Ok(routes) => {
+                let routes: Vec<_> = routes
+                    .into_iter()
+                    .map(|v| {
+                        let route = GSIRoute {
+                            gsi: if v.isa {
+                                super::ioapic::legacy_irq_route(v.line as u8).gsi
+                            } else {
+                                v.line
+                            },
+                            active_low: v.active_low,
+                            level_triggered: v.level_triggered,
+                        };
+                        OSValue::from(HashMap::from([
+                            ("Slot".into(), v.slot.into()),
+                            ("Pin".into(), v.pin.into()),
+                            ("GSI".into(), route.gsi.into()),
+                            ("Mode".into(), (irq_mode(route) as u64).into()),
+                        ]))
+                    })
+                    .collect();
+                props.insert(PCI_ROUTES_KEY.into(), routes.into());
+            }
+            Err(e) => warn!("AML: Failed to evaluate {path}._PRT: {e:?}"),
+        }
+    }
+    props
+}
+
+// Present devices are published below an `ACPI` entry, nested the same way as in the namespace.
+fn publish(state: &crate::system::state::SystemState, aml: &mut Interpreter) {
+    let mut dt_index = state.dt_index.as_ref().unwrap().write();
+    let mut dt_id_gen = state.dt_id_gen.as_ref().unwrap().lock();
+
+    let root = *dt_index
+        .iter()
+        .find(|(_, v)| v.lock().parent.is_none())
+        .unwrap()
+        .0;
+    let acpi = OSDTEntry {
+        id: dt_id_gen.next(),
+        parent: Some(root.into()),
+        properties: HashMap::from([(OSDTENTRY_NAME_KEY.into(), "ACPI".into())]),
+        ..Default::default()
+    };
+    dt_index[&root].lock().children.push(acpi.id.into());
+
+    let mut nodes: HashMap<String, u64> = HashMap::from([(name::ROOT.into(), acpi.id)]);
+    dt_index.insert(acpi.id, acpi.into());
+    let mut absent = HashSet::new();
+    for path in aml.devices() {
+        let mut ancestor = name::parent(&path);
+        let parent = loop {
+            match ancestor {
+                Some(v) if absent.contains(v) => break None,
+                Some(v) if nodes.contains_key(v) => break Some(nodes[v]),
+                Some(v) => ancestor = name::parent(v),
+                None => break None,
+            }
+        };
+        let Some(parent) = parent else {
+            absent.insert(path);
+            continue;
+        };
+
+        // Children of devices that aren't present can only be present if the parent is still functioning.
+        let status = aml.status(&path);
+        if status & 1 == 0 {
+            if status & 8 == 0 {
+                absent.insert(path);
+            }
+            continue;
+        }
+
+        let ent = OSDTEntry {
+            id: dt_id_gen.next(),
+            parent: Some(parent.into()),
+            properties: properties(aml, &path),
+            ..Default::default()
+        };
+        dt_index[&parent].lock().children.push(ent.id.into());
+        nodes.insert(path, ent.id);
+        dt_index.insert(ent.id, ent.into());
+    }
+}
+
+pub fn setup(state: &mut crate::system::state::SystemState) {
+    let acpi = state.acpi.as_ref().unwrap();
+    let mut aml = Interpreter::new(Box::new(super::aml::KernelHandler));
+
+    let dsdt = acpi
+        .find::<FixedACPIDescTable>("FACP")
+        .map(FixedACPIDescTable::dsdt);
+    let ssdts = acpi
+        .tables
+        .iter()
+        .copied()
+        .filter(|v| v.signature() == "SSDT");
+    for table in dsdt.into_iter().chain(ssdts) {
+        debug!("Loading {}: {table:#X?}", table.signature());
+        if let Err(e) = super::aml::load_table(&mut aml, table) {
+            error!("Failed to load {}: {e:?}", table.signature());
+        }
+    }
+    aml.initialise();
+    publish(state, &mut aml);
+
+    state.aml = Some(aml.into());
+}
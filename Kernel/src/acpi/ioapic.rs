@@ -4,63 +4,68 @@ use amd64::spec::mps::{Polarity, TriggerMode};
 
 use super::tables::madt::ic::ioapic::{IOAPICRedir, InputOutputAPIC};
 
-pub fn wire_legacy_irq(irq: u8, masked: bool) {
-    let state = unsafe { &mut *crate::system::state::SYS_STATE.get() };
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GSIRoute {
+    pub gsi: u32,
+    pub active_low: bool,
+    pub level_triggered: bool,
+}
+
+// ISA IRQs are identity mapped, active high and edge-triggered unless an override says otherwise.
+pub fn legacy_irq_route(irq: u8) -> GSIRoute {
+    let state = unsafe { &*crate::system::state::SYS_STATE.get() };
     let madt = state.madt.as_ref().unwrap().lock();
     madt.isos.iter().find(|v| v.irq == irq).map_or_else(
-        || {
-            let ioapic = find_for_gsi(&madt, 0).unwrap();
-            debug!("Routing legacy irq {irq} to I/O APIC {}", ioapic.id);
-            ioapic.write_redir(
-                u32::from(irq),
-                IOAPICRedir::new()
-                    .with_vector(irq + 0x20)
-                    .with_masked(masked),
-            );
+        || GSIRoute {
+            gsi: irq.into(),
+            active_low: false,
+            level_triggered: false,
         },
         |v| {
-            let ioapic =
-                find_for_gsi(&madt, v.gsi).unwrap_or_else(|| find_for_gsi(&madt, 0).unwrap());
-            let gsi = v.gsi;
-            debug!(
-                "Routing legacy irq {irq} to I/O APIC {} at gsi {gsi}",
-                ioapic.id
-            );
             let flags = v.flags;
-            ioapic.write_redir(
-                v.gsi - ioapic.gsi_base,
-                IOAPICRedir::new()
-                    .with_vector(irq + 0x20)
-                    .with_active_high(flags.polarity() == Polarity::ActiveHigh)
-                    .with_trigger_at_level(flags.trigger_mode() == TriggerMode::LevelTriggered)
-                    .with_masked(masked),
-            );
+            GSIRoute {
+                gsi: v.gsi,
+                active_low: flags.polarity() == Polarity::ActiveLow,
+                level_triggered: flags.trigger_mode() == TriggerMode::LevelTriggered,
+            }
         },
-    );
+    )
 }
 
-pub fn set_irq_mask(irq: u8, masked: bool) {
-    let state = unsafe { &mut *crate::system::state::SYS_STATE.get() };
+pub fn route_gsi(route: GSIRoute, vector: u8, dest: u8, masked: bool) -> bool {
+    let state = unsafe { &*crate::system::state::SYS_STATE.get() };
     let madt = state.madt.as_ref().unwrap().lock();
-    madt.isos.iter().find(|v| v.irq == irq).map_or_else(
-        || {
-            let ioapic = find_for_gsi(&madt, 0).unwrap();
-            ioapic.write_redir(
-                u32::from(irq),
-                ioapic.read_redir(u32::from(irq)).with_masked(masked),
-            );
-        },
-        |v| {
-            let ioapic =
-                find_for_gsi(&madt, v.gsi).unwrap_or_else(|| find_for_gsi(&madt, 0).unwrap());
-            ioapic.write_redir(
-                v.gsi - ioapic.gsi_base,
-                ioapic
-                    .read_redir(v.gsi - ioapic.gsi_base)
-                    .with_masked(masked),
-            );
-        },
+    let Some(ioapic) = find_for_gsi(&madt, route.gsi) else {
+        return false;
+    };
+    debug!(
+        "Routing GSI {} to vector {vector:#X} on CPU {dest} through I/O APIC {}",
+        route.gsi, ioapic.id
     );
+    ioapic.write_redir(
+        route.gsi - ioapic.gsi_base,
+        IOAPICRedir::new()
+            .with_vector(vector)
+            .with_active_low(route.active_low)
+            .with_trigger_at_level(route.level_triggered)
+            .with_dest(dest)
+            .with_masked(masked),
+    );
+    true
+}
+
+pub fn wire_legacy_irq(irq: u8, masked: bool) {
+    route_gsi(legacy_irq_route(irq), irq + 0x20, 0, masked);
+}
+
+pub fn set_gsi_mask(gsi: u32, masked: bool) {
+    let state = unsafe { &*crate::system::state::SYS_STATE.get() };
+    let madt = state.madt.as_ref().unwrap().lock();
+    let Some(ioapic) = find_for_gsi(&madt, gsi) else {
+        return;
+    };
+    let index = gsi - ioapic.gsi_base;
+    ioapic.write_redir(index, ioapic.read_redir(index).with_masked(masked));
 }
 
 pub fn find_for_gsi(madt: &super::madt::MADTData, gsi: u32) -> Option<&'static InputOutputAPIC> {
@@ -68,7 +73,7 @@ pub fn find_for_gsi(madt: &super::madt::MADTData, gsi: u32) -> Option<&'static I
         .iter()
         .find(|ioapic| {
             gsi >= ioapic.gsi_base
-                && gsi < (ioapic.gsi_base + u32::from(ioapic.read_ver().max_redir()))
+                && gsi <= (ioapic.gsi_base + u32::from(ioapic.read_ver().max_redir()))
         })
         .copied()
 }
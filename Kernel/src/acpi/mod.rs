@@ -6,9 +6,12 @@ use amd64::paging::PageTableFlags;
 
 use self::tables::hpet::Hpet;
 
+pub mod aml;
 pub mod apic;
+pub mod devices;
 pub mod ioapic;
 pub mod madt;
+pub mod power;
 pub mod tables;
 
 pub struct ACPIState {
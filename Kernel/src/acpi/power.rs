@@ -0,0 +1,341 @@
+// Copyright (c) ChefKiss Inc 2021-2023. Licensed under the Thou Shalt Not Profit License version 1.5. See LICENSE for details.
+
+use alloc::vec::Vec;
+
+use amd64::{
+    io::port::PortIO,
+    paging::{PageTableFlags, PAGE_SIZE, PHYS_VIRT_OFFSET},
+    spec::mps::{Polarity, TriggerMode},
+};
+
+use super::{
+    ioapic::GSIRoute,
+    tables::fadt::{FixedACPIDescTable, GenericAddress},
+};
+use fireworkkit::msg::KernelMessage;
+
+use crate::system::RegisterState;
+
+const SYSTEM_MEMORY: u8 = 0;
+const SYSTEM_IO: u8 = 1;
+const PCI_CONFIG: u8 = 2;
+
+const SCI_EN: u16 = 1;
+const PWRBTN: u16 = 1 << 8;
+const SLP_TYP_SHIFT: u16 = 10;
+const SLP_EN: u16 = 1 << 13;
+const WAK_STS: u16 = 1 << 15;
+const RESET_REG_SUP: u32 = 1 << 10;
+
+fn fadt() -> Option<&'static FixedACPIDescTable> {
+    let state = unsafe { &*crate::system::state::SYS_STATE.get() };
+    state.acpi.as_ref()?.find("FACP")
+}
+
+// The extended blocks take precedence when the table has them, the legacy ones are always in I/O space.
+fn block(
+    fadt: &FixedACPIDescTable,
+    offset: usize,
+    x: GenericAddress,
+    legacy: u32,
+) -> Option<GenericAddress> {
+    if fadt.has_field(offset, 12) && x.address != 0 {
+        return Some(x);
+    }
+    (legacy != 0).then(|| GenericAddress {
+        space_id: SYSTEM_IO,
+        bit_width: 0,
+        bit_offset: 0,
+        access_size: 0,
+        address: legacy.into(),
+    })
+}
+
+// Event blocks hold the status register, followed by the enable register.
+fn pm1_evt(fadt: &FixedACPIDescTable) -> [Option<GenericAddress>; 2] {
+    [
+        block(fadt, 148, fadt.x_pm1a_evt_blk, fadt.pm1a_evt_blk),
+        block(fadt, 160, fadt.x_pm1b_evt_blk, fadt.pm1b_evt_blk),
+    ]
+}
+
+fn pm1_cnt(fadt: &FixedACPIDescTable) -> [Option<GenericAddress>; 2] {
+    [
+        block(fadt, 172, fadt.x_pm1a_cnt_blk, fadt.pm1a_cnt_blk),
+        block(fadt, 184, fadt.x_pm1b_cnt_blk, fadt.pm1b_cnt_blk),
+    ]
+}
+
+fn gpe(fadt: &FixedACPIDescTable) -> [(Option<GenericAddress>, u8); 2] {
+    [
+        (
+            block(fadt, 220, fadt.x_gpe0_blk, fadt.gpe0_blk),
+            fadt.gpe0_blk_len,
+        ),
+        (
+            block(fadt, 232, fadt.x_gpe1_blk, fadt.gpe1_blk),
+            fadt.gpe1_blk_len,
+        ),
+    ]
+}
+
+// Writes when `v` is set, otherwise reads. The registers touched here are either 8 or 16-bit wide.
+unsafe fn access(reg: GenericAddress, offset: u64, width: u64, v: Option<u64>) -> u64 {
+    let addr = reg.address + offset;
+    match reg.space_id {
+        SYSTEM_MEMORY => {
+            let state = &*crate::system::state::SYS_STATE.get();
+            let page = addr & !(PAGE_SIZE - 1);
+            state.pml4.as_ref().unwrap().lock().map_mmio(
+                page + PHYS_VIRT_OFFSET,
+                page,
+                1,
+                PageTableFlags::new_present().with_writable(true),
+            );
+            let ptr = addr + PHYS_VIRT_OFFSET;
+            match (width, v) {
+                (1, None) => (ptr as *const u8).read_volatile().into(),
+                (_, None) => (ptr as *const u16).read_volatile().into(),
+                (1, Some(v)) => {
+                    (ptr as *mut u8).write_volatile(v as _);
+                    0
+                }
+                (_, Some(v)) => {
+                    (ptr as *mut u16).write_volatile(v as _);
+                    0
+                }
+            }
+        }
+        SYSTEM_IO => port_access(addr as u16, width, v),
+        // Only the reset register may live here, in the configuration space of a device on bus 0.
+        PCI_CONFIG => {
+            let addr = crate::system::pci::config_address(
+                0,
+                (addr >> 32) as u8 & 0x1F,
+                (addr >> 16) as u8 & 7,
+                addr as u8,
+            );
+            crate::system::pci::config_access(addr, width, v)
+        }
+        space => {
+            warn!("Register {addr:#X} in unsupported address space {space:#X}");
+            0
+        }
+    }
+}
+
+unsafe fn port_access(port: u16, width: u64, v: Option<u64>) -> u64 {
+    match (width, v) {
+        (1, None) => u8::read(port).into(),
+        (_, None) => u16::read(port).into(),
+        (1, Some(v)) => {
+            u8::write(port, v as _);
+            0
+        }
+        (_, Some(v)) => {
+            u16::write(port, v as _);
+            0
+        }
+    }
+}
+
+// Gives the hardware time to act on a write before falling back to something else.
+fn settle() {
+    let state = unsafe { &*crate::system::state::SYS_STATE.get() };
+    let hpet = state.hpet.as_ref().unwrap();
+    let target = hpet.time_ns() + 100_000_000;
+    while hpet.time_ns() < target {
+        core::hint::spin_loop();
+    }
+}
+
+// Some firmware starts out handling the fixed events through SMIs, until asked to hand them over.
+unsafe fn enable_acpi_mode(fadt: &FixedACPIDescTable) {
+    let Some(cnt) = pm1_cnt(fadt)[0] else {
+        return;
+    };
+    let (smi_cmd, acpi_enable) = (fadt.smi_cmd, fadt.acpi_enable);
+    if access(cnt, 0, 2, None) as u16 & SCI_EN != 0 || smi_cmd == 0 || acpi_enable == 0 {
+        return;
+    }
+
+    debug!("Switching firmware to ACPI mode");
+    u8::write(smi_cmd as u16, acpi_enable);
+    let state = &*crate::system::state::SYS_STATE.get();
+    let hpet = state.hpet.as_ref().unwrap();
+    let target = hpet.time_ns() + 3_000_000_000;
+    while access(cnt, 0, 2, None) as u16 & SCI_EN == 0 {
+        if hpet.time_ns() >= target {
+            warn!("Firmware did not switch to ACPI mode");
+            return;
+        }
+        core::hint::spin_loop();
+    }
+}
+
+// The SCI is a shared, level-triggered, active-low ISA IRQ. Overrides may move it, but whatever they leave to the
+// bus stays that way.
+fn sci_route(irq: u16) -> GSIRoute {
+    let state = unsafe { &*crate::system::state::SYS_STATE.get() };
+    let madt = state.madt.as_ref().unwrap().lock();
+    let Some(iso) = madt.isos.iter().find(|v| u16::from(v.irq) == irq) else {
+        return GSIRoute {
+            gsi: irq.into(),
+            active_low: true,
+            level_triggered: true,
+        };
+    };
+    let (gsi, flags) = (iso.gsi, iso.flags);
+    GSIRoute {
+        gsi,
+        active_low: flags.polarity() != Polarity::ActiveHigh,
+        level_triggered: flags.trigger_mode() != TriggerMode::EdgeTriggered,
+    }
+}
+
+unsafe extern "sysv64" fn sci_handler(_state: &mut RegisterState) {
+    let Some(fadt) = fadt() else {
+        return;
+    };
+    let mut pressed = false;
+    for blk in pm1_evt(fadt).into_iter().flatten() {
+        let status = access(blk, 0, 2, None) as u16;
+        if status & PWRBTN != 0 {
+            access(blk, 0, 2, Some(PWRBTN.into()));
+            pressed = true;
+        }
+    }
+    if !pressed {
+        return;
+    }
+
+    // Shutting down runs AML and waits on the hardware, which has no place in an interrupt handler.
+    let state = &*crate::system::state::SYS_STATE.get();
+    let scheduler = state.scheduler.as_ref().unwrap();
+    let pids: Vec<u64> = scheduler
+        .processes
+        .read()
+        .iter()
+        .filter_map(|(&pid, v)| v.lock().privileged.then_some(pid))
+        .collect();
+    if pids.is_empty() {
+        warn!("Power button pressed, but there is no privileged process to handle it");
+    }
+    for pid in pids {
+        let _ = scheduler.post_kernel_message(pid, &KernelMessage::PowerButton);
+    }
+}
+
+pub fn setup(state: &crate::system::state::SystemState) {
+    let Some(fadt) = state
+        .acpi
+        .as_ref()
+        .unwrap()
+        .find::<FixedACPIDescTable>("FACP")
+    else {
+        warn!("No FADT, power management is unavailable");
+        return;
+    };
+
+    unsafe {
+        enable_acpi_mode(fadt);
+        // Nothing handles GPEs, firmware leaving some enabled would flood the SCI.
+        for (blk, len) in gpe(fadt) {
+            let Some(blk) = blk else {
+                continue;
+            };
+            for i in 0..u64::from(len / 2) {
+                access(blk, u64::from(len / 2) + i, 1, Some(0));
+            }
+        }
+        // Status bits are cleared by writing ones to them.
+        for blk in pm1_evt(fadt).into_iter().flatten() {
+            access(blk, 0, 2, Some((PWRBTN | WAK_STS).into()));
+            access(blk, u64::from(fadt.pm1_evt_len / 2), 2, Some(PWRBTN.into()));
+        }
+    }
+
+    let route = sci_route(fadt.sci_int);
+    if !state
+        .scheduler
+        .as_ref()
+        .unwrap()
+        .register_kernel_irq(route, sci_handler)
+    {
+        warn!("Failed to route the SCI on GSI {}", route.gsi);
+    }
+}
+
+// Enters S5 through the PM1 control blocks, only returns if the machine is still running afterwards.
+pub fn shutdown() {
+    let state = unsafe { &*crate::system::state::SYS_STATE.get() };
+    let (Some(fadt), Some(aml)) = (fadt(), state.aml.as_ref()) else {
+        return;
+    };
+    let types: [u16; 2] = match aml.lock().prepare_sleep(5) {
+        Ok(v) => v.into(),
+        Err(e) => {
+            error!("Failed to prepare for S5: {e:?}");
+            return;
+        }
+    };
+
+    unsafe {
+        for blk in pm1_evt(fadt).into_iter().flatten() {
+            access(blk, 0, 2, Some(WAK_STS.into()));
+        }
+        // Both sleep types have to be in place before either block gets SLP_EN.
+        let cnt = pm1_cnt(fadt);
+        let mut values = [0; 2];
+        for ((blk, typ), value) in cnt.into_iter().zip(types).zip(&mut values) {
+            let Some(blk) = blk else {
+                continue;
+            };
+            *value = (access(blk, 0, 2, None) as u16 & !(SLP_EN | (7 << SLP_TYP_SHIFT)))
+                | ((typ & 7) << SLP_TYP_SHIFT);
+            access(blk, 0, 2, Some((*value).into()));
+        }
+        for (blk, value) in cnt.into_iter().zip(values) {
+            if let Some(blk) = blk {
+                access(blk, 0, 2, Some((value | SLP_EN).into()));
+            }
+        }
+    }
+    settle();
+    error!("Machine is still running after entering S5");
+}
+
+// Tries the FADT reset register, then the keyboard controller, and finally a triple fault.
+pub fn reboot() -> ! {
+    if let Some(fadt) = fadt() {
+        let (flags, reg, value) = (fadt.flags, fadt.reset_reg, fadt.reset_value);
+        if fadt.has_field(116, 13) && flags & RESET_REG_SUP != 0 && reg.address != 0 {
+            unsafe { access(reg, 0, 1, Some(value.into())) };
+            settle();
+            warn!("Reset register had no effect");
+        }
+    }
+
+    unsafe {
+        for _ in 0..0x10000 {
+            if u8::read(0x64) & 2 == 0 {
+                break;
+            }
+            core::hint::spin_loop();
+        }
+        u8::write(0x64, 0xFE);
+    }
+    settle();
+    warn!("Keyboard controller did not reset the machine");
+
+    // Without an IDT, the breakpoint escalates to a triple fault.
+    unsafe {
+        crate::interrupts::idt::IDTReg {
+            limit: 0,
+            base: core::ptr::null(),
+        }
+        .reload();
+        core::arch::asm!("int3", options(nomem, nostack));
+    }
+    crate::hlt_loop!();
+}
@@ -0,0 +1,88 @@
+// Copyright (c) ChefKiss Inc 2021-2023. Licensed under the Thou Shalt Not Profit License version 1.5. See LICENSE for details.
+
+#![allow(dead_code)]
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+pub struct GenericAddress {
+    pub space_id: u8,
+    pub bit_width: u8,
+    pub bit_offset: u8,
+    pub access_size: u8,
+    pub address: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+pub struct FixedACPIDescTable {
+    header: super::SDTHeader,
+    pub firmware_ctrl: u32,
+    pub dsdt: u32,
+    __: u8,
+    pub preferred_pm_profile: u8,
+    pub sci_int: u16,
+    pub smi_cmd: u32,
+    pub acpi_enable: u8,
+    pub acpi_disable: u8,
+    pub s4bios_req: u8,
+    pub pstate_cnt: u8,
+    pub pm1a_evt_blk: u32,
+    pub pm1b_evt_blk: u32,
+    pub pm1a_cnt_blk: u32,
+    pub pm1b_cnt_blk: u32,
+    pub pm2_cnt_blk: u32,
+    pub pm_tmr_blk: u32,
+    pub gpe0_blk: u32,
+    pub gpe1_blk: u32,
+    pub pm1_evt_len: u8,
+    pub pm1_cnt_len: u8,
+    pub pm2_cnt_len: u8,
+    pub pm_tmr_len: u8,
+    pub gpe0_blk_len: u8,
+    pub gpe1_blk_len: u8,
+    pub gpe1_base: u8,
+    pub cst_cnt: u8,
+    pub p_lvl2_lat: u16,
+    pub p_lvl3_lat: u16,
+    pub flush_size: u16,
+    pub flush_stride: u16,
+    pub duty_offset: u8,
+    pub duty_width: u8,
+    pub day_alrm: u8,
+    pub mon_alrm: u8,
+    pub century: u8,
+    pub iapc_boot_arch: u16,
+    ___: u8,
+    pub flags: u32,
+    pub reset_reg: GenericAddress,
+    pub reset_value: u8,
+    pub arm_boot_arch: u16,
+    pub minor_version: u8,
+    pub x_firmware_ctrl: u64,
+    pub x_dsdt: u64,
+    pub x_pm1a_evt_blk: GenericAddress,
+    pub x_pm1b_evt_blk: GenericAddress,
+    pub x_pm1a_cnt_blk: GenericAddress,
+    pub x_pm1b_cnt_blk: GenericAddress,
+    pub x_pm2_cnt_blk: GenericAddress,
+    pub x_pm_tmr_blk: GenericAddress,
+    pub x_gpe0_blk: GenericAddress,
+    pub x_gpe1_blk: GenericAddress,
+}
+
+impl FixedACPIDescTable {
+    // ACPI 1.0 tables end right after the flags, everything past them is only valid if the table is long enough.
+    pub const fn has_field(&self, offset: usize, size: usize) -> bool {
+        self.header.length() >= offset + size
+    }
+
+    pub fn dsdt(&self) -> &'static super::SDTHeader {
+        let x_dsdt = self.x_dsdt;
+        let addr = if self.has_field(140, 8) && x_dsdt != 0 {
+            x_dsdt
+        } else {
+            u64::from(self.dsdt)
+        };
+        unsafe { &*((addr + amd64::paging::PHYS_VIRT_OFFSET) as *const super::SDTHeader) }
+    }
+}
@@ -66,7 +66,7 @@ pub struct IOAPICRedir {
     pub delivery_mode: DeliveryMode,
     pub logical_dest: bool,
     pub pending: bool,
-    pub active_high: bool,
+    pub active_low: bool,
     pub remote_irr: bool,
     pub trigger_at_level: bool,
     pub masked: bool,
@@ -2,6 +2,7 @@
 
 use alloc::vec::Vec;
 
+#[derive(Debug)]
 pub struct IncrementalIDGen {
     last_used: u64,
     freed: Vec<u64>,
@@ -23,6 +23,11 @@ macro_rules! isr_stub {
             "push r15",
             "mov rdi, rsp",
             "call {}",
+            "cmp rax, rsp",
+            "je 2f",
+            "mov rsp, rax",
+            "call {}",
+            "2:",
             "pop r15",
             "pop r14",
             "pop r13",
@@ -42,12 +47,15 @@ macro_rules! isr_stub {
             "iretq",
             const $i,
             sym isr_handler,
+            sym crate::system::smp::finish_switch,
             options(noreturn),
         )
     };
 }
 
-unsafe extern "sysv64" fn isr_handler(regs: &mut crate::system::RegisterState) {
+unsafe extern "sysv64" fn isr_handler(
+    regs: &mut crate::system::RegisterState,
+) -> *mut crate::system::RegisterState {
     let n = regs.int_num as u8;
     let handler = &(*super::HANDLERS.get())[n as usize];
     (handler.func)(regs);
@@ -58,6 +66,7 @@ unsafe extern "sysv64" fn isr_handler(regs: &mut crate::system::RegisterState) {
     if !handler.should_iret && !handler.is_irq {
         crate::hlt_loop!();
     }
+    crate::system::smp::resume_frame(regs)
 }
 
 macro_rules! isr_noerr {
@@ -41,7 +41,7 @@ pub static IDTR: IDTReg = IDTReg {
     base: unsafe { (*ENTRIES.get()).as_ptr() },
 };
 
-type HandlerFn = unsafe extern "sysv64" fn(&mut RegisterState);
+pub type HandlerFn = unsafe extern "sysv64" fn(&mut RegisterState);
 
 pub struct InterruptHandler {
     pub func: HandlerFn,
@@ -154,6 +154,10 @@ impl IDTReg {
             entry.offset_high = (base >> 32) as u32;
         });
 
+        self.reload();
+    }
+
+    pub unsafe fn reload(&self) {
         core::arch::asm!("lidt [{}]", in(reg) self, options(readonly, preserves_flags));
     }
 }
@@ -183,3 +187,20 @@ pub fn set_handler(
         should_iret,
     };
 }
+
+pub fn is_free(isr: u8) -> bool {
+    unsafe { (*HANDLERS.get())[isr as usize].func as usize == default_handler as usize }
+}
+
+pub fn clear_handler(isr: u8) {
+    let ent = unsafe { &mut (*ENTRIES.get())[isr as usize] };
+    ent.flags = ent.flags.with_dpl(PrivilegeLevel::Supervisor).with_ist(0);
+
+    unsafe {
+        (*HANDLERS.get())[isr as usize] = InterruptHandler {
+            func: default_handler,
+            is_irq: false,
+            should_iret: false,
+        };
+    }
+}
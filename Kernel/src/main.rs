@@ -159,13 +159,20 @@ extern "C" fn kernel_main(boot_info: &'static sulphur_dioxide::BootInfo) -> ! {
 
     acpi::madt::setup(state);
     acpi::apic::setup(state);
+    system::smp::setup(state);
+    system::fpu::init_cpu();
 
     system::tasking::userland::setup();
 
     let fkcache: FKCache = postcard::from_bytes(boot_info.fkcache).unwrap();
     state.fkcache = Some(fkcache.into());
-    state.scheduler =
-        Some(system::tasking::scheduler::Scheduler::new(&acpi::get_hpet(state)).into());
+    state.hpet = Some(acpi::get_hpet(state));
+    acpi::devices::setup(state);
+    system::smp::start_aps(state);
+    let hpet = state.hpet.as_ref().unwrap();
+    state.scheduler = Some(system::tasking::scheduler::Scheduler::new(hpet));
+    system::smp::release_aps();
+    acpi::power::setup(state);
 
     system::fkext::spawn_initial_matches();
 
@@ -0,0 +1,17 @@
+// Copyright (c) ChefKiss Inc 2021-2023. Licensed under the Thou Shalt Not Profit License version 1.5. See LICENSE for details.
+
+pub unsafe extern "sysv64" fn dev_unavailable(regs: &mut crate::system::RegisterState) {
+    // The kernel itself is soft-float, so only threads can end up here.
+    if regs.cs.trailing_zeros() >= 2 {
+        exception_msg!("device unavailable", "<No Additional Information>", regs);
+        return;
+    }
+
+    let scheduler = (*crate::system::state::SYS_STATE.get())
+        .scheduler
+        .as_ref()
+        .unwrap();
+    let idx = crate::system::smp::current_index();
+    let cpu = crate::system::smp::current();
+    scheduler.with_current_thread(|thread| crate::system::fpu::claim(cpu, idx, thread));
+}
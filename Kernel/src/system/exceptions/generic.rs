@@ -7,7 +7,6 @@ super::generic_exception!(breakpoint, "breakpoint");
 super::generic_exception!(overflow, "overflow");
 super::generic_exception!(bound_range, "bound range exceeded");
 super::generic_exception!(invalid_opcode, "invalid opcode");
-super::generic_exception!(dev_unavailable, "device unavailable");
 super::generic_exception!(double_fault, "double fault");
 super::generic_exception!(coproc_segment_overrun, "coprocessor segment overrun");
 
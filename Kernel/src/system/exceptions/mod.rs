@@ -2,6 +2,9 @@
 
 macro_rules! exception_msg {
     ($name:expr, $msg:expr, $regs:expr) => {
+        exception_msg!($name, $msg, $regs, $regs.rip);
+    };
+    ($name:expr, $msg:expr, $regs:expr, $addr:expr) => {
         while crate::system::serial::SERIAL.is_locked() {
             crate::system::serial::SERIAL.force_unlock()
         }
@@ -13,10 +16,12 @@ macro_rules! exception_msg {
             panic!("Received {} exception: {}", $name, $msg);
         } else {
             use core::fmt::Write;
-            let mut scheduler = sys_state.scheduler.as_ref().unwrap().lock();
-            let cur_proc = scheduler.current_process().unwrap();
-            let image_base = cur_proc.image_base;
-            let proc_path = &cur_proc.path;
+            let scheduler = sys_state.scheduler.as_ref().unwrap();
+            let (image_base, proc_path) = scheduler
+                .with_current_process(|cur_proc| (cur_proc.image_base, cur_proc.path.clone()));
+            let offset = $regs.rip.wrapping_sub(image_base);
+            let location = crate::system::fkext::resolve_symbol(&proc_path, offset)
+                .map_or_else(|| "???".into(), |(name, off)| format!("{name}+{off:#X}"));
             writeln!(
                 crate::system::serial::SERIAL.lock(),
                 "Received {} exception in user-land: {}",
@@ -35,6 +40,11 @@ macro_rules! exception_msg {
                 "Process Path: {proc_path}"
             )
             .unwrap();
+            writeln!(
+                crate::system::serial::SERIAL.lock(),
+                "Faulting RIP: {image_base:#018X}+{offset:#06X} -> {location}"
+            )
+            .unwrap();
 
             if sys_state.verbose {
                 if let Some(v) = sys_state.terminal.as_mut() {
@@ -42,10 +52,18 @@ macro_rules! exception_msg {
                     writeln!(v, "{}", $regs).unwrap();
                     writeln!(v, "Image Base: {image_base:#018X}").unwrap();
                     writeln!(v, "Process Path: {proc_path}").unwrap();
+                    writeln!(
+                        v,
+                        "Faulting RIP: {image_base:#018X}+{offset:#06X} -> {location}"
+                    )
+                    .unwrap();
                 }
             }
 
-            scheduler.process_teardown();
+            scheduler.process_teardown(fireworkkit::TerminationReason::Fault {
+                vector: $regs.int_num as u8,
+                addr: $addr,
+            });
             scheduler.schedule($regs);
         }
     };
@@ -62,8 +80,12 @@ macro_rules! generic_exception {
 use exception_msg;
 use generic_exception;
 
-use crate::system::gdt::PrivilegeLevel;
+use crate::system::{
+    gdt::PrivilegeLevel,
+    tss::{DOUBLE_FAULT_IST, NMI_IST},
+};
 
+mod fpu;
 mod gdt;
 mod generic;
 mod page_fault;
@@ -73,16 +95,23 @@ pub fn init() {
     let dpl = PrivilegeLevel::Supervisor;
     crate::interrupts::idt::set_handler(0, 0, dpl, generic::div_by_zero, false, true);
     crate::interrupts::idt::set_handler(1, 0, dpl, generic::debug, false, true);
-    crate::interrupts::idt::set_handler(2, 0, dpl, generic::nmi, false, false);
+    crate::interrupts::idt::set_handler(2, NMI_IST, dpl, generic::nmi, false, false);
     crate::interrupts::idt::set_handler(3, 0, dpl, generic::breakpoint, false, true);
     crate::interrupts::idt::set_handler(4, 0, dpl, generic::overflow, false, true);
     crate::interrupts::idt::set_handler(5, 0, dpl, generic::bound_range, false, true);
     crate::interrupts::idt::set_handler(6, 0, dpl, generic::invalid_opcode, false, true);
-    crate::interrupts::idt::set_handler(7, 0, dpl, generic::dev_unavailable, false, true);
-    crate::interrupts::idt::set_handler(8, 1, dpl, generic::double_fault, false, false);
+    crate::interrupts::idt::set_handler(7, 0, dpl, fpu::dev_unavailable, false, true);
+    crate::interrupts::idt::set_handler(
+        8,
+        DOUBLE_FAULT_IST,
+        dpl,
+        generic::double_fault,
+        false,
+        false,
+    );
     crate::interrupts::idt::set_handler(9, 0, dpl, generic::coproc_segment_overrun, false, false);
-    crate::interrupts::idt::set_handler(10, 0, dpl, gdt::invalid_tss, false, false);
-    crate::interrupts::idt::set_handler(11, 0, dpl, gdt::segment_not_present, false, false);
+    crate::interrupts::idt::set_handler(10, 0, dpl, gdt::invalid_tss, false, true);
+    crate::interrupts::idt::set_handler(11, 0, dpl, gdt::segment_not_present, false, true);
     crate::interrupts::idt::set_handler(12, 0, dpl, gdt::stack_exception, false, true);
     crate::interrupts::idt::set_handler(13, 0, dpl, gdt::general_protection_fault, false, true);
     crate::interrupts::idt::set_handler(14, 0, dpl, page_fault::page_fault, false, true);
@@ -4,6 +4,33 @@ pub unsafe extern "sysv64" fn page_fault(regs: &mut crate::system::RegisterState
     let mut cr2: u64;
     core::arch::asm!("mov {}, cr2", out(reg) cr2, options(nomem, nostack, preserves_flags));
 
+    // Non-present faults may just be lazily allocated memory, either touched by the process
+    // itself or by the kernel on its behalf during a system call.
+    if (regs.err_code & (1 << 0)) == 0 {
+        let scheduler = (*crate::system::state::SYS_STATE.get())
+            .scheduler
+            .as_ref()
+            .filter(|v| v.current_pid().is_some());
+        if let Some(scheduler) = scheduler {
+            match scheduler.with_current_process(|process| process.handle_fault(cr2)) {
+                Some(true) => return,
+                Some(false) => {}
+                // System calls back user buffers before touching them, so a supervisor fault here may be
+                // holding locks and can't just be torn down.
+                None if (regs.err_code & (1 << 2)) != 0 => {
+                    error!("Out of memory backing {cr2:#X}, terminating process");
+                    scheduler.process_teardown(fireworkkit::TerminationReason::Fault {
+                        vector: regs.int_num as u8,
+                        addr: cr2,
+                    });
+                    scheduler.schedule(regs);
+                    return;
+                }
+                None => error!("Out of memory backing {cr2:#X}"),
+            }
+        }
+    }
+
     let msg = format!(
         "There was a {} while {} a {} page at {cr2:#X?}.{}{}{}{}",
         if (regs.err_code & (1 << 0)) == 0 {
@@ -43,5 +70,5 @@ pub unsafe extern "sysv64" fn page_fault(regs: &mut crate::system::RegisterState
         },
     );
 
-    super::exception_msg!("page fault", msg, regs);
+    super::exception_msg!("page fault", msg, regs, cr2);
 }
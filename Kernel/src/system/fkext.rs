@@ -1,23 +1,54 @@
 // Copyright (c) ChefKiss Inc 2021-2023. Licensed under the Thou Shalt Not Profit License version 1.5. See LICENSE for details.
 
-use alloc::vec::Vec;
-use core::hash::Hash;
+use alloc::{borrow::ToOwned, string::String, vec::Vec};
 
 use fireworkkit::{
-    osdtentry::{FKEXT_MATCH_KEY, FKEXT_PROC_KEY, OSDTENTRY_NAME_KEY},
-    FKInfo,
+    msg::KernelMessage,
+    osdtentry::{
+        FKEXT_MATCH_KEY, FKEXT_PROC_KEY, FKEXT_RESTARTS_KEY, INTERRUPTS_KEY, OSDTENTRY_NAME_KEY,
+        PORT_RANGES_KEY,
+    },
+    osvalue::OSValue,
+    syscall::IRQMode,
+    FKInfo, RestartPolicy, TerminationReason,
 };
 use hashbrown::HashMap;
 
 use super::tasking::scheduler::Scheduler;
 use crate::incr_id::IncrementalIDGen;
 
-fn is_subset<K: Eq + Hash, V: Eq>(a: &HashMap<K, V>, b: &HashMap<K, V>) -> bool {
+const MAX_RESTARTS: u64 = 5;
+
+// A list property, like `_CID`, also matches a personality asking for one of its elements.
+fn is_subset(a: &HashMap<String, OSValue>, b: &HashMap<String, OSValue>) -> bool {
     if a.len() > b.len() {
         return false;
     }
 
-    a.iter().all(|(k, v)| b.get(k) == Some(v))
+    a.iter().all(|(k, v)| match b.get(k) {
+        Some(OSValue::Vec(list)) if !matches!(v, OSValue::Vec(_)) => list.contains(v),
+        other => other == Some(v),
+    })
+}
+
+fn published_ranges(ent: &super::state::OSDTEntry, key: &str) -> Vec<(u64, u64)> {
+    let Some(OSValue::Vec(ranges)) = ent.properties.get(key) else {
+        return Vec::new();
+    };
+    ranges
+        .iter()
+        .filter_map(|v| <(&u64, &u64)>::try_from(v).ok())
+        .map(|(&base, &size)| (base, size))
+        .collect()
+}
+
+// Extensions get the ports listed in their info, plus those a bus driver published for their device.
+fn port_grants(info: &FKInfo, device: Option<&super::state::OSDTEntry>) -> Vec<(u64, u64)> {
+    info.io_ports
+        .iter()
+        .map(|&(base, count)| (base.into(), count.into()))
+        .chain(device.map_or_else(Vec::new, |v| published_ranges(v, PORT_RANGES_KEY)))
+        .collect()
 }
 
 fn load_fkext(
@@ -26,15 +57,23 @@ fn load_fkext(
     personality: &str,
     payload: &[u8],
     dt_id_gen: &mut IncrementalIDGen,
-    scheduler: &mut Scheduler,
+    scheduler: &Scheduler,
 ) -> (u64, spin::Mutex<super::state::OSDTEntry>) {
     debug!(
         "FireworkKit extension {} matched <{}> for personality {personality}",
         info.identifier, ent.id
     );
-    let thread = scheduler.spawn_proc(info.identifier.clone(), payload);
+    let id = dt_id_gen.next();
+    let io_ports = port_grants(info, Some(ent));
+    let pid = scheduler.spawn_proc(
+        info.identifier.clone(),
+        payload,
+        id,
+        io_ports,
+        info.privileged,
+    );
     let new = super::state::OSDTEntry {
-        id: dt_id_gen.next(),
+        id,
         parent: Some(ent.id.into()),
         properties: HashMap::from([
             (
@@ -45,16 +84,15 @@ fn load_fkext(
                 FKEXT_MATCH_KEY.into(),
                 (info.identifier.as_str(), personality).into(),
             ),
-            (FKEXT_PROC_KEY.into(), thread.pid.into()),
+            (FKEXT_PROC_KEY.into(), pid.into()),
         ]),
         ..Default::default()
     };
     ent.children.push(new.id.into());
-    thread.regs.rdi = new.id;
     (new.id, new.into())
 }
 
-pub fn handle_change(scheduler: &mut Scheduler, ent: fireworkkit::osdtentry::OSDTEntry) {
+pub fn handle_change(scheduler: &Scheduler, ent: fireworkkit::osdtentry::OSDTEntry) {
     let state = unsafe { &*super::state::SYS_STATE.get() };
 
     let dt_index = state.dt_index.as_ref().unwrap();
@@ -98,7 +136,7 @@ pub fn spawn_initial_matches() {
 
     let dt_index = state.dt_index.as_ref().unwrap();
     let mut dt_id_gen = state.dt_id_gen.as_ref().unwrap().lock();
-    let mut scheduler = state.scheduler.as_ref().unwrap().lock();
+    let scheduler = state.scheduler.as_ref().unwrap();
 
     let mut newly_matched = vec![];
     for ((info, payload), mut ent) in iproduct!(
@@ -115,7 +153,7 @@ pub fn spawn_initial_matches() {
                     personality,
                     payload,
                     &mut dt_id_gen,
-                    &mut scheduler,
+                    scheduler,
                 );
                 newly_matched.push(new);
             }
@@ -123,3 +161,152 @@ pub fn spawn_initial_matches() {
     }
     dt_index.write().extend(newly_matched);
 }
+
+pub fn find_owner(
+    dt_index: &HashMap<u64, spin::Mutex<super::state::OSDTEntry>>,
+    mut ent: Option<fireworkkit::osdtentry::OSDTEntry>,
+) -> Option<u64> {
+    while let Some(id) = ent {
+        let ent_ = dt_index.get::<u64>(&id.into())?.lock();
+        if let Some(&pid) = ent_
+            .properties
+            .get(FKEXT_PROC_KEY)
+            .and_then(|v| <&u64>::try_from(v).ok())
+        {
+            return Some(pid);
+        }
+        ent = ent_.parent;
+    }
+    None
+}
+
+// Resources published by a bus driver on the device node that an extension was matched against.
+fn with_device<R: Default>(dt_entry: u64, f: impl FnOnce(&super::state::OSDTEntry) -> R) -> R {
+    let state = unsafe { &*super::state::SYS_STATE.get() };
+
+    let dt_index = state.dt_index.as_ref().unwrap().read();
+    let Some(parent) = dt_index.get(&dt_entry).and_then(|v| v.lock().parent) else {
+        return R::default();
+    };
+    dt_index
+        .get::<u64>(&parent.into())
+        .map_or_else(R::default, |v| f(&v.lock()))
+}
+
+pub fn device_ranges(dt_entry: u64, key: &str) -> Vec<(u64, u64)> {
+    with_device(dt_entry, |v| published_ranges(v, key))
+}
+
+// Lines the bus driver published for the device, along with the mode they have to be registered with.
+pub fn device_interrupts(dt_entry: u64) -> Vec<(u64, IRQMode)> {
+    with_device(dt_entry, |ent| {
+        let Some(OSValue::Vec(irqs)) = ent.properties.get(INTERRUPTS_KEY) else {
+            return Vec::new();
+        };
+        irqs.iter()
+            .filter_map(|v| <(&u32, &u64)>::try_from(v).ok())
+            .filter_map(|(&line, &mode)| Some((line.into(), IRQMode::try_from(mode).ok()?)))
+            .collect()
+    })
+}
+
+pub fn handle_exit(
+    scheduler: &Scheduler,
+    pid: u64,
+    dt_entry: u64,
+    reason: Option<TerminationReason>,
+) {
+    let state = unsafe { &*super::state::SYS_STATE.get() };
+
+    let dt_index = state.dt_index.as_ref().unwrap().read();
+    let Some(ent) = dt_index.get(&dt_entry) else {
+        return;
+    };
+    let (parent, identifier, restarts) = {
+        let ent = ent.lock();
+        if ent.properties.get(FKEXT_PROC_KEY) != Some(&pid.into()) {
+            return;
+        }
+        let identifier = ent
+            .properties
+            .get(FKEXT_MATCH_KEY)
+            .and_then(|v| <(&str, &str)>::try_from(v).ok())
+            .map(|(identifier, _)| identifier.to_owned());
+        let restarts = ent
+            .properties
+            .get(FKEXT_RESTARTS_KEY)
+            .and_then(|v| <&u64>::try_from(v).ok())
+            .copied()
+            .unwrap_or_default();
+        (ent.parent, identifier, restarts)
+    };
+
+    // The entry lock is released first, as the walk locks ancestors.
+    if let Some(owner) = find_owner(&dt_index, parent) {
+        let _ = scheduler.post_kernel_message(owner, &KernelMessage::ProcessExited { pid, reason });
+    }
+
+    let fkcache = state.fkcache.as_ref().unwrap().lock();
+    let Some((info, payload)) = identifier.and_then(|identifier| {
+        fkcache
+            .0
+            .iter()
+            .find(|(info, _)| info.identifier == identifier)
+    }) else {
+        return;
+    };
+    let restart = match info.restart {
+        RestartPolicy::Never => false,
+        RestartPolicy::OnCrash => reason.is_some(),
+        RestartPolicy::Always => true,
+    };
+
+    let device = parent.and_then(|v| dt_index.get::<u64>(&v.into()));
+    let io_ports = port_grants(info, device.map(|v| v.lock()).as_deref());
+    let mut ent = ent.lock();
+    if !restart || restarts >= MAX_RESTARTS {
+        ent.properties.remove(FKEXT_PROC_KEY);
+        return;
+    }
+    debug!(
+        "Restarting FireworkKit extension {} on <{}> ({reason:?})",
+        info.identifier, ent.id
+    );
+    let new = scheduler.spawn_proc(
+        info.identifier.clone(),
+        payload,
+        dt_entry,
+        io_ports,
+        info.privileged,
+    );
+    ent.properties.insert(FKEXT_PROC_KEY.into(), new.into());
+    ent.properties
+        .insert(FKEXT_RESTARTS_KEY.into(), (restarts + 1).into());
+}
+
+// Looks up the function containing `offset` within the image of an extension.
+pub fn resolve_symbol(identifier: &str, offset: u64) -> Option<(String, u64)> {
+    let state = unsafe { &*super::state::SYS_STATE.get() };
+
+    let fkcache = state.fkcache.as_ref()?.lock();
+    let (_, payload) = fkcache
+        .0
+        .iter()
+        .find(|(info, _)| info.identifier == identifier)?;
+    let exec = elf::ElfBytes::<elf::endian::NativeEndian>::minimal_parse(payload).ok()?;
+    // Extensions are usually stripped, in which case only exported symbols are left.
+    let (symbols, strings) = exec
+        .symbol_table()
+        .ok()
+        .flatten()
+        .or_else(|| exec.dynamic_symbol_table().ok().flatten())?;
+    let symbol = symbols.iter().find(|v| {
+        v.st_symtype() == elf::abi::STT_FUNC
+            && (v.st_value..v.st_value + v.st_size).contains(&offset)
+    })?;
+    let name = strings.get(symbol.st_name as _).ok()?;
+    Some((
+        format!("{}", rustc_demangle::demangle(name)),
+        offset - symbol.st_value,
+    ))
+}
@@ -0,0 +1,120 @@
+// Copyright (c) ChefKiss Inc 2021-2023. Licensed under the Thou Shalt Not Profit License version 1.5. See LICENSE for details.
+
+use alloc::boxed::Box;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use amd64::{
+    cpuid::{CPUIdentification, ExtendedStateInfo},
+    cr::{ControlReg0, ControlReg4, ExtendedControlReg0},
+};
+
+use super::{smp::CPUState, tasking::Thread};
+
+static USE_XSAVE: AtomicBool = AtomicBool::new(false);
+static AREA_SIZE: AtomicUsize = AtomicUsize::new(512);
+
+#[derive(Clone, Copy)]
+#[repr(C, align(64))]
+struct Chunk([u8; 64]);
+
+pub struct FPUState(Box<[Chunk]>);
+
+impl FPUState {
+    fn new() -> Self {
+        let mut chunks = vec![Chunk([0; 64]); AREA_SIZE.load(Ordering::Relaxed).div_ceil(64)];
+        // Default FCW and MXCSR, everything masked.
+        chunks[0].0[..2].copy_from_slice(&0x037Fu16.to_le_bytes());
+        chunks[0].0[24..28].copy_from_slice(&0x1F80u32.to_le_bytes());
+        Self(chunks.into_boxed_slice())
+    }
+
+    unsafe fn save(&mut self) {
+        let area = self.0.as_mut_ptr();
+        if USE_XSAVE.load(Ordering::Relaxed) {
+            core::arch::asm!("xsave64 [{}]", in(reg) area, in("eax") u32::MAX, in("edx") u32::MAX, options(nostack, preserves_flags));
+        } else {
+            core::arch::asm!("fxsave64 [{}]", in(reg) area, options(nostack, preserves_flags));
+        }
+    }
+
+    unsafe fn restore(&self) {
+        let area = self.0.as_ptr();
+        if USE_XSAVE.load(Ordering::Relaxed) {
+            core::arch::asm!("xrstor64 [{}]", in(reg) area, in("eax") u32::MAX, in("edx") u32::MAX, options(nostack, preserves_flags));
+        } else {
+            core::arch::asm!("fxrstor64 [{}]", in(reg) area, options(nostack, preserves_flags));
+        }
+    }
+}
+
+impl core::fmt::Debug for FPUState {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_tuple("FPUState")
+            .field(&(self.0.len() * 64))
+            .finish()
+    }
+}
+
+pub fn init_cpu() {
+    let features = CPUIdentification::new().features;
+    assert!(features.fxsr(), "FXSAVE is not supported");
+
+    unsafe {
+        ControlReg0::read()
+            .with_emulation(false)
+            .with_monitor_coproc(true)
+            .with_numeric_error(true)
+            .with_task_switched(true)
+            .write();
+        let cr4 = ControlReg4::read()
+            .with_os_fxsave(true)
+            .with_os_xmm_exceptions(true);
+        if !features.xsave() {
+            cr4.write();
+            return;
+        }
+        cr4.with_os_xsave(true).write();
+
+        let supported = ExtendedControlReg0::from(ExtendedStateInfo::new().supported);
+        ExtendedControlReg0::new()
+            .with_x87(true)
+            .with_sse(true)
+            .with_avx(features.avx() && supported.avx())
+            .write();
+    }
+
+    let size = ExtendedStateInfo::new().enabled_size as usize;
+    AREA_SIZE.fetch_max(size, Ordering::Relaxed);
+    USE_XSAVE.store(true, Ordering::Relaxed);
+}
+
+#[inline]
+unsafe fn set_task_switched(value: bool) {
+    ControlReg0::read().with_task_switched(value).write();
+}
+
+// The registers stay loaded when switching away, they only need to be written back if the thread
+// touched them during its time slice, which is the case if CR0.TS got cleared.
+pub unsafe fn switch_out(cpu: &CPUState, thread: &mut Thread) {
+    if cpu.fpu_owner.load(Ordering::Relaxed) != thread.id || ControlReg0::read().task_switched() {
+        return;
+    }
+    if let Some(state) = thread.fpu_state.as_mut() {
+        state.save();
+    }
+}
+
+pub unsafe fn switch_in(cpu: &CPUState, idx: usize, thread: &Thread) {
+    let loaded = cpu.fpu_owner.load(Ordering::Relaxed) == thread.id && thread.fpu_cpu == Some(idx);
+    set_task_switched(!loaded);
+}
+
+pub unsafe fn claim(cpu: &CPUState, idx: usize, thread: &mut Thread) {
+    set_task_switched(false);
+    if cpu.fpu_owner.load(Ordering::Relaxed) == thread.id && thread.fpu_cpu == Some(idx) {
+        return;
+    }
+    thread.fpu_state.get_or_insert_with(FPUState::new).restore();
+    thread.fpu_cpu = Some(idx);
+    cpu.fpu_owner.store(thread.id, Ordering::Relaxed);
+}
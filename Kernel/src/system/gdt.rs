@@ -176,8 +176,9 @@ pub struct GDTData {
     _null: SegmentDescriptor,
     _code_segment: SegmentDescriptor,
     _data_segment: SegmentDescriptor,
-    _user_code_segment: SegmentDescriptor,
+    // SYSRET expects the user data segment to come right before the user code segment.
     _user_data_segment: SegmentDescriptor,
+    _user_code_segment: SegmentDescriptor,
     pub task_segment: TaskSegmentDescriptor,
 }
 
@@ -194,14 +195,14 @@ impl GDTData {
                 DescriptorType::DataSegment,
                 PrivilegeLevel::Supervisor,
             ),
-            _user_code_segment: SegmentDescriptor::new_from_ty(
-                DescriptorType::CodeSegment,
-                PrivilegeLevel::User,
-            ),
             _user_data_segment: SegmentDescriptor::new_from_ty(
                 DescriptorType::DataSegment,
                 PrivilegeLevel::User,
             ),
+            _user_code_segment: SegmentDescriptor::new_from_ty(
+                DescriptorType::CodeSegment,
+                PrivilegeLevel::User,
+            ),
             task_segment: TaskSegmentDescriptor::null(),
         }
     }
@@ -3,10 +3,13 @@
 pub mod allocator;
 pub mod exceptions;
 pub mod fkext;
+pub mod fpu;
 pub mod gdt;
 mod panic;
+pub mod pci;
 pub mod pmm;
 pub mod serial;
+pub mod smp;
 pub mod state;
 pub mod tasking;
 pub mod terminal;
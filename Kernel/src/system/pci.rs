@@ -0,0 +1,43 @@
+// Copyright (c) ChefKiss Inc 2021-2023. Licensed under the Thou Shalt Not Profit License version 1.5. See LICENSE for details.
+
+use amd64::io::port::PortIO;
+
+pub const CONFIG_ADDRESS: u16 = 0xCF8;
+pub const CONFIG_DATA: u16 = 0xCFC;
+
+// The address and data ports are used as a pair, so nobody may move the address in between.
+static CONFIG_LOCK: spin::Mutex<()> = spin::Mutex::new(());
+
+#[must_use]
+pub const fn config_address(bus: u8, slot: u8, func: u8, offset: u8) -> u32 {
+    ((bus as u32) << 16)
+        | ((slot as u32) << 11)
+        | ((func as u32) << 8)
+        | offset as u32
+        | 0x8000_0000
+}
+
+// Writes when `v` is set, otherwise reads. The low bits of the address pick the byte within the dword, the access
+// may not cross into the next one.
+pub unsafe fn config_access(addr: u32, width: u64, v: Option<u64>) -> u64 {
+    let _lock = CONFIG_LOCK.lock();
+    u32::write(CONFIG_ADDRESS, addr & !3);
+    let port = CONFIG_DATA + (addr as u16 & 3);
+    match (width, v) {
+        (1, None) => u8::read(port).into(),
+        (2, None) => u16::read(port).into(),
+        (_, None) => u32::read(port).into(),
+        (1, Some(v)) => {
+            u8::write(port, v as _);
+            0
+        }
+        (2, Some(v)) => {
+            u16::write(port, v as _);
+            0
+        }
+        (_, Some(v)) => {
+            u32::write(port, v as _);
+            0
+        }
+    }
+}
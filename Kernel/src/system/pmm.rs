@@ -40,12 +40,10 @@ impl BitmapAllocator {
             let MemoryEntry::Usable(v) = v else {
                 return None;
             };
-            // Skip the first 2 MiB.
-            Some(if v.base <= 0x20_0000 && v.base + v.length > 0x20_0000 {
-                MemoryData::new(0x20_0000, v.length - 0x20_0000)
-            } else {
-                *v
-            })
+            // Skip the first 2 MiB, the AP trampoline gets copied to a fixed address in there.
+            let end = v.base + v.length;
+            let base = v.base.max(0x20_0000);
+            (end > base).then(|| MemoryData::new(base, end - base))
         }) {
             if v.length == 0 {
                 continue;
@@ -128,6 +126,32 @@ impl BitmapAllocator {
             })
     }
 
+    // Devices may only reach part of physical memory and often need naturally aligned buffers.
+    pub unsafe fn alloc_below(&mut self, count: u64, max_addr: u64, align: u64) -> Option<*mut u8> {
+        let step = (align / PAGE_SIZE).max(1);
+        let end = (max_addr.saturating_add(1) / PAGE_SIZE).min(self.highest_addr / PAGE_SIZE);
+        let mut page = 0;
+
+        while page + count <= end {
+            if let Some(used) =
+                (page..page + count).rfind(|&i| crate::bitmap::bit_test(self.bitmap, i))
+            {
+                page = (used + 1).next_multiple_of(step);
+                continue;
+            }
+
+            for i in page..page + count {
+                crate::bitmap::bit_set(self.bitmap, i);
+            }
+
+            self.free_pages -= count;
+
+            return Some((page * PAGE_SIZE) as *mut _);
+        }
+
+        None
+    }
+
     pub unsafe fn free(&mut self, ptr: *mut u8, count: u64) {
         let idx = ptr as u64 / PAGE_SIZE;
 
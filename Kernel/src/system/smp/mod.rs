@@ -0,0 +1,347 @@
+// Copyright (c) ChefKiss Inc 2021-2023. Licensed under the Thou Shalt Not Profit License version 1.5. See LICENSE for details.
+
+use alloc::{boxed::Box, vec::Vec};
+use core::{
+    cell::SyncUnsafeCell,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+};
+
+use amd64::paging::PageTableFlags;
+
+use super::{
+    gdt::{GDTData, GDTReg, PrivilegeLevel, SegmentSelector},
+    tasking::KernelStack,
+    tss::{TaskSegmentSelector, DOUBLE_FAULT_IST, NMI_IST},
+    RegisterState,
+};
+use crate::{
+    acpi::apic::{DeliveryMode, InterruptCommand},
+    timer::Timer,
+};
+
+mod trampoline;
+
+const AP_STACK_SIZE: usize = 0x4000;
+const IST_STACK_SIZE: usize = 0x4000;
+
+static AP_READY: AtomicBool = AtomicBool::new(false);
+// APs wait for the scheduler before taking any interrupts.
+static APS_RELEASED: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Default)]
+#[repr(C)]
+pub struct SyscallScratch {
+    pub kern_rsp: u64,
+    pub user_rsp: u64,
+}
+
+pub struct CPUState {
+    pub lapic_id: u8,
+    pub gdt: SyncUnsafeCell<GDTData>,
+    pub tss: SyncUnsafeCell<TaskSegmentSelector>,
+    pub syscall: SyncUnsafeCell<SyscallScratch>,
+    pub kern_stack: Vec<u8>,
+    pub ist_stacks: [KernelStack; 2],
+    // Thread whose kernel stack this CPU is executing on, 0 for its own.
+    pub stack_owner: AtomicU64,
+    pub switch_to: SyncUnsafeCell<Option<(u64, u64)>>,
+    pub dead_stacks: spin::Mutex<Vec<KernelStack>>,
+    pub fpu_owner: AtomicU64,
+}
+
+impl CPUState {
+    #[inline]
+    pub fn new(lapic_id: u8) -> Self {
+        let kern_stack = vec![0; 0x14000];
+        let kern_rsp = kern_stack.as_ptr() as u64 + kern_stack.len() as u64;
+        let ist_stacks = [
+            KernelStack::new(IST_STACK_SIZE),
+            KernelStack::new(IST_STACK_SIZE),
+        ];
+        let mut tss = TaskSegmentSelector::new(kern_rsp);
+        tss.interrupt_stack_table[DOUBLE_FAULT_IST as usize - 1] = ist_stacks[0].top();
+        tss.interrupt_stack_table[NMI_IST as usize - 1] = ist_stacks[1].top();
+        Self {
+            lapic_id,
+            gdt: SyncUnsafeCell::new(GDTData::new()),
+            tss: SyncUnsafeCell::new(tss),
+            syscall: SyncUnsafeCell::new(SyscallScratch {
+                kern_rsp,
+                user_rsp: 0,
+            }),
+            kern_stack,
+            ist_stacks,
+            stack_owner: AtomicU64::new(0),
+            switch_to: SyncUnsafeCell::new(None),
+            dead_stacks: spin::Mutex::new(Vec::new()),
+            fpu_owner: AtomicU64::new(0),
+        }
+    }
+
+    // Called by the scheduler once it picked what to run next, `tid` being 0 for the idle loop.
+    pub unsafe fn prepare_switch(&self, tid: u64, stack_top: u64) {
+        let tss = &mut *self.tss.get();
+        tss.privilege_stack_table[0] = stack_top;
+        (*self.syscall.get()).kern_rsp = stack_top;
+        *self.switch_to.get() = Some((tid, stack_top));
+    }
+
+    // Frames get copied over to the top of the next thread's kernel stack, the entry stubs then
+    // switch to it before calling `finish_switch`, as the old stack may still be in use until then.
+    pub unsafe fn resume_frame(&self, regs: *mut RegisterState) -> *mut RegisterState {
+        let Some((_, top)) = *self.switch_to.get() else {
+            return regs;
+        };
+        let frame = (top as *mut RegisterState).sub(1);
+        if frame == regs {
+            *self.switch_to.get() = None;
+            return regs;
+        }
+        frame.write(*regs);
+        frame
+    }
+
+    pub unsafe fn finish_switch(&self) {
+        if let Some((tid, _)) = (*self.switch_to.get()).take() {
+            self.stack_owner.store(tid, Ordering::Release);
+        }
+        drop(core::mem::take(&mut *self.dead_stacks.lock()));
+    }
+
+    pub fn kern_stack_top(&self) -> u64 {
+        self.kern_stack.as_ptr() as u64 + self.kern_stack.len() as u64
+    }
+
+    pub unsafe fn load(&self) {
+        let gdt = &mut *self.gdt.get();
+        let tss_addr = self.tss.get() as u64;
+        gdt.task_segment.base_low = tss_addr as u16;
+        gdt.task_segment.base_middle = (tss_addr >> 16) as u8;
+        gdt.task_segment.attrs = gdt.task_segment.attrs.with_present(true);
+        gdt.task_segment.base_high = (tss_addr >> 24) as u8;
+        gdt.task_segment.base_upper = (tss_addr >> 32) as u32;
+
+        GDTReg {
+            limit: (core::mem::size_of::<GDTData>() - 1) as u16,
+            addr: self.gdt.get(),
+        }
+        .load();
+
+        core::arch::asm!(
+            "ltr ax",
+            in("ax") SegmentSelector::new(5, PrivilegeLevel::Supervisor).0,
+            options(nostack, preserves_flags),
+        );
+    }
+}
+
+pub fn current_index() -> usize {
+    let state = unsafe { &*super::state::SYS_STATE.get() };
+    let id = state.lapic.as_ref().unwrap().id();
+    state
+        .cpus
+        .as_ref()
+        .unwrap()
+        .iter()
+        .position(|v| v.lapic_id == id)
+        .unwrap()
+}
+
+pub fn current() -> &'static CPUState {
+    let state = unsafe { &*super::state::SYS_STATE.get() };
+    &state.cpus.as_ref().unwrap()[current_index()]
+}
+
+pub fn stack_holder(tid: u64) -> Option<usize> {
+    let state = unsafe { &*super::state::SYS_STATE.get() };
+    state
+        .cpus
+        .as_ref()
+        .unwrap()
+        .iter()
+        .position(|v| v.stack_owner.load(Ordering::Acquire) == tid)
+}
+
+// Kernel stacks of threads that are still being switched away from are freed by that CPU later on.
+pub fn retire_stack(tid: u64, stack: KernelStack) {
+    let state = unsafe { &*super::state::SYS_STATE.get() };
+    if let Some(idx) = stack_holder(tid) {
+        let cpu = &state.cpus.as_ref().unwrap()[idx];
+        cpu.dead_stacks.lock().push(stack);
+    }
+}
+
+pub unsafe extern "sysv64" fn resume_frame(regs: &mut RegisterState) -> *mut RegisterState {
+    let state = &*super::state::SYS_STATE.get();
+    if state.cpus.is_none() {
+        return regs;
+    }
+    current().resume_frame(regs)
+}
+
+pub unsafe extern "sysv64" fn finish_switch() {
+    current().finish_switch();
+}
+
+pub fn send_ipi(index: usize, vector: u8) {
+    let state = unsafe { &*super::state::SYS_STATE.get() };
+    state.lapic.as_ref().unwrap().send_ipi(
+        InterruptCommand::new()
+            .with_vector(vector)
+            .with_assert(true)
+            .with_dest(state.cpus.as_ref().unwrap()[index].lapic_id),
+    );
+}
+
+pub fn setup(state: &mut super::state::SystemState) {
+    let bsp_id = state.lapic.as_ref().unwrap().id();
+    let mut cpus = vec![Box::new(CPUState::new(bsp_id))];
+    cpus.extend(
+        state
+            .madt
+            .as_ref()
+            .unwrap()
+            .lock()
+            .proc_lapics
+            .iter()
+            .filter(|v| {
+                let flags = v.flags;
+                flags.enabled() && v.apic_id != bsp_id
+            })
+            .map(|v| Box::new(CPUState::new(v.apic_id))),
+    );
+    state.cpus = Some(cpus);
+
+    unsafe { state.cpus.as_ref().unwrap()[0].load() }
+}
+
+extern "C" fn ap_main() -> ! {
+    let state = unsafe { &*super::state::SYS_STATE.get() };
+
+    unsafe {
+        super::vmm::init_pat();
+        current().load();
+        crate::interrupts::idt::IDTR.reload();
+        super::tasking::userland::syscall::setup_cpu();
+        super::fpu::init_cpu();
+    }
+
+    let lapic = state.lapic.as_ref().unwrap();
+    lapic.init();
+    debug!("CPU {} is up.", lapic.id());
+
+    // Also tells the BSP that the trampoline data is no longer needed.
+    AP_READY.store(true, Ordering::SeqCst);
+
+    while !APS_RELEASED.load(Ordering::SeqCst) {
+        core::hint::spin_loop();
+    }
+    lapic.start_timer();
+    super::tasking::scheduler::Scheduler::unmask();
+
+    crate::hlt_loop!();
+}
+
+// Has to run before the scheduler is created, as CPUs that fail to start are dropped.
+pub fn start_aps(state: &mut super::state::SystemState) {
+    let cpus = state.cpus.as_mut().unwrap();
+    if cpus.len() < 2 {
+        return;
+    }
+    let timer = state.hpet.as_ref().unwrap();
+
+    let code = trampoline::code();
+    let trampoline_addr = trampoline::TRAMPOLINE_ADDR;
+    let mut pml4 = state.pml4.as_ref().unwrap().lock();
+    unsafe {
+        pml4.map(
+            trampoline_addr,
+            trampoline_addr,
+            1,
+            PageTableFlags::new_present().with_writable(true),
+        );
+        core::ptr::copy_nonoverlapping(
+            code.as_ptr(),
+            (trampoline_addr + amd64::paging::PHYS_VIRT_OFFSET) as *mut u8,
+            code.len(),
+        );
+    }
+
+    let cr3: u64;
+    let cr0: u64;
+    let cr4: u64;
+    unsafe {
+        core::arch::asm!("mov {}, cr3", out(reg) cr3, options(nomem, nostack, preserves_flags));
+        core::arch::asm!("mov {}, cr0", out(reg) cr0, options(nomem, nostack, preserves_flags));
+        core::arch::asm!("mov {}, cr4", out(reg) cr4, options(nomem, nostack, preserves_flags));
+    }
+    assert!(cr3 < 0x1_0000_0000, "Kernel PML4 is not reachable by APs");
+
+    let data = (trampoline_addr
+        + amd64::paging::PHYS_VIRT_OFFSET
+        + trampoline::data_offset() as u64) as *mut trampoline::TrampolineData;
+    let lapic = state.lapic.as_ref().unwrap();
+
+    let mut failed = Vec::new();
+    for cpu in cpus.iter().skip(1) {
+        let stack = vec![0u8; AP_STACK_SIZE].leak();
+        unsafe {
+            data.write(trampoline::TrampolineData {
+                cr0,
+                cr3,
+                cr4,
+                stack: stack.as_ptr() as u64 + stack.len() as u64,
+                entry: ap_main as usize as u64,
+            });
+        }
+        AP_READY.store(false, Ordering::SeqCst);
+
+        lapic.send_ipi(
+            InterruptCommand::new()
+                .with_delivery_mode(DeliveryMode::Init)
+                .with_assert(true)
+                .with_dest(cpu.lapic_id),
+        );
+        timer.sleep(10);
+        for _ in 0..2 {
+            lapic.send_ipi(
+                InterruptCommand::new()
+                    .with_vector((trampoline_addr >> 12) as u8)
+                    .with_delivery_mode(DeliveryMode::StartUp)
+                    .with_assert(true)
+                    .with_dest(cpu.lapic_id),
+            );
+            timer.sleep(1);
+            if AP_READY.load(Ordering::SeqCst) {
+                break;
+            }
+        }
+
+        for _ in 0..100 {
+            if AP_READY.load(Ordering::SeqCst) {
+                break;
+            }
+            timer.sleep(1);
+        }
+
+        // Parking it makes sure it doesn't pick up the data meant for the next one later on.
+        if !AP_READY.load(Ordering::SeqCst) {
+            warn!("CPU {} did not come up.", cpu.lapic_id);
+            lapic.send_ipi(
+                InterruptCommand::new()
+                    .with_delivery_mode(DeliveryMode::Init)
+                    .with_assert(true)
+                    .with_dest(cpu.lapic_id),
+            );
+            timer.sleep(10);
+            failed.push(cpu.lapic_id);
+        }
+    }
+
+    unsafe { pml4.unmap(trampoline_addr, 1) }
+    cpus.retain(|v| !failed.contains(&v.lapic_id));
+}
+
+pub fn release_aps() {
+    APS_RELEASED.store(true, Ordering::SeqCst);
+}
@@ -0,0 +1,89 @@
+// Copyright (c) ChefKiss Inc 2021-2023. Licensed under the Thou Shalt Not Profit License version 1.5. See LICENSE for details.
+
+pub const TRAMPOLINE_ADDR: u64 = 0x8000;
+
+#[derive(Debug, Default, Clone, Copy)]
+#[repr(C)]
+pub struct TrampolineData {
+    pub cr0: u64,
+    pub cr3: u64,
+    pub cr4: u64,
+    pub stack: u64,
+    pub entry: u64,
+}
+
+extern "C" {
+    static ap_trampoline_start: u8;
+    static ap_trampoline_data: u8;
+    static ap_trampoline_end: u8;
+}
+
+// Real mode straight to long mode; the paging structures and control registers are taken from the BSP.
+core::arch::global_asm!(
+    ".pushsection .text.ap_trampoline, \"ax\", @progbits",
+    ".code16",
+    ".global ap_trampoline_start",
+    "ap_trampoline_start:",
+    "    cli",
+    "    cld",
+    "    xorw %ax, %ax",
+    "    movw %ax, %ds",
+    "    lgdtl ({addr} + ap_trampoline_gdtr - ap_trampoline_start)",
+    "    movl ({addr} + ap_trampoline_data + 16 - ap_trampoline_start), %eax",
+    "    movl %eax, %cr4",
+    "    movl ({addr} + ap_trampoline_data + 8 - ap_trampoline_start), %eax",
+    "    movl %eax, %cr3",
+    "    movl $0xC0000080, %ecx",
+    "    rdmsr",
+    "    orl $(1 << 8), %eax",
+    "    wrmsr",
+    "    movl ({addr} + ap_trampoline_data - ap_trampoline_start), %eax",
+    "    movl %eax, %cr0",
+    "    ljmpl $0x08, $({addr} + ap_trampoline_long - ap_trampoline_start)",
+    ".code64",
+    "ap_trampoline_long:",
+    "    movw $0x10, %ax",
+    "    movw %ax, %ds",
+    "    movw %ax, %es",
+    "    movw %ax, %ss",
+    "    xorw %ax, %ax",
+    "    movw %ax, %fs",
+    "    movw %ax, %gs",
+    "    movq ({addr} + ap_trampoline_data + 24 - ap_trampoline_start), %rsp",
+    "    xorq %rbp, %rbp",
+    "    movq ({addr} + ap_trampoline_data + 32 - ap_trampoline_start), %rax",
+    "    callq *%rax",
+    "    ud2",
+    ".balign 8",
+    "ap_trampoline_gdt:",
+    "    .quad 0",
+    "    .quad 0x00AF9A000000FFFF",
+    "    .quad 0x00CF92000000FFFF",
+    "ap_trampoline_gdtr:",
+    "    .word 23",
+    "    .long {addr} + ap_trampoline_gdt - ap_trampoline_start",
+    ".balign 8",
+    ".global ap_trampoline_data",
+    "ap_trampoline_data:",
+    "    .fill 5, 8, 0",
+    ".global ap_trampoline_end",
+    "ap_trampoline_end:",
+    ".popsection",
+    addr = const TRAMPOLINE_ADDR,
+    options(att_syntax),
+);
+
+pub fn code() -> &'static [u8] {
+    unsafe {
+        let start = core::ptr::addr_of!(ap_trampoline_start);
+        let end = core::ptr::addr_of!(ap_trampoline_end);
+        core::slice::from_raw_parts(start, end as usize - start as usize)
+    }
+}
+
+pub fn data_offset() -> usize {
+    unsafe {
+        core::ptr::addr_of!(ap_trampoline_data) as usize
+            - core::ptr::addr_of!(ap_trampoline_start) as usize
+    }
+}
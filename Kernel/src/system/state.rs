@@ -6,11 +6,13 @@ use core::cell::SyncUnsafeCell;
 use hashbrown::HashMap;
 
 use super::{
-    pmm::BitmapAllocator, tasking::scheduler::Scheduler, terminal::Terminal, vmm::PageTableLvl4,
+    pmm::BitmapAllocator, smp::CPUState, tasking::scheduler::Scheduler, terminal::Terminal,
+    vmm::PageTableLvl4,
 };
 use crate::{
-    acpi::{apic::LocalAPIC, madt::MADTData, ACPIState},
+    acpi::{aml::Interpreter, apic::LocalAPIC, madt::MADTData, ACPIState},
     incr_id::IncrementalIDGen,
+    timer::hpet::Hpet,
 };
 
 pub static SYS_STATE: SyncUnsafeCell<SystemState> = SyncUnsafeCell::new(SystemState::new());
@@ -31,9 +33,12 @@ pub struct SystemState {
     pub pml4: Option<spin::Mutex<Box<PageTableLvl4>>>,
     pub terminal: Option<Terminal>,
     pub acpi: Option<ACPIState>,
+    pub aml: Option<spin::Mutex<Interpreter>>,
     pub madt: Option<spin::Mutex<MADTData>>,
     pub lapic: Option<LocalAPIC>,
-    pub scheduler: Option<spin::Mutex<Scheduler>>,
+    pub hpet: Option<Hpet>,
+    pub cpus: Option<Vec<Box<CPUState>>>,
+    pub scheduler: Option<Scheduler>,
     pub interrupt_context: Option<super::RegisterState>,
     pub in_panic: core::sync::atomic::AtomicBool,
     pub dt_index: Option<spin::RwLock<HashMap<u64, spin::Mutex<OSDTEntry>>>>,
@@ -52,8 +57,11 @@ impl SystemState {
             pml4: None,
             terminal: None,
             acpi: None,
+            aml: None,
             madt: None,
             lapic: None,
+            hpet: None,
+            cpus: None,
             scheduler: None,
             interrupt_context: None,
             in_panic: core::sync::atomic::AtomicBool::new(false),
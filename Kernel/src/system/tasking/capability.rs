@@ -0,0 +1,38 @@
+// Copyright (c) ChefKiss Inc 2021-2023. Licensed under the Thou Shalt Not Profit License version 1.5. See LICENSE for details.
+
+use alloc::string::String;
+
+#[bitfield(u8)]
+pub struct Rights {
+    pub send: bool,
+    pub grant: bool,
+    pub write: bool,
+    #[bits(5)]
+    __: u8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Object {
+    Endpoint(u64),
+    SharedMemory(u64),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Capability {
+    pub object: Object,
+    pub rights: Rights,
+}
+
+#[derive(Debug)]
+pub struct Endpoint {
+    pub owner: u64,
+    pub name: Option<String>,
+}
+
+#[derive(Debug)]
+pub struct SharedRegion {
+    pub phys: u64,
+    pub size: u64,
+    // Both capabilities and live mappings count, the pages are freed once neither is left.
+    pub refs: u64,
+}
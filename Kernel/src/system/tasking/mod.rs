@@ -1,23 +1,64 @@
 // Copyright (c) ChefKiss Inc 2021-2023. Licensed under the Thou Shalt Not Profit License version 1.5. See LICENSE for details.
 
-use alloc::{boxed::Box, collections::VecDeque, string::String, vec::Vec};
-
-use amd64::paging::PageTableFlags;
-use fireworkkit::msg::Message;
+use alloc::{
+    boxed::Box,
+    collections::{BTreeMap, VecDeque},
+    string::String,
+    vec::Vec,
+};
+
+use amd64::paging::{PageTableFlags, PAGE_SIZE};
+use fireworkkit::{
+    dma::CacheType, msg::Message, syscall::ThreadPriority, TerminationReason, ThreadControlBlock,
+};
 use hashbrown::{HashMap, HashSet};
 
 use super::gdt::{PrivilegeLevel, SegmentSelector};
+use crate::incr_id::IncrementalIDGen;
 
+pub mod capability;
+pub mod run_queue;
 pub mod scheduler;
 pub mod userland;
+pub mod vma;
 
 pub const STACK_SIZE: u64 = 0x14000;
+pub const KERN_STACK_SIZE: usize = 0x8000;
+pub const USER_VIRT_BASE: u64 = 0x1000_0000;
+pub const USER_VIRT_END: u64 = 0x7FFF_FFFF_0000;
+
+#[derive(Default)]
+pub struct KernelStack(Vec<u8>);
+
+impl KernelStack {
+    #[inline]
+    pub fn new(size: usize) -> Self {
+        Self(vec![0; size])
+    }
+
+    #[inline]
+    pub fn top(&self) -> u64 {
+        self.0.as_ptr() as u64 + self.0.len() as u64
+    }
+}
+
+impl core::fmt::Debug for KernelStack {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.debug_tuple("KernelStack")
+            .field(&format_args!("{:#X}", self.top()))
+            .finish()
+    }
+}
 
 #[derive(Debug, PartialEq, Eq)]
 pub enum ThreadState {
     Active,
     Inactive,
     Suspended,
+    Joining,
+    Sleeping,
+    Calling(u64),
+    Dying,
 }
 
 impl ThreadState {
@@ -26,10 +67,20 @@ impl ThreadState {
         *self == Self::Suspended
     }
 
+    #[inline]
+    pub fn is_sleeping(&self) -> bool {
+        *self == Self::Sleeping
+    }
+
     #[inline]
     pub fn is_inactive(&self) -> bool {
         *self == Self::Inactive
     }
+
+    #[inline]
+    pub fn is_dying(&self) -> bool {
+        *self == Self::Dying
+    }
 }
 
 #[derive(Debug)]
@@ -37,10 +88,16 @@ pub struct Thread {
     pub id: u64,
     pub pid: u64,
     pub state: ThreadState,
+    pub priority: ThreadPriority,
+    pub cpu: Option<usize>,
+    pub deadline: Option<u64>,
     pub regs: super::RegisterState,
     pub fs_base: usize,
     pub gs_base: usize,
     pub stack_addr: u64,
+    pub kern_stack: KernelStack,
+    pub fpu_state: Option<super::fpu::FPUState>,
+    pub fpu_cpu: Option<usize>,
 }
 
 impl Thread {
@@ -50,26 +107,69 @@ impl Thread {
             id,
             pid,
             state: ThreadState::Inactive,
+            priority: ThreadPriority::default(),
+            cpu: None,
+            deadline: None,
             regs: super::RegisterState {
                 rip,
-                cs: SegmentSelector::new(3, PrivilegeLevel::User).into(),
+                cs: SegmentSelector::new(4, PrivilegeLevel::User).into(),
                 rflags: 0x202,
                 rsp: stack_addr + STACK_SIZE,
-                ss: SegmentSelector::new(4, PrivilegeLevel::User).into(),
+                ss: SegmentSelector::new(3, PrivilegeLevel::User).into(),
                 ..Default::default()
             },
             fs_base: 0,
             gs_base: 0,
             stack_addr,
+            kern_stack: KernelStack::new(KERN_STACK_SIZE),
+            fpu_state: None,
+            fpu_cpu: None,
+        }
+    }
+
+    pub const fn block(&mut self, regs: &super::RegisterState, state: ThreadState) {
+        if matches!(self.state, ThreadState::Dying) {
+            return;
         }
+        self.regs = *regs;
+        self.state = state;
+        self.cpu = None;
     }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum AllocationType {
-    Kernel,
     Readable,
     Writable,
+    Lazy,
+    Shared { writable: bool },
+    Dma(CacheType),
+    Mmio,
+}
+
+impl AllocationType {
+    #[inline]
+    pub const fn is_writable(self) -> bool {
+        matches!(
+            self,
+            Self::Writable
+                | Self::Lazy
+                | Self::Shared { writable: true }
+                | Self::Dma(_)
+                | Self::Mmio
+        )
+    }
+
+    // Indices into the PAT as programmed by `vmm::init_pat`, entries 4 to 7 are left uncacheable.
+    #[inline]
+    pub const fn pat_entry(self) -> u8 {
+        match self {
+            Self::Dma(CacheType::WriteThrough) => 1,
+            Self::Dma(CacheType::WriteCombining) => 2,
+            Self::Dma(CacheType::Uncacheable) | Self::Mmio => 4,
+            _ => 0,
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -77,100 +177,135 @@ pub struct Process {
     pub id: u64,
     pub path: String,
     pub image_base: u64,
+    pub dt_entry: u64,
+    pub io_ports: Vec<(u64, u64)>,
+    pub pci_config_addr: u32,
+    pub privileged: bool,
+    pub exit_reason: Option<TerminationReason>,
     pub cr3: spin::Mutex<Box<userland::page_table::UserPML4>>,
     pub messages: VecDeque<Message>,
-    pub allocations: HashMap<u64, (u64, AllocationType)>,
+    pub allocations: BTreeMap<u64, (u64, AllocationType)>,
+    pub vmas: vma::VirtualAllocator,
+    pub shm_mappings: HashMap<u64, u64>,
     pub msg_id_to_addr: HashMap<u64, u64>,
     pub addr_to_msg_id: HashMap<u64, u64>,
     pub thread_ids: HashSet<u64>,
+    pub thread_joiners: HashMap<u64, Vec<u64>>,
+    pub exited_threads: HashSet<u64>,
+    pub caps: HashMap<u64, capability::Capability>,
+    pub cap_id_gen: IncrementalIDGen,
     pub alloc_lock: spin::Mutex<()>,
 }
 
 impl Process {
     #[inline]
-    pub fn new(id: u64, path: String, image_base: u64) -> Self {
+    pub fn new(id: u64, path: String, image_base: u64, dt_entry: u64) -> Self {
         Self {
             id,
             path,
             image_base,
-            cr3: Box::new(userland::page_table::UserPML4::new(id)).into(),
+            dt_entry,
+            io_ports: Vec::new(),
+            pci_config_addr: 0,
+            privileged: false,
+            exit_reason: None,
+            cr3: Box::new(userland::page_table::UserPML4::new()).into(),
             messages: VecDeque::new(),
-            allocations: HashMap::new(),
+            allocations: BTreeMap::new(),
+            vmas: vma::VirtualAllocator::new(USER_VIRT_BASE, USER_VIRT_END),
+            shm_mappings: HashMap::new(),
             msg_id_to_addr: HashMap::new(),
             addr_to_msg_id: HashMap::new(),
             thread_ids: HashSet::new(),
+            thread_joiners: HashMap::new(),
+            exited_threads: HashSet::new(),
+            caps: HashMap::new(),
+            cap_id_gen: IncrementalIDGen::new(),
             alloc_lock: spin::Mutex::new(()),
         }
     }
 
     #[inline]
-    pub fn new_thread(&mut self, id: u64, rip: u64, stack_addr: u64) -> Thread {
-        let thread = Thread::new(id, self.id, rip, stack_addr);
+    pub fn new_thread(&mut self, id: u64, rip: u64, stack_addr: u64) -> Option<Thread> {
+        let size = core::mem::size_of::<ThreadControlBlock>();
+        let tcb_addr = self.allocate(size as _)?;
+        let tcb = ThreadControlBlock {
+            this: tcb_addr,
+            tid: id,
+            tls: 0,
+        };
+        self.write(tcb_addr, unsafe {
+            core::slice::from_raw_parts((&tcb as *const ThreadControlBlock).cast(), size)
+        });
+        let mut thread = Thread::new(id, self.id, rip, stack_addr);
+        thread.fs_base = tcb_addr as _;
         self.thread_ids.insert(id);
-        thread
+        Some(thread)
     }
 
-    pub fn track_alloc(&mut self, addr: u64, size: u64, ty: AllocationType) {
-        let _lock = self.alloc_lock.lock();
+    pub fn grant_cap(&mut self, cap: capability::Capability) -> u64 {
+        let id = self.cap_id_gen.next();
+        self.caps.insert(id, cap);
+        id
+    }
 
-        if self.allocations.contains_key(&addr) {
-            panic!("PID {}: Address {addr:#X} already allocated", self.id);
-        }
+    pub fn track_alloc(&mut self, phys: u64, size: u64, ty: AllocationType) -> Option<u64> {
+        let _lock = self.alloc_lock.lock();
 
-        let page_count = (size + 0xFFF) / 0x1000;
+        let page_count = size.div_ceil(PAGE_SIZE);
 
+        // Device memory lies outside of what the PMM manages.
         assert!(
-            unsafe {
-                (*crate::system::state::SYS_STATE.get())
-                    .pmm
-                    .as_ref()
-                    .unwrap()
-                    .lock()
-                    .is_allocated((addr - fireworkkit::USER_VIRT_OFFSET) as *mut _, page_count)
-            },
-            "PID {}: Address {addr:#X} not allocated",
+            ty == AllocationType::Mmio
+                || unsafe {
+                    (*crate::system::state::SYS_STATE.get())
+                        .pmm
+                        .as_ref()
+                        .unwrap()
+                        .lock()
+                        .is_allocated(phys as *mut _, page_count)
+                },
+            "PID {}: Physical address {phys:#X} not allocated",
             self.id,
         );
 
+        let addr = self.vmas.alloc(size)?;
         trace!(
-            "PID {}: Tracking {addr:#X} ({ty:?}, {size} byte{}, {page_count} page{}, will map: {})",
+            "PID {}: Tracking {addr:#X} -> {phys:#X} ({ty:?}, {size} byte{}, {page_count} page{})",
             self.id,
             if size > 1 { "s" } else { "" },
             if page_count > 1 { "s" } else { "" },
-            ty != AllocationType::Kernel
         );
         self.allocations.insert(addr, (size, ty));
 
-        if ty == AllocationType::Kernel {
-            return;
-        }
-
         unsafe {
             drop(_lock);
             self.cr3.lock().map(
                 addr,
-                addr - fireworkkit::USER_VIRT_OFFSET,
+                phys,
                 page_count,
                 PageTableFlags::new_present()
-                    .with_writable(ty == AllocationType::Writable)
-                    .with_user(true),
+                    .with_writable(ty.is_writable())
+                    .with_user(true)
+                    .with_pat_entry(ty.pat_entry()),
             );
         }
+        Some(addr)
     }
 
-    pub fn track_kernelside_alloc(&mut self, addr: u64, size: u64) -> u64 {
-        let _lock = self.alloc_lock.lock();
-
-        let addr = addr - amd64::paging::PHYS_VIRT_OFFSET + fireworkkit::USER_VIRT_OFFSET;
-        drop(_lock);
-        self.track_alloc(addr, size, AllocationType::Readable);
-        addr
+    pub fn track_kernelside_alloc(&mut self, addr: u64, size: u64) -> Option<u64> {
+        self.track_alloc(
+            addr - amd64::paging::PHYS_VIRT_OFFSET,
+            size,
+            AllocationType::Readable,
+        )
     }
 
     pub fn region_is_valid(&self, addr: u64, size: u64) -> bool {
         self.allocations
-            .iter()
-            .any(|(k, (v, _))| k <= &addr && addr + size <= k + v)
+            .range(..=addr)
+            .next_back()
+            .is_some_and(|(k, (v, _))| addr + size <= k + v)
     }
 
     pub fn region_is_within_bounds(&self, addr: u64, size: u64) -> bool {
@@ -197,19 +332,52 @@ impl Process {
             self.id
         );
 
-        unsafe {
+        self.vmas.free(addr, size);
+        drop(_lock);
+        // Shared region pages are reference counted by the scheduler.
+        self.free_pages(
+            addr,
+            page_count,
+            !matches!(ty, AllocationType::Shared { .. } | AllocationType::Mmio),
+        );
+    }
+
+    // Lazy ranges may be sparsely backed, so pages are unmapped one by one.
+    fn free_pages(&self, addr: u64, count: u64, owned: bool) {
+        let mut cr3 = self.cr3.lock();
+        let mut pmm = unsafe {
             (*crate::system::state::SYS_STATE.get())
                 .pmm
                 .as_ref()
                 .unwrap()
                 .lock()
-                .free((addr - fireworkkit::USER_VIRT_OFFSET) as *mut _, page_count);
+        };
+        for page in (0..count).map(|i| addr + PAGE_SIZE * i) {
+            let Some(phys) = (unsafe { cr3.virt_to_phys(page) }) else {
+                continue;
+            };
+            unsafe {
+                if owned {
+                    pmm.free(phys as *mut _, 1);
+                }
+                cr3.unmap(page, 1);
+            }
         }
+    }
 
-        if ty != AllocationType::Kernel {
-            drop(_lock);
-            unsafe { self.cr3.lock().unmap(addr, page_count) }
+    // Maps pages owned by another process read-only, without tracking them as an allocation.
+    pub fn map_foreign(&mut self, pages: &[u64], size: u64) -> Option<u64> {
+        let addr = self.vmas.alloc(size)?;
+        let mut cr3 = self.cr3.lock();
+        for (virt, &phys) in (addr..).step_by(PAGE_SIZE as _).zip(pages) {
+            unsafe { cr3.map(virt, phys, 1, PageTableFlags::new_present().with_user(true)) }
         }
+        Some(addr)
+    }
+
+    pub fn unmap_foreign(&mut self, addr: u64, size: u64) {
+        self.free_pages(addr, size.div_ceil(PAGE_SIZE), false);
+        self.vmas.free(addr, size);
     }
 
     pub fn track_msg(&mut self, id: u64, addr: u64) {
@@ -236,37 +404,165 @@ impl Process {
         self.free_alloc(addr);
     }
 
-    pub fn is_msg(&self, addr: u64) -> bool {
-        let _lock = self.alloc_lock.lock();
+    pub fn may_access_port(&self, port: u16, width: u64) -> bool {
+        let port = u64::from(port);
+        self.io_ports
+            .iter()
+            .any(|&(base, count)| port >= base && port + width <= base + count)
+    }
 
-        if !self.allocations.contains_key(&addr) {
-            panic!("PID {}: Address {addr:#X} not allocated", self.id);
-        }
+    pub fn is_mmio(&self, addr: u64) -> bool {
+        self.allocations
+            .range(..=addr)
+            .next_back()
+            .is_some_and(|(k, (v, ty))| *ty == AllocationType::Mmio && addr < k + v)
+    }
 
+    pub fn is_shared(&self, addr: u64) -> bool {
+        matches!(
+            self.allocations.get(&addr),
+            Some((_, AllocationType::Shared { .. }))
+        )
+    }
+
+    pub fn is_msg(&self, addr: u64) -> bool {
+        let _lock = self.alloc_lock.lock();
         self.addr_to_msg_id.contains_key(&addr)
     }
 
-    pub fn allocate(&mut self, size: u64) -> (u64, u64) {
+    // Memory is only reserved here, pages get backed with zeroes once they are first touched.
+    pub fn allocate(&mut self, size: u64) -> Option<u64> {
         let _lock = self.alloc_lock.lock();
 
-        let page_count = (size + 0xFFF) / 0x1000;
+        let addr = self.vmas.alloc(size)?;
+        trace!(
+            "PID {}: Reserving {addr:#X} ({} pages, {size} bytes)",
+            self.id,
+            size.div_ceil(PAGE_SIZE),
+        );
+        self.allocations.insert(addr, (size, AllocationType::Lazy));
+        Some(addr)
+    }
+
+    pub fn allocate_dma(
+        &mut self,
+        size: u64,
+        max_phys: u64,
+        align: u64,
+        cache: CacheType,
+    ) -> Option<(u64, u64)> {
+        let page_count = size.div_ceil(PAGE_SIZE);
         trace!(
-            "PID {}: Allocating {page_count} pages ({size} bytes)",
+            "PID {}: Allocating {page_count} DMA pages ({size} bytes, below {max_phys:#X}, {cache:?})",
             self.id
         );
-        let addr = unsafe {
-            (*crate::system::state::SYS_STATE.get())
+        let phys = unsafe {
+            let phys = (*crate::system::state::SYS_STATE.get())
                 .pmm
                 .as_ref()
                 .unwrap()
                 .lock()
-                .alloc(page_count)
-                .unwrap() as u64
+                .alloc_below(page_count, max_phys, align)? as u64;
+            core::ptr::write_bytes(
+                (phys + amd64::paging::PHYS_VIRT_OFFSET) as *mut u8,
+                0,
+                (page_count * PAGE_SIZE) as _,
+            );
+            phys
+        };
+        let Some(addr) = self.track_alloc(phys, size, AllocationType::Dma(cache)) else {
+            unsafe {
+                (*crate::system::state::SYS_STATE.get())
+                    .pmm
+                    .as_ref()
+                    .unwrap()
+                    .lock()
+                    .free(phys as *mut _, page_count);
+            }
+            return None;
         };
-        let virt = addr + fireworkkit::USER_VIRT_OFFSET;
+        Some((addr, phys))
+    }
+
+    pub fn map_mmio(&mut self, phys: u64, size: u64) -> Option<u64> {
+        trace!("PID {}: Mapping MMIO {phys:#X} ({size} bytes)", self.id);
+        self.track_alloc(phys, size, AllocationType::Mmio)
+    }
+
+    fn back_page(&self, page: u64) -> Option<u64> {
+        let mut cr3 = self.cr3.lock();
+        if let Some(phys) = unsafe { cr3.virt_to_phys(page) } {
+            return Some(phys);
+        }
+
+        unsafe {
+            let phys = (*crate::system::state::SYS_STATE.get())
+                .pmm
+                .as_ref()
+                .unwrap()
+                .lock()
+                .alloc(1)? as u64;
+            core::ptr::write_bytes(
+                (phys + amd64::paging::PHYS_VIRT_OFFSET) as *mut u8,
+                0,
+                PAGE_SIZE as _,
+            );
+            cr3.map(
+                page,
+                phys,
+                1,
+                PageTableFlags::new_present()
+                    .with_writable(true)
+                    .with_user(true),
+            );
+            Some(phys)
+        }
+    }
+
+    // Called on non-present faults, returns whether the access can be retried, or `None` if the
+    // page is lazily allocated but there is no memory left to back it.
+    pub fn handle_fault(&self, addr: u64) -> Option<bool> {
+        let _lock = self.alloc_lock.lock();
+
+        let is_lazy = self
+            .allocations
+            .range(..=addr)
+            .next_back()
+            .is_some_and(|(&k, &(v, ty))| {
+                ty == AllocationType::Lazy && addr < k + v.div_ceil(PAGE_SIZE) * PAGE_SIZE
+            });
         drop(_lock);
-        self.track_alloc(virt, size, AllocationType::Writable);
-        (virt, page_count)
+        if is_lazy {
+            self.back_page(addr & !(PAGE_SIZE - 1))?;
+        }
+        Some(is_lazy)
+    }
+
+    // Backs every page of the range and returns their physical addresses.
+    pub fn populate(&self, addr: u64, size: u64) -> Option<Vec<u64>> {
+        let start = addr & !(PAGE_SIZE - 1);
+        (start..addr + size)
+            .step_by(PAGE_SIZE as _)
+            .map(|page| self.back_page(page))
+            .collect()
+    }
+
+    pub fn write(&self, addr: u64, data: &[u8]) {
+        let mut off = 0;
+        while off < data.len() {
+            let virt = addr + off as u64;
+            let page_off = virt & (PAGE_SIZE - 1);
+            let len = ((PAGE_SIZE - page_off) as usize).min(data.len() - off);
+            let phys = self.back_page(virt - page_off).unwrap();
+            unsafe {
+                core::ptr::copy_nonoverlapping(
+                    data[off..].as_ptr(),
+                    (phys + page_off + amd64::paging::PHYS_VIRT_OFFSET) as *mut u8,
+                    len,
+                );
+            }
+            off += len;
+        }
     }
 }
 
@@ -0,0 +1,81 @@
+// Copyright (c) ChefKiss Inc 2021-2023. Licensed under the Thou Shalt Not Profit License version 1.5. See LICENSE for details.
+
+use alloc::collections::VecDeque;
+
+use fireworkkit::syscall::ThreadPriority;
+
+const PRIORITY_COUNT: usize = 3;
+
+pub const fn time_slice(priority: ThreadPriority) -> u64 {
+    match priority {
+        ThreadPriority::High => 10,
+        ThreadPriority::Normal => 5,
+        ThreadPriority::Low => 2,
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct RunQueue {
+    pub current_tid: Option<u64>,
+    pub current_pid: Option<u64>,
+    pub current_priority: ThreadPriority,
+    pub remaining_ticks: u64,
+    queues: [VecDeque<u64>; PRIORITY_COUNT],
+}
+
+impl RunQueue {
+    #[inline]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, tid: u64, priority: ThreadPriority) {
+        self.queues[priority as usize].push_back(tid);
+    }
+
+    pub fn pop(&mut self) -> Option<(u64, ThreadPriority)> {
+        self.queues
+            .iter_mut()
+            .zip([
+                ThreadPriority::High,
+                ThreadPriority::Normal,
+                ThreadPriority::Low,
+            ])
+            .find_map(|(queue, priority)| Some((queue.pop_front()?, priority)))
+    }
+
+    pub fn steal(&mut self) -> Option<(u64, ThreadPriority)> {
+        self.queues
+            .iter_mut()
+            .zip([
+                ThreadPriority::High,
+                ThreadPriority::Normal,
+                ThreadPriority::Low,
+            ])
+            .find_map(|(queue, priority)| Some((queue.pop_back()?, priority)))
+    }
+
+    pub fn remove(&mut self, tid: u64) {
+        for queue in &mut self.queues {
+            queue.retain(|&v| v != tid);
+        }
+    }
+
+    pub fn has_higher_priority(&self, priority: ThreadPriority) -> bool {
+        self.queues[..priority as usize]
+            .iter()
+            .any(|v| !v.is_empty())
+    }
+
+    pub const fn is_idle(&self) -> bool {
+        self.current_tid.is_none()
+    }
+
+    pub fn tick(&mut self) -> bool {
+        if self.is_idle() || self.has_higher_priority(self.current_priority) {
+            return false;
+        }
+        self.remaining_ticks = self.remaining_ticks.saturating_sub(1);
+        self.remaining_ticks != 0
+    }
+}
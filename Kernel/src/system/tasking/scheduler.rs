@@ -1,69 +1,121 @@
 // Copyright (c) ChefKiss Inc 2021-2023. Licensed under the Thou Shalt Not Profit License version 1.5. See LICENSE for details.
 
-use alloc::{string::String, vec::Vec};
-use core::{cell::SyncUnsafeCell, ops::ControlFlow};
+use alloc::{collections::BTreeMap, string::String, vec::Vec};
+use core::{
+    ops::ControlFlow,
+    sync::atomic::{AtomicU64, Ordering},
+};
 
+use amd64::{
+    msr::{
+        seg_base::{FSBase, GSBase},
+        ModelSpecificReg,
+    },
+    paging::PAGE_SIZE,
+};
 use fireworkkit::{
     msg::{KernelMessage, Message},
-    TerminationReason,
+    syscall::{IRQMode, ThreadPriority},
+    Error, TerminationReason,
 };
-use hashbrown::HashMap;
+use hashbrown::{HashMap, HashSet};
 
+use super::{
+    capability::{Endpoint, Object, SharedRegion},
+    run_queue::{self, RunQueue},
+};
 use crate::{
+    acpi::ioapic::GSIRoute,
+    incr_id::IncrementalIDGen,
+    interrupts::idt::HandlerFn,
     system::{
         gdt::{PrivilegeLevel, SegmentSelector},
+        smp,
         tasking::AllocationType,
-        tss::TaskSegmentSelector,
         RegisterState,
     },
     timer::Timer,
 };
 
-static TSS: SyncUnsafeCell<TaskSegmentSelector> = SyncUnsafeCell::new(TaskSegmentSelector::new(0));
+pub const TICK_NS: u64 = 1_000_000;
+
+#[derive(Debug, Default)]
+pub struct IRQLine {
+    pub subscribers: Vec<u64>,
+    // Subscribers that haven't acknowledged the last interrupt yet, the line stays masked until then.
+    pub pending: HashSet<u64>,
+    // None for message signalled interrupts, which don't go through an I/O APIC.
+    pub gsi: Option<u32>,
+    // Runs before subscribers are notified, lines the kernel listens on stay wired without them.
+    pub kernel_handler: Option<HandlerFn>,
+}
+
+pub struct MessageRoute {
+    pub src: u64,
+    pub dst: u64,
+    // Where the receiver sees the buffer.
+    pub addr: u64,
+    pub size: u64,
+}
+
+pub struct PendingCall {
+    pub tid: u64,
+    pub pid: u64,
+    pub target: u64,
+    pub acked: bool,
+    pub cancelled: bool,
+}
 
 pub struct Scheduler {
-    pub processes: HashMap<u64, super::Process>,
-    pub threads: HashMap<u64, super::Thread>,
-    pub current_tid: Option<u64>,
-    pub current_pid: Option<u64>,
-    pub kern_stack: Vec<u8>,
-    pub irq_handlers: HashMap<u8, u64>,
-    pub message_sources: HashMap<u64, u64>,
-    pub pid_gen: crate::incr_id::IncrementalIDGen,
-    pub tid_gen: crate::incr_id::IncrementalIDGen,
-    pub msg_id_gen: crate::incr_id::IncrementalIDGen,
+    pub processes: spin::RwLock<HashMap<u64, spin::Mutex<super::Process>>>,
+    pub threads: spin::RwLock<HashMap<u64, spin::Mutex<super::Thread>>>,
+    pub run_queues: Vec<spin::Mutex<RunQueue>>,
+    pub irq_handlers: spin::Mutex<HashMap<u8, IRQLine>>,
+    pub message_routes: spin::Mutex<HashMap<u64, MessageRoute>>,
+    pub pending_calls: spin::Mutex<HashMap<u64, PendingCall>>,
+    pub endpoints: spin::RwLock<HashMap<u64, Endpoint>>,
+    pub endpoint_id_gen: spin::Mutex<IncrementalIDGen>,
+    pub shm_regions: spin::Mutex<HashMap<u64, SharedRegion>>,
+    pub shm_id_gen: spin::Mutex<IncrementalIDGen>,
+    pub pid_gen: spin::Mutex<IncrementalIDGen>,
+    pub tid_gen: spin::Mutex<IncrementalIDGen>,
+    pub msg_id_gen: spin::Mutex<IncrementalIDGen>,
+    pub ticks: AtomicU64,
+    pub sleep_queue: spin::Mutex<BTreeMap<u64, Vec<u64>>>,
 }
 
 unsafe extern "sysv64" fn irq_handler(state: &mut RegisterState) {
-    let irq = (state.int_num - 0x20) as u8;
-    crate::acpi::ioapic::set_irq_mask(irq, true);
-    let mut this = (*crate::system::state::SYS_STATE.get())
+    let vector = state.int_num as u8;
+    let this = (*crate::system::state::SYS_STATE.get())
         .scheduler
         .as_ref()
-        .unwrap()
-        .lock();
-    let pid = this.irq_handlers.get(&irq).copied().unwrap();
-    let s: &mut [u8] = postcard::to_allocvec(&KernelMessage::IRQFired(irq))
-        .unwrap()
-        .leak();
+        .unwrap();
+    let (subscribers, kernel_handler) = {
+        let mut irq_handlers = this.irq_handlers.lock();
+        let Some(line) = irq_handlers.get_mut(&vector) else {
+            return;
+        };
+        // Message signalled interrupts are edge-triggered, so there is no line to mask. The kernel handles
+        // its part right away, the line only has to wait for subscribers.
+        if let Some(gsi) = line.gsi.filter(|_| !line.subscribers.is_empty()) {
+            crate::acpi::ioapic::set_gsi_mask(gsi, true);
+            line.pending.extend(&line.subscribers);
+        }
+        (line.subscribers.clone(), line.kernel_handler)
+    };
+    if let Some(handler) = kernel_handler {
+        handler(state);
+    }
 
-    let virt = this
-        .processes
-        .get_mut(&pid)
-        .unwrap()
-        .track_kernelside_alloc(s.as_ptr() as _, s.len() as _);
-
-    let msg = Message::new(
-        this.msg_id_gen.next(),
-        0,
-        core::slice::from_raw_parts(virt as *const _, s.len() as _),
-    );
-    this.message_sources.insert(msg.id, 0);
-    let process = this.processes.get_mut(&pid).unwrap();
-    process.track_msg(msg.id, virt);
-
-    let tids = process.thread_ids.clone();
-    if super::userland::handlers::msg::handle_new(&mut this, pid, tids, msg).is_break() {
+    let mut reschedule = false;
+    for pid in subscribers {
+        match this.post_kernel_message(pid, &KernelMessage::IRQFired(vector)) {
+            Some(flow) => reschedule |= flow.is_break(),
+            // Nobody is going to acknowledge an interrupt that was never delivered.
+            None => this.ack_irq(vector, pid),
+        }
+    }
+    if reschedule {
         this.schedule(state);
     }
 }
@@ -73,61 +125,77 @@ extern "C" fn idle() {
 }
 
 pub unsafe extern "sysv64" fn schedule(state: &mut RegisterState) {
+    let this = (*crate::system::state::SYS_STATE.get())
+        .scheduler
+        .as_ref()
+        .unwrap();
+    if smp::current_index() == 0 {
+        this.tick();
+    }
+    if this.run_queue().lock().tick() {
+        return;
+    }
+    this.schedule(state);
+}
+
+unsafe extern "sysv64" fn reschedule(state: &mut RegisterState) {
     (*crate::system::state::SYS_STATE.get())
         .scheduler
         .as_ref()
         .unwrap()
-        .lock()
         .schedule(state);
 }
 
+unsafe fn use_kernel_pml4() {
+    (*crate::system::state::SYS_STATE.get())
+        .pml4
+        .as_ref()
+        .unwrap()
+        .lock()
+        .set_cr3();
+}
+
 impl Scheduler {
     #[inline]
     pub fn new(timer: &impl Timer) -> Self {
-        let kern_stack = vec![0; 0x14000];
-
-        unsafe {
-            let gdt = &mut *crate::system::gdt::GDT.get();
-            (*TSS.get()) =
-                TaskSegmentSelector::new(kern_stack.as_ptr() as u64 + kern_stack.len() as u64);
-            let tss_addr = TSS.get() as u64;
-            gdt.task_segment.base_low = tss_addr as u16;
-            gdt.task_segment.base_middle = (tss_addr >> 16) as u8;
-            gdt.task_segment.attrs = gdt.task_segment.attrs.with_present(true);
-            gdt.task_segment.base_high = (tss_addr >> 24) as u8;
-            gdt.task_segment.base_upper = (tss_addr >> 32) as u32;
-
-            core::arch::asm!(
-                "ltr ax",
-                in("ax") SegmentSelector::new(5, crate::system::gdt::PrivilegeLevel::Supervisor).0,
-                options(nostack, preserves_flags),
-            );
-        }
-
         let state = unsafe { &mut *crate::system::state::SYS_STATE.get() };
-        state.lapic.as_ref().unwrap().setup_timer(timer);
+        state.lapic.as_mut().unwrap().setup_timer(timer);
+        let cpu_count = state.cpus.as_ref().unwrap().len();
 
         crate::interrupts::idt::set_handler(
             128,
-            1,
+            0,
             PrivilegeLevel::Supervisor,
             schedule,
             true,
             true,
         );
+        crate::interrupts::idt::set_handler(
+            129,
+            0,
+            PrivilegeLevel::Supervisor,
+            reschedule,
+            true,
+            true,
+        );
         crate::acpi::ioapic::wire_legacy_irq(96, false);
 
         Self {
-            processes: HashMap::new(),
-            threads: HashMap::new(),
-            current_tid: None,
-            current_pid: None,
-            kern_stack,
-            irq_handlers: HashMap::new(),
-            message_sources: HashMap::new(),
-            pid_gen: crate::incr_id::IncrementalIDGen::new(),
-            tid_gen: crate::incr_id::IncrementalIDGen::new(),
-            msg_id_gen: crate::incr_id::IncrementalIDGen::new(),
+            processes: spin::RwLock::new(HashMap::new()),
+            threads: spin::RwLock::new(HashMap::new()),
+            run_queues: (0..cpu_count).map(|_| RunQueue::new().into()).collect(),
+            irq_handlers: spin::Mutex::new(HashMap::new()),
+            message_routes: spin::Mutex::new(HashMap::new()),
+            pending_calls: spin::Mutex::new(HashMap::new()),
+            endpoints: spin::RwLock::new(HashMap::new()),
+            endpoint_id_gen: IncrementalIDGen::new().into(),
+            shm_regions: spin::Mutex::new(HashMap::new()),
+            shm_id_gen: IncrementalIDGen::new().into(),
+            pid_gen: IncrementalIDGen::new().into(),
+            tid_gen: IncrementalIDGen::new().into(),
+            msg_id_gen: IncrementalIDGen::new().into(),
+            ticks: AtomicU64::new(0),
+            sleep_queue: spin::Mutex::new(BTreeMap::new()),
         }
     }
 
@@ -139,7 +207,14 @@ impl Scheduler {
         unsafe { core::arch::asm!("int 128", options(nostack, preserves_flags)) }
     }
 
-    pub fn spawn_proc(&mut self, path: String, exec_data: &[u8]) -> &mut super::Thread {
+    pub fn spawn_proc(
+        &self,
+        path: String,
+        exec_data: &[u8],
+        dt_entry: u64,
+        io_ports: Vec<(u64, u64)>,
+        privileged: bool,
+    ) -> u64 {
         let exec = elf::ElfBytes::<elf::endian::NativeEndian>::minimal_parse(exec_data).unwrap();
         assert_eq!(exec.ehdr.e_type, elf::abi::ET_DYN);
         assert_eq!(exec.ehdr.class, elf::file::Class::ELF64);
@@ -152,7 +227,15 @@ impl Scheduler {
             .map(|v| v.p_vaddr + v.p_memsz)
             .max()
             .unwrap();
-        let data = vec![0; max_vaddr as usize].leak();
+
+        let pid = self.pid_gen.lock().next();
+        let mut proc = super::Process::new(pid, path, 0, dt_entry);
+        proc.io_ports = io_ports;
+        proc.privileged = privileged;
+        unsafe { proc.cr3.lock().map_higher_half() }
+        // Only pages with file data or relocations get backed now, the rest is zero-filled on demand.
+        let virt_addr = proc.allocate(max_vaddr).unwrap();
+        proc.image_base = virt_addr;
         for hdr in exec
             .segments()
             .unwrap()
@@ -161,153 +244,748 @@ impl Scheduler {
         {
             let fsz = hdr.p_filesz as usize;
             let foff = hdr.p_offset as usize;
-            let ext_vaddr = hdr.p_vaddr as usize;
-            data[ext_vaddr..ext_vaddr + fsz].copy_from_slice(&exec_data[foff..foff + fsz]);
+            proc.write(virt_addr + hdr.p_vaddr, &exec_data[foff..foff + fsz]);
         }
 
-        let virt_addr =
-            data.as_ptr() as u64 - amd64::paging::PHYS_VIRT_OFFSET + fireworkkit::USER_VIRT_OFFSET;
         for v in exec.section_headers().unwrap().iter() {
             let Ok(relas) = exec.section_data_as_relas(&v) else {
                 continue;
             };
             for reloc in relas {
-                let ptr = unsafe { &mut *data.as_mut_ptr().add(reloc.r_offset as _).cast::<u64>() };
-                match reloc.r_type {
-                    elf::abi::R_X86_64_NONE => {}
+                let value = match reloc.r_type {
+                    elf::abi::R_X86_64_NONE => continue,
                     elf::abi::R_X86_64_RELATIVE => {
-                        *ptr = virt_addr.checked_add_signed(reloc.r_addend).unwrap()
+                        virt_addr.checked_add_signed(reloc.r_addend).unwrap()
                     }
                     v => unimplemented!("{v:#X?}"),
-                }
+                };
+                proc.write(virt_addr + reloc.r_offset, &value.to_ne_bytes());
             }
         }
 
-        let pid = self.pid_gen.next();
-        let proc = self
-            .processes
-            .try_insert(pid, super::Process::new(pid, path, virt_addr))
+        let tid = self.tid_gen.lock().next();
+        let stack_addr = proc.allocate(super::STACK_SIZE).unwrap();
+        let mut thread = proc
+            .new_thread(tid, virt_addr + exec.ehdr.e_entry, stack_addr)
             .unwrap();
-        unsafe { proc.cr3.lock().map_higher_half() }
-        proc.track_alloc(virt_addr, data.len() as _, AllocationType::Writable);
-        let tid = self.tid_gen.next();
-        let stack_addr = proc.allocate(super::STACK_SIZE).0;
-        let thread = proc.new_thread(tid, virt_addr + exec.ehdr.e_entry, stack_addr);
-        self.threads.try_insert(tid, thread).unwrap()
+        thread.regs.rdi = dt_entry;
+        let priority = thread.priority;
+
+        self.processes.write().try_insert(pid, proc.into()).unwrap();
+        self.threads.write().try_insert(tid, thread.into()).unwrap();
+        self.run_queue().lock().push(tid, priority);
+        pid
     }
 
-    pub fn current_thread_mut(&mut self) -> Option<&mut super::Thread> {
-        self.threads.get_mut(&self.current_tid?)
+    pub fn run_queue(&self) -> &spin::Mutex<RunQueue> {
+        &self.run_queues[smp::current_index()]
     }
 
-    pub fn current_process(&self) -> Option<&super::Process> {
-        self.processes.get(&self.current_pid?)
+    pub fn current_tid(&self) -> Option<u64> {
+        self.run_queue().lock().current_tid
     }
 
-    pub fn current_process_mut(&mut self) -> Option<&mut super::Process> {
-        self.processes.get_mut(&self.current_pid?)
+    pub fn current_pid(&self) -> Option<u64> {
+        self.run_queue().lock().current_pid
     }
 
-    pub fn next_thread_mut(&mut self) -> Option<&mut super::Thread> {
-        let mut i = self.current_tid.map(|v| v + 1).unwrap_or_default() as usize;
-        if i > self.threads.len() {
-            i = 0;
-        }
+    pub fn with_process<R>(&self, pid: u64, f: impl FnOnce(&mut super::Process) -> R) -> Option<R> {
+        self.processes.read().get(&pid).map(|v| f(&mut v.lock()))
+    }
+
+    pub fn with_current_process<R>(&self, f: impl FnOnce(&mut super::Process) -> R) -> R {
+        self.with_process(self.current_pid().unwrap(), f).unwrap()
+    }
+
+    pub fn with_thread<R>(&self, tid: u64, f: impl FnOnce(&mut super::Thread) -> R) -> Option<R> {
+        self.threads.read().get(&tid).map(|v| f(&mut v.lock()))
+    }
 
-        self.threads
-            .values_mut()
-            .skip(i)
-            .find(|v| v.state.is_inactive())
+    pub fn with_current_thread<R>(&self, f: impl FnOnce(&mut super::Thread) -> R) -> R {
+        self.with_thread(self.current_tid().unwrap(), f).unwrap()
     }
 
-    pub unsafe fn schedule(&mut self, state: &mut RegisterState) {
-        if let Some(old_thread) = self.current_thread_mut() {
-            old_thread.regs = *state;
-            if !old_thread.state.is_suspended() {
-                old_thread.state = super::ThreadState::Inactive;
+    // Returns None if the message had to be dropped.
+    pub fn post_kernel_message(
+        &self,
+        pid: u64,
+        msg: &KernelMessage,
+    ) -> Option<ControlFlow<Option<TerminationReason>>> {
+        let data = postcard::to_allocvec(msg).unwrap();
+
+        let msg_id = self.msg_id_gen.lock().next();
+        let mut routes = self.message_routes.lock();
+        // The message is dropped if the process is gone or has no address space left for it.
+        let msg = self.with_process(pid, |process| {
+            let virt = process.track_kernelside_alloc(data.as_ptr() as _, data.len() as _)?;
+            let s = data.leak();
+            process.track_msg(msg_id, virt);
+            Some(Message::new(
+                msg_id,
+                0,
+                unsafe { core::slice::from_raw_parts(virt as *const _, s.len() as _) },
+                None,
+            ))
+        });
+        let Some(msg) = msg.flatten() else {
+            self.msg_id_gen.lock().free(msg_id);
+            return None;
+        };
+        routes.insert(
+            msg_id,
+            MessageRoute {
+                src: 0,
+                dst: pid,
+                addr: msg.data.as_ptr() as _,
+                size: msg.data.len() as _,
+            },
+        );
+        drop(routes);
+
+        Some(super::userland::handlers::msg::handle_new(self, pid, msg))
+    }
+
+    pub fn ticks(&self) -> u64 {
+        self.ticks.load(Ordering::Relaxed)
+    }
+
+    pub fn sleep_until(&self, tid: u64, deadline: u64) {
+        self.sleep_queue
+            .lock()
+            .entry(deadline)
+            .or_default()
+            .push(tid);
+    }
+
+    fn tick(&self) {
+        let now = self.ticks.fetch_add(1, Ordering::Relaxed) + 1;
+        let expired = {
+            let mut sleep_queue = self.sleep_queue.lock();
+            let pending = sleep_queue.split_off(&(now + 1));
+            core::mem::replace(&mut *sleep_queue, pending)
+        };
+
+        for (deadline, tid) in expired
+            .into_iter()
+            .flat_map(|(deadline, tids)| tids.into_iter().map(move |tid| (deadline, tid)))
+        {
+            // Threads that were woken up or reaped in the meantime leave stale entries behind.
+            let priority = self
+                .with_thread(tid, |thread| {
+                    if !(thread.state.is_suspended() || thread.state.is_sleeping())
+                        || thread.deadline != Some(deadline)
+                    {
+                        return None;
+                    }
+                    thread.state = super::ThreadState::Inactive;
+                    thread.deadline = None;
+                    thread.regs.rax = 0;
+                    Some(thread.priority)
+                })
+                .flatten();
+            if let Some(priority) = priority {
+                self.run_queue().lock().push(tid, priority);
             }
         }
+    }
 
-        let Some(thread) = self.next_thread_mut() else {
-            *state = RegisterState {
-                rip: idle as usize as _,
-                cs: SegmentSelector::new(1, PrivilegeLevel::Supervisor).into(),
-                rflags: 0x202,
-                rsp: self.kern_stack.as_ptr() as u64 + self.kern_stack.len() as u64,
-                ss: SegmentSelector::new(2, PrivilegeLevel::Supervisor).into(),
-                ..Default::default()
-            };
+    pub fn release_shm(&self, id: u64) {
+        let mut shm_regions = self.shm_regions.lock();
+        let Some(region) = shm_regions.get_mut(&id) else {
+            return;
+        };
+        region.refs -= 1;
+        if region.refs != 0 {
+            return;
+        }
+
+        let region = shm_regions.remove(&id).unwrap();
+        self.shm_id_gen.lock().free(id);
+        unsafe {
             (*crate::system::state::SYS_STATE.get())
-                .pml4
+                .pmm
                 .as_ref()
                 .unwrap()
                 .lock()
-                .set_cr3();
-            self.current_tid = None;
-            self.current_pid = None;
-            return;
+                .free(region.phys as *mut _, region.size.div_ceil(0x1000));
+        }
+    }
+
+    fn steal(&self, idx: usize) -> Option<(u64, ThreadPriority)> {
+        let count = self.run_queues.len();
+        (1..count)
+            .map(|i| &self.run_queues[(idx + i) % count])
+            .find_map(|v| v.try_lock()?.steal())
+    }
+
+    pub unsafe fn schedule(&self, state: &mut RegisterState) {
+        let idx = smp::current_index();
+        let run_queue = &self.run_queues[idx];
+
+        let prev = {
+            let mut run_queue = run_queue.lock();
+            run_queue.current_pid = None;
+            run_queue.current_tid.take()
         };
+        if let Some(tid) = prev {
+            let dying = self
+                .with_thread(tid, |thread| {
+                    crate::system::fpu::switch_out(smp::current(), thread);
+                    if thread.cpu != Some(idx) {
+                        return None;
+                    }
+                    thread.cpu = None;
+                    if thread.state.is_dying() {
+                        return Some(thread.pid);
+                    }
+                    thread.regs = *state;
+                    if thread.state == super::ThreadState::Active {
+                        thread.state = super::ThreadState::Inactive;
+                        run_queue.lock().push(tid, thread.priority);
+                    }
+                    None
+                })
+                .flatten();
+            if let Some(pid) = dying {
+                use_kernel_pml4();
+                self.reap_thread(pid, tid);
+            }
+        }
 
-        *state = thread.regs;
-        thread.state = super::ThreadState::Active;
-        let pid = thread.pid;
-        let tid = Some(thread.id);
-        self.processes.get_mut(&pid).unwrap().cr3.lock().set_cr3();
-        self.current_tid = tid;
-        self.current_pid = Some(pid);
+        // Threads whose kernel stack is still in use by another CPU can't be resumed just yet.
+        let mut busy = Vec::new();
+        self.switch_next(idx, state, &mut busy);
+        let mut run_queue = run_queue.lock();
+        for (tid, priority) in busy {
+            run_queue.push(tid, priority);
+        }
+    }
+
+    unsafe fn switch_next(
+        &self,
+        idx: usize,
+        state: &mut RegisterState,
+        busy: &mut Vec<(u64, ThreadPriority)>,
+    ) {
+        let run_queue = &self.run_queues[idx];
+        let cpu = smp::current();
+
+        loop {
+            let next = run_queue.lock().pop();
+            let Some((tid, priority)) = next.or_else(|| self.steal(idx)) else {
+                *state = RegisterState {
+                    rip: idle as usize as _,
+                    cs: SegmentSelector::new(1, PrivilegeLevel::Supervisor).into(),
+                    rflags: 0x202,
+                    rsp: cpu.kern_stack_top(),
+                    ss: SegmentSelector::new(2, PrivilegeLevel::Supervisor).into(),
+                    ..Default::default()
+                };
+                cpu.prepare_switch(0, cpu.kern_stack_top());
+                use_kernel_pml4();
+                return;
+            };
+
+            if smp::stack_holder(tid).is_some_and(|v| v != idx) {
+                busy.push((tid, priority));
+                continue;
+            }
+
+            let Some(Some((pid, priority, stack_top))) = self.with_thread(tid, |thread| {
+                if !thread.state.is_inactive() {
+                    return None;
+                }
+                thread.state = super::ThreadState::Active;
+                thread.cpu = Some(idx);
+                *state = thread.regs;
+                super::userland::syscall::leave_fast_frame(state);
+                crate::system::fpu::switch_in(cpu, idx, thread);
+                FSBase(thread.fs_base as _).write();
+                GSBase(thread.gs_base as _).write();
+                Some((thread.pid, thread.priority, thread.kern_stack.top()))
+            }) else {
+                continue;
+            };
+
+            if self
+                .with_process(pid, |process| process.cr3.lock().set_cr3())
+                .is_none()
+            {
+                continue;
+            }
+            cpu.prepare_switch(tid, stack_top);
+
+            let mut run_queue = run_queue.lock();
+            run_queue.current_tid = Some(tid);
+            run_queue.current_pid = Some(pid);
+            run_queue.current_priority = priority;
+            run_queue.remaining_ticks = run_queue::time_slice(priority);
+            return;
+        }
+    }
+
+    // 0x20 to 0x2F are left to the remapped 8259 PIC, whose spurious IRQs still arrive there.
+    fn free_vector(irq_handlers: &HashMap<u8, IRQLine>) -> Option<u8> {
+        (0x30..0xF0).find(|&v| !irq_handlers.contains_key(&v) && crate::interrupts::idt::is_free(v))
+    }
+
+    fn current_apic_id() -> u8 {
+        unsafe {
+            (*crate::system::state::SYS_STATE.get())
+                .lapic
+                .as_ref()
+                .unwrap()
+                .id()
+        }
     }
 
     pub fn register_irq(
-        &mut self,
-        state: &RegisterState,
+        &self,
+        state: &mut RegisterState,
     ) -> ControlFlow<Option<TerminationReason>> {
-        let irq = state.rsi as u8;
-        if irq > 0xDF {
-            return ControlFlow::Break(Some(TerminationReason::MalformedArgument));
+        let Ok(mode) = IRQMode::try_from(state.rdx) else {
+            return super::userland::error(state, Error::InvalidArgument);
+        };
+        let route = match (mode, u8::try_from(state.rsi), u32::try_from(state.rsi)) {
+            (IRQMode::Legacy, Ok(irq), _) => crate::acpi::ioapic::legacy_irq_route(irq),
+            (IRQMode::Legacy, Err(_), _) | (_, _, Err(_)) => {
+                return super::userland::error(state, Error::InvalidArgument);
+            }
+            (mode, _, Ok(gsi)) => crate::acpi::ioapic::GSIRoute {
+                gsi,
+                active_low: matches!(mode, IRQMode::EdgeLow | IRQMode::LevelLow),
+                level_triggered: matches!(mode, IRQMode::LevelHigh | IRQMode::LevelLow),
+            },
+        };
+
+        // Subscribers keep the line masked until they acknowledge, so only drivers of a device using it may join.
+        let (pid, dt_entry, privileged) =
+            self.with_current_process(|process| (process.id, process.dt_entry, process.privileged));
+        if !privileged
+            && !crate::system::fkext::device_interrupts(dt_entry).contains(&(state.rsi, mode))
+        {
+            return super::userland::error(state, Error::InsufficientPermissions);
+        }
+
+        let mut irq_handlers = self.irq_handlers.lock();
+        // INTx lines are shared, later subscribers join the line as it is already wired.
+        if let Some((&vector, line)) = irq_handlers
+            .iter_mut()
+            .find(|(_, v)| v.gsi == Some(route.gsi))
+        {
+            if line.subscribers.contains(&pid) {
+                drop(irq_handlers);
+                return super::userland::error(state, Error::AlreadyExists);
+            }
+            line.subscribers.push(pid);
+            state.rax = vector.into();
+            return ControlFlow::Continue(());
         }
-        let pid = self.current_pid.unwrap();
-        if self.irq_handlers.try_insert(irq, pid).is_err() {
-            return ControlFlow::Break(Some(TerminationReason::AlreadyExists));
+        let Some(vector) = Self::free_vector(&irq_handlers) else {
+            drop(irq_handlers);
+            return super::userland::error(state, Error::OutOfMemory);
+        };
+        if !crate::acpi::ioapic::route_gsi(route, vector, Self::current_apic_id(), true) {
+            drop(irq_handlers);
+            return super::userland::error(state, Error::NotFound);
         }
+        irq_handlers.insert(
+            vector,
+            IRQLine {
+                subscribers: vec![pid],
+                gsi: Some(route.gsi),
+                ..Default::default()
+            },
+        );
+        drop(irq_handlers);
 
-        crate::acpi::ioapic::wire_legacy_irq(irq, false);
         crate::interrupts::idt::set_handler(
-            irq + 0x20,
-            1,
+            vector,
+            0,
             PrivilegeLevel::Supervisor,
             irq_handler,
             true,
             true,
         );
+        crate::acpi::ioapic::set_gsi_mask(route.gsi, false);
+
+        state.rax = vector.into();
+        ControlFlow::Continue(())
+    }
+
+    // Extensions asking for the same GSI later join the line, instead of rerouting it away from the kernel.
+    pub fn register_kernel_irq(&self, route: GSIRoute, handler: HandlerFn) -> bool {
+        let mut irq_handlers = self.irq_handlers.lock();
+        let Some(vector) = Self::free_vector(&irq_handlers) else {
+            return false;
+        };
+        if !crate::acpi::ioapic::route_gsi(route, vector, Self::current_apic_id(), true) {
+            return false;
+        }
+        irq_handlers.insert(
+            vector,
+            IRQLine {
+                gsi: Some(route.gsi),
+                kernel_handler: Some(handler),
+                ..Default::default()
+            },
+        );
+        drop(irq_handlers);
+
+        crate::interrupts::idt::set_handler(
+            vector,
+            0,
+            PrivilegeLevel::Supervisor,
+            irq_handler,
+            true,
+            true,
+        );
+        crate::acpi::ioapic::set_gsi_mask(route.gsi, false);
+        true
+    }
+
+    pub fn register_msi(
+        &self,
+        state: &mut RegisterState,
+    ) -> ControlFlow<Option<TerminationReason>> {
+        let pid = self.current_pid().unwrap();
+        let mut irq_handlers = self.irq_handlers.lock();
+        let Some(vector) = Self::free_vector(&irq_handlers) else {
+            drop(irq_handlers);
+            return super::userland::error(state, Error::OutOfMemory);
+        };
+        irq_handlers.insert(
+            vector,
+            IRQLine {
+                subscribers: vec![pid],
+                ..Default::default()
+            },
+        );
+        drop(irq_handlers);
+
+        crate::interrupts::idt::set_handler(
+            vector,
+            0,
+            PrivilegeLevel::Supervisor,
+            irq_handler,
+            true,
+            true,
+        );
+
+        // Fixed delivery, edge-triggered, to the CPU that registered it.
+        state.rax = 0xFEE0_0000 | (u64::from(Self::current_apic_id()) << 12);
+        state.rdi = vector.into();
+        ControlFlow::Continue(())
+    }
+
+    pub fn ack_irq(&self, vector: u8, pid: u64) {
+        let mut irq_handlers = self.irq_handlers.lock();
+        let Some(line) = irq_handlers.get_mut(&vector) else {
+            return;
+        };
+        if let Some(gsi) = line
+            .gsi
+            .filter(|_| line.pending.remove(&pid) && line.pending.is_empty())
+        {
+            crate::acpi::ioapic::set_gsi_mask(gsi, false);
+        }
+    }
+
+    pub fn set_priority(
+        &self,
+        state: &mut RegisterState,
+    ) -> ControlFlow<Option<TerminationReason>> {
+        let Ok(priority) = ThreadPriority::try_from(state.rsi) else {
+            return super::userland::error(state, Error::InvalidArgument);
+        };
+        self.with_current_thread(|thread| thread.priority = priority);
+
+        let mut run_queue = self.run_queue().lock();
+        run_queue.current_priority = priority;
+        run_queue.remaining_ticks = run_queue
+            .remaining_ticks
+            .min(run_queue::time_slice(priority));
+
+        ControlFlow::Continue(())
+    }
+
+    pub fn spawn_thread(
+        &self,
+        state: &mut RegisterState,
+    ) -> ControlFlow<Option<TerminationReason>> {
+        let (entry, arg) = (state.rsi, state.rdx);
+        let tid = self.tid_gen.lock().next();
+        let thread = self.with_current_process(|process| {
+            if !process.region_is_valid(entry, 1) {
+                return Err(Error::InvalidArgument);
+            }
+            let stack_addr = process
+                .allocate(super::STACK_SIZE)
+                .ok_or(Error::OutOfMemory)?;
+            let Some(mut thread) = process.new_thread(tid, entry, stack_addr) else {
+                process.free_alloc(stack_addr);
+                return Err(Error::OutOfMemory);
+            };
+            thread.regs.rdi = arg;
+            Ok(thread)
+        });
+        let thread = match thread {
+            Ok(v) => v,
+            Err(e) => {
+                self.tid_gen.lock().free(tid);
+                return super::userland::error(state, e);
+            }
+        };
+
+        let priority = thread.priority;
+        self.threads.write().try_insert(tid, thread.into()).unwrap();
+        self.run_queue().lock().push(tid, priority);
+        state.rax = tid;
 
         ControlFlow::Continue(())
     }
 
-    pub fn thread_teardown(&mut self) -> ControlFlow<Option<TerminationReason>> {
-        let id = self.current_tid.take().unwrap();
-        self.threads.remove(&id);
-        self.tid_gen.free(id);
+    pub fn join_thread(&self, state: &mut RegisterState) -> ControlFlow<Option<TerminationReason>> {
+        let tid = state.rsi;
+        let cur_tid = self.current_tid().unwrap();
+        if tid == cur_tid {
+            return super::userland::error(state, Error::InvalidArgument);
+        }
 
-        let proc = self.current_process_mut().unwrap();
-        proc.thread_ids.remove(&id);
-        if proc.thread_ids.is_empty() {
-            let pid = self.current_pid.take().unwrap();
-            self.processes.remove(&pid);
-            self.pid_gen.free(pid);
+        let exited = self.with_current_process(|process| {
+            if process.exited_threads.remove(&tid) {
+                return Some(true);
+            }
+            if !process.thread_ids.contains(&tid) {
+                return None;
+            }
+            process.thread_joiners.entry(tid).or_default().push(cur_tid);
+            self.with_thread(cur_tid, |thread| {
+                thread.block(state, super::ThreadState::Joining);
+            });
+            Some(false)
+        });
+        match exited {
+            None => super::userland::error(state, Error::NotFound),
+            Some(true) => {
+                self.tid_gen.lock().free(tid);
+                ControlFlow::Continue(())
+            }
+            Some(false) => ControlFlow::Break(None),
         }
+    }
+
+    fn reap_thread(&self, pid: u64, tid: u64) {
+        let mut thread = self
+            .threads
+            .write()
+            .remove(&tid)
+            .map(spin::Mutex::into_inner);
+        if let Some(thread) = thread.as_mut() {
+            smp::retire_stack(tid, core::mem::take(&mut thread.kern_stack));
+        }
+        for run_queue in &self.run_queues {
+            run_queue.lock().remove(tid);
+        }
+
+        let Some((is_empty, joiners)) = self.with_process(pid, |proc| {
+            proc.thread_ids.remove(&tid);
+            let is_empty = proc.thread_ids.is_empty();
+            if let Some(thread) = thread.as_ref().filter(|_| !is_empty) {
+                proc.free_alloc(thread.stack_addr);
+                proc.free_alloc(thread.fs_base as _);
+            }
+            let joiners = proc.thread_joiners.remove(&tid).unwrap_or_default();
+            // The ID stays reserved until joined, so a late join can't end up waiting on a new thread.
+            if thread.is_some() && joiners.is_empty() {
+                proc.exited_threads.insert(tid);
+            }
+            (is_empty, joiners)
+        }) else {
+            if thread.is_some() {
+                self.tid_gen.lock().free(tid);
+            }
+            return;
+        };
+        if thread.is_some() && !joiners.is_empty() {
+            self.tid_gen.lock().free(tid);
+        }
+
+        for joiner in joiners {
+            let priority = self
+                .with_thread(joiner, |thread| {
+                    if thread.state != super::ThreadState::Joining {
+                        return None;
+                    }
+                    thread.state = super::ThreadState::Inactive;
+                    Some(thread.priority)
+                })
+                .flatten();
+            if let Some(priority) = priority {
+                self.run_queue().lock().push(joiner, priority);
+            }
+        }
+
+        if is_empty {
+            let proc = self.processes.write().remove(&pid);
+            let Some(proc) = proc.map(spin::Mutex::into_inner) else {
+                return;
+            };
+            let (dt_entry, reason) = (proc.dt_entry, proc.exit_reason);
+            self.reclaim_process(proc);
+            self.pid_gen.lock().free(pid);
+            crate::system::fkext::handle_exit(self, pid, dt_entry, reason);
+        }
+    }
+
+    fn reclaim_process(&self, mut proc: super::Process) {
+        let pid = proc.id;
+
+        // Endpoint IDs are never reused, so stale capabilities can't alias a new endpoint.
+        self.endpoints.write().retain(|_, v| v.owner != pid);
+
+        self.irq_handlers.lock().retain(|&vector, line| {
+            line.subscribers.retain(|&v| v != pid);
+            if line.subscribers.is_empty() && line.kernel_handler.is_none() {
+                if let Some(gsi) = line.gsi {
+                    crate::acpi::ioapic::set_gsi_mask(gsi, true);
+                }
+                crate::interrupts::idt::clear_handler(vector);
+                return false;
+            }
+            // The remaining subscribers shouldn't wait on an acknowledgement that never comes.
+            if let Some(gsi) = line
+                .gsi
+                .filter(|_| line.pending.remove(&pid) && line.pending.is_empty())
+            {
+                crate::acpi::ioapic::set_gsi_mask(gsi, false);
+            }
+            true
+        });
+
+        let mut orphaned = Vec::new();
+        self.pending_calls.lock().retain(|&id, call| {
+            if call.pid == pid {
+                call.cancelled = true;
+            }
+            if call.target != pid {
+                return true;
+            }
+            orphaned.push((id, call.tid, call.acked));
+            false
+        });
+        for (id, tid, acked) in orphaned {
+            if acked {
+                self.msg_id_gen.lock().free(id);
+            }
+            let priority = self
+                .with_thread(tid, |thread| {
+                    if thread.state != super::ThreadState::Calling(id) {
+                        return None;
+                    }
+                    thread.state = super::ThreadState::Inactive;
+                    thread.regs.rax = Error::NotFound.into_status();
+                    Some(thread.priority)
+                })
+                .flatten();
+            if let Some(priority) = priority {
+                self.run_queue().lock().push(tid, priority);
+            }
+        }
+
+        self.message_routes.lock().retain(|&id, route| {
+            if route.dst == pid {
+                if route.src != 0 && route.src != pid {
+                    self.with_process(route.src, |process| process.free_msg(id));
+                }
+                self.msg_id_gen.lock().free(id);
+                return false;
+            }
+            if route.src != pid {
+                return true;
+            }
+
+            // Hand the buffer over to the receiver so it stays valid until it is acknowledged.
+            let src_addr = proc.msg_id_to_addr.remove(&id).unwrap();
+            proc.addr_to_msg_id.remove(&src_addr);
+            let alloc = proc.allocations.remove(&src_addr).unwrap();
+            let moved = self.with_process(route.dst, |process| {
+                process
+                    .allocations
+                    .insert(route.addr, (route.size, AllocationType::Readable));
+                process.track_msg(id, route.addr);
+            });
+            if moved.is_none() {
+                proc.allocations.insert(src_addr, alloc);
+                self.msg_id_gen.lock().free(id);
+                return false;
+            }
+            // Only the pages covering the message are mapped by the receiver.
+            let used = route.size.div_ceil(PAGE_SIZE);
+            proc.free_pages(
+                src_addr + used * PAGE_SIZE,
+                alloc.0.div_ceil(PAGE_SIZE) - used,
+                true,
+            );
+            route.src = route.dst;
+            true
+        });
+
+        for cap in proc.caps.values() {
+            if let Object::SharedMemory(id) = cap.object {
+                self.release_shm(id);
+            }
+        }
+        for &id in proc.shm_mappings.keys() {
+            self.release_shm(id);
+        }
+
+        let mut tid_gen = self.tid_gen.lock();
+        for tid in proc.exited_threads.drain() {
+            tid_gen.free(tid);
+        }
+    }
+
+    pub fn thread_teardown(&self) -> ControlFlow<Option<TerminationReason>> {
+        let (tid, pid) = {
+            let mut run_queue = self.run_queue().lock();
+            (
+                run_queue.current_tid.take().unwrap(),
+                run_queue.current_pid.take().unwrap(),
+            )
+        };
+        unsafe { use_kernel_pml4() }
+        self.reap_thread(pid, tid);
 
         ControlFlow::Break(None)
     }
 
-    pub fn process_teardown(&mut self) {
-        // TODO: Teardown any residual messages too.
-        self.current_tid = None;
-        let pid = self.current_pid.take().unwrap();
-        let proc = self.processes.remove(&pid).unwrap();
-        for tid in &proc.thread_ids {
-            self.threads.remove(tid);
-            self.tid_gen.free(*tid);
+    pub fn process_teardown(&self, reason: TerminationReason) {
+        let idx = smp::current_index();
+        let pid = {
+            let mut run_queue = self.run_queues[idx].lock();
+            run_queue.current_tid = None;
+            run_queue.current_pid.take().unwrap()
+        };
+        unsafe { use_kernel_pml4() }
+        self.with_process(pid, |proc| proc.exit_reason = Some(reason));
+
+        let thread_ids = self
+            .with_process(pid, |proc| proc.thread_ids.clone())
+            .unwrap_or_default();
+        for tid in thread_ids {
+            // Threads running on other CPUs are reaped by those CPUs once they are descheduled.
+            let remote = self
+                .with_thread(tid, |thread| {
+                    let cpu = thread.cpu.filter(|&v| v != idx)?;
+                    thread.state = super::ThreadState::Dying;
+                    Some(cpu)
+                })
+                .flatten();
+            match remote {
+                Some(cpu) => smp::send_ipi(cpu, 129),
+                None => self.reap_thread(pid, tid),
+            }
         }
-        self.pid_gen.free(pid);
     }
 }
@@ -2,41 +2,97 @@
 
 use core::ops::ControlFlow;
 
-use fireworkkit::TerminationReason;
+use amd64::paging::PAGE_SIZE;
+use fireworkkit::{dma::CacheType, Error, TerminationReason};
 
-use crate::system::{tasking::scheduler::Scheduler, RegisterState};
+use crate::system::{
+    tasking::{scheduler::Scheduler, userland::error},
+    RegisterState,
+};
 
 pub fn alloc(
-    scheduler: &mut Scheduler,
+    scheduler: &Scheduler,
     state: &mut RegisterState,
 ) -> ControlFlow<Option<TerminationReason>> {
-    let process = scheduler.current_process_mut().unwrap();
-    let (addr, pages) = process.allocate(state.rsi);
+    let size = state.rsi;
+    let Some(addr) = scheduler.with_current_process(|process| process.allocate(size)) else {
+        return error(state, Error::OutOfMemory);
+    };
+    state.rax = addr;
+    ControlFlow::Continue(())
+}
 
-    unsafe {
-        core::ptr::write_bytes(addr as *mut u8, 0, (pages * 0x1000) as _);
+pub fn alloc_dma(
+    scheduler: &Scheduler,
+    state: &mut RegisterState,
+) -> ControlFlow<Option<TerminationReason>> {
+    let (size, max_phys, align) = (state.rsi, state.rdx, state.rcx);
+    let Ok(cache) = CacheType::try_from(state.r8) else {
+        return error(state, Error::InvalidArgument);
+    };
+    if size == 0 || (align != 0 && !align.is_power_of_two()) {
+        return error(state, Error::InvalidArgument);
     }
+    // A limit of 0 means the device can reach all of physical memory.
+    let max_phys = if max_phys == 0 { u64::MAX } else { max_phys };
+
+    let Some((addr, phys)) = scheduler
+        .with_current_process(|process| process.allocate_dma(size, max_phys, align, cache))
+    else {
+        return error(state, Error::OutOfMemory);
+    };
 
     state.rax = addr;
+    state.rdi = phys;
     ControlFlow::Continue(())
 }
 
 pub fn free(
-    scheduler: &mut Scheduler,
-    state: &RegisterState,
+    scheduler: &Scheduler,
+    state: &mut RegisterState,
 ) -> ControlFlow<Option<TerminationReason>> {
-    let addr = state.rsi;
+    let (addr, size) = (state.rsi, state.rdx);
 
-    let process = scheduler.current_process_mut().unwrap();
-    if process.is_msg(addr) {
-        return ControlFlow::Break(Some(TerminationReason::MalformedAddress));
+    let freed = scheduler.with_current_process(|process| {
+        if !process.region_is_mapped(addr, size) || process.is_msg(addr) || process.is_shared(addr)
+        {
+            return false;
+        }
+        process.free_alloc(addr);
+        true
+    });
+    if !freed {
+        return error(state, Error::InvalidArgument);
+    }
+
+    ControlFlow::Continue(())
+}
+
+pub fn dma_address(
+    scheduler: &Scheduler,
+    state: &mut RegisterState,
+) -> ControlFlow<Option<TerminationReason>> {
+    let (addr, size) = (state.rsi, state.rdx);
+    if size == 0 {
+        return error(state, Error::InvalidArgument);
     }
 
-    let size = state.rdx;
-    if process.region_is_mapped(addr, size) {
-        process.free_alloc(state.rsi);
-        ControlFlow::Continue(())
-    } else {
-        ControlFlow::Break(Some(TerminationReason::MalformedArgument))
+    let Some(pages) = scheduler.with_current_process(|process| {
+        if !process.region_is_valid(addr, size) {
+            return None;
+        }
+        Some(process.populate(addr, size))
+    }) else {
+        return ControlFlow::Break(Some(TerminationReason::MalformedAddress));
+    };
+    let Some(pages) = pages else {
+        return error(state, Error::OutOfMemory);
+    };
+    // Devices see the buffer as one block, so it has to be physically contiguous.
+    if pages.windows(2).any(|v| v[1] != v[0] + PAGE_SIZE) {
+        return error(state, Error::InvalidArgument);
     }
+
+    state.rax = pages[0] + (addr & (PAGE_SIZE - 1));
+    ControlFlow::Continue(())
 }
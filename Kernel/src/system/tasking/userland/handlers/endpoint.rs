@@ -0,0 +1,91 @@
+// Copyright (c) ChefKiss Inc 2021-2023. Licensed under the Thou Shalt Not Profit License version 1.5. See LICENSE for details.
+
+use alloc::string::String;
+use core::ops::ControlFlow;
+
+use fireworkkit::{Error, TerminationReason};
+
+use crate::system::{
+    tasking::{
+        capability::{Capability, Endpoint, Object, Rights},
+        scheduler::Scheduler,
+        userland::{error, user_buffer, Failure},
+    },
+    RegisterState,
+};
+
+fn read_name(scheduler: &Scheduler, state: &RegisterState) -> Result<Option<String>, Failure> {
+    let (addr, size) = (state.rsi, state.rdx);
+    if size == 0 {
+        return Ok(None);
+    }
+
+    core::str::from_utf8(user_buffer(scheduler, addr, size)?)
+        .map(|v| Some(v.into()))
+        .map_err(|_| Error::InvalidData.into())
+}
+
+pub fn create(
+    scheduler: &Scheduler,
+    state: &mut RegisterState,
+) -> ControlFlow<Option<TerminationReason>> {
+    let name = match read_name(scheduler, state) {
+        Ok(v) => v,
+        Err(failure) => return failure.report(state),
+    };
+
+    let id = {
+        let mut endpoints = scheduler.endpoints.write();
+        if name.is_some() && endpoints.values().any(|v| v.name == name) {
+            return error(state, Error::AlreadyExists);
+        }
+        let id = scheduler.endpoint_id_gen.lock().next();
+        endpoints.insert(
+            id,
+            Endpoint {
+                owner: scheduler.current_pid().unwrap(),
+                name,
+            },
+        );
+        id
+    };
+
+    state.rax = scheduler.with_current_process(|process| {
+        process.grant_cap(Capability {
+            object: Object::Endpoint(id),
+            rights: Rights::new().with_send(true).with_grant(true),
+        })
+    });
+
+    ControlFlow::Continue(())
+}
+
+pub fn lookup(
+    scheduler: &Scheduler,
+    state: &mut RegisterState,
+) -> ControlFlow<Option<TerminationReason>> {
+    let name = match read_name(scheduler, state) {
+        Ok(Some(v)) => v,
+        Ok(None) => return error(state, Error::InvalidArgument),
+        Err(failure) => return failure.report(state),
+    };
+
+    let Some(id) = scheduler
+        .endpoints
+        .read()
+        .iter()
+        .find(|(_, v)| v.name.as_ref() == Some(&name))
+        .map(|(&k, _)| k)
+    else {
+        return error(state, Error::NotFound);
+    };
+
+    state.rax = scheduler.with_current_process(|process| {
+        process.grant_cap(Capability {
+            object: Object::Endpoint(id),
+            rights: Rights::new().with_send(true),
+        })
+    });
+
+    ControlFlow::Continue(())
+}
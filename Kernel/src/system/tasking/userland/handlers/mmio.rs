@@ -0,0 +1,40 @@
+// Copyright (c) ChefKiss Inc 2021-2023. Licensed under the Thou Shalt Not Profit License version 1.5. See LICENSE for details.
+
+use core::ops::ControlFlow;
+
+use amd64::paging::PAGE_SIZE;
+use fireworkkit::{osdtentry::MMIO_RANGES_KEY, Error, TerminationReason};
+
+use crate::system::{
+    tasking::{scheduler::Scheduler, userland::error},
+    RegisterState,
+};
+
+pub fn map(
+    scheduler: &Scheduler,
+    state: &mut RegisterState,
+) -> ControlFlow<Option<TerminationReason>> {
+    let (phys, size) = (state.rsi, state.rdx);
+    let Some(end) = phys.checked_add(size).filter(|_| size != 0) else {
+        return error(state, Error::InvalidArgument);
+    };
+
+    let dt_entry = scheduler.with_current_process(|process| process.dt_entry);
+    let ranges = crate::system::fkext::device_ranges(dt_entry, MMIO_RANGES_KEY);
+    if !ranges
+        .iter()
+        .any(|&(base, len)| phys >= base && end <= base + len)
+    {
+        return error(state, Error::InsufficientPermissions);
+    }
+
+    // Registers don't have to start on a page boundary, the offset is kept in the returned address.
+    let offset = phys & (PAGE_SIZE - 1);
+    let Some(addr) =
+        scheduler.with_current_process(|process| process.map_mmio(phys - offset, size + offset))
+    else {
+        return error(state, Error::OutOfMemory);
+    };
+    state.rax = addr + offset;
+    ControlFlow::Continue(())
+}
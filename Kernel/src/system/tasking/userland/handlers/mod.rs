@@ -2,33 +2,34 @@
 
 use core::{fmt::Write, ops::ControlFlow};
 
-use fireworkkit::TerminationReason;
+use fireworkkit::{Error, TerminationReason};
 
+use super::{error, user_buffer};
 use crate::system::{tasking::scheduler::Scheduler, RegisterState};
 
 pub mod alloc;
+pub mod endpoint;
+pub mod mmio;
 pub mod msg;
 pub mod os_dt_entry;
 pub mod port;
+pub mod power;
+pub mod shm;
+pub mod time;
 
 pub fn kprint(
     scheduler: &Scheduler,
-    state: &RegisterState,
+    state: &mut RegisterState,
 ) -> ControlFlow<Option<TerminationReason>> {
     let addr = state.rsi;
     let size = state.rdx;
 
-    if !scheduler
-        .current_process()
-        .unwrap()
-        .region_is_valid(addr, size)
-    {
-        return ControlFlow::Break(Some(TerminationReason::MalformedAddress));
-    }
-
-    let s = unsafe { core::slice::from_raw_parts(addr as *const _, size as _) };
+    let s = match user_buffer(scheduler, addr, size) {
+        Ok(v) => v,
+        Err(failure) => return failure.report(state),
+    };
     let Ok(s) = core::str::from_utf8(s) else {
-        return ControlFlow::Break(Some(TerminationReason::MalformedBody));
+        return error(state, Error::InvalidData);
     };
 
     write!(crate::system::serial::SERIAL.lock(), "{s}").unwrap();
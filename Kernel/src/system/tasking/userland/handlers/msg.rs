@@ -2,136 +2,360 @@
 
 use core::ops::ControlFlow;
 
-use amd64::paging::{PageTableFlags, PAGE_SIZE};
 use fireworkkit::{
     msg::{KernelMessage, Message},
-    TerminationReason,
+    Error, TerminationReason,
 };
-use hashbrown::HashSet;
 
 use crate::system::{
-    tasking::{scheduler::Scheduler, ThreadState},
+    tasking::{
+        capability::Object,
+        scheduler::{MessageRoute, PendingCall, Scheduler},
+        userland::{error, Failure},
+        ThreadState,
+    },
     RegisterState,
 };
 
+fn deliver(regs: &mut RegisterState, msg: &Message) {
+    regs.rax = msg.id;
+    regs.rdi = msg.pid;
+    regs.rsi = msg.data.as_ptr() as _;
+    regs.rdx = msg.data.len() as _;
+    regs.rcx = msg.cap.unwrap_or_default();
+}
+
 pub fn handle_new(
-    scheduler: &mut Scheduler,
+    scheduler: &Scheduler,
     pid: u64,
-    tids: HashSet<u64>,
     msg: Message,
 ) -> ControlFlow<Option<TerminationReason>> {
-    let idle = scheduler.current_tid.is_none();
-    for tid in tids {
-        let thread = scheduler.threads.get_mut(&tid).unwrap();
-        if !thread.state.is_suspended() {
-            continue;
-        }
-        thread.state = ThreadState::Inactive;
-        thread.regs.rax = msg.id;
-        thread.regs.rdi = msg.pid;
-        thread.regs.rsi = msg.data.as_ptr() as _;
-        thread.regs.rdx = msg.data.len() as _;
-        if idle {
-            return ControlFlow::Break(None);
+    let idle = scheduler.current_tid().is_none();
+    let Some(woken) = scheduler.with_process(pid, |process| {
+        for tid in &process.thread_ids {
+            let woken = scheduler.with_thread(*tid, |thread| {
+                if !thread.state.is_suspended() {
+                    return None;
+                }
+                thread.state = ThreadState::Inactive;
+                thread.deadline = None;
+                deliver(&mut thread.regs, &msg);
+                Some((thread.id, thread.priority))
+            });
+            if let Some(Some(v)) = woken {
+                return Some(v);
+            }
         }
+        process.messages.push_front(msg);
+        None
+    }) else {
         return ControlFlow::Continue(());
+    };
+
+    let Some((tid, priority)) = woken else {
+        return ControlFlow::Continue(());
+    };
+    scheduler.run_queue().lock().push(tid, priority);
+    if idle {
+        return ControlFlow::Break(None);
     }
-    let process = scheduler.processes.get_mut(&pid).unwrap();
-    process.messages.push_front(msg);
     ControlFlow::Continue(())
 }
 
-pub fn send(
-    scheduler: &mut Scheduler,
-    state: &RegisterState,
-) -> ControlFlow<Option<TerminationReason>> {
-    let src = scheduler.current_pid.unwrap();
-    let target = state.rsi;
+fn resolve(scheduler: &Scheduler, handle: u64) -> Result<u64, Failure> {
+    let Some(Object::Endpoint(id)) = scheduler
+        .with_current_process(|process| process.caps.get(&handle).copied())
+        .filter(|v| v.rights.send())
+        .map(|v| v.object)
+    else {
+        return Err(Error::InsufficientPermissions.into());
+    };
+    scheduler
+        .endpoints
+        .read()
+        .get(&id)
+        .map(|v| v.owner)
+        .ok_or_else(|| Error::NotFound.into())
+}
+
+fn new_message(
+    scheduler: &Scheduler,
+    target: u64,
+    addr: u64,
+    size: u64,
+    cap_handle: u64,
+) -> Result<Message, Failure> {
+    let src = scheduler.current_pid().unwrap();
     if src == target {
-        return ControlFlow::Break(Some(TerminationReason::MalformedArgument));
+        return Err(Error::InvalidArgument.into());
     }
 
-    let (addr, size) = (state.rdx, state.rcx);
-    if !scheduler
-        .current_process()
-        .unwrap()
-        .region_is_within_bounds(addr, size)
-    {
-        return ControlFlow::Break(Some(TerminationReason::MalformedAddress));
-    }
+    // Capabilities are moved to the receiver, not copied.
+    let (cap, pages) = scheduler.with_current_process(|process| {
+        if !process.region_is_within_bounds(addr, size) {
+            return Err(Failure::Fatal(TerminationReason::MalformedAddress));
+        }
+        if process.is_shared(addr) || process.is_msg(addr) || process.is_mmio(addr) {
+            return Err(Failure::Error(Error::InvalidArgument));
+        }
+        let Some(pages) = process.populate(addr, size) else {
+            return Err(Failure::Error(Error::OutOfMemory));
+        };
+        let cap = match cap_handle {
+            0 => None,
+            v if process.caps.get(&v).is_some_and(|v| v.rights.grant()) => process.caps.remove(&v),
+            _ => return Err(Failure::Error(Error::InsufficientPermissions)),
+        };
+        Ok((cap, pages))
+    })?;
 
-    if !scheduler.processes.contains_key(&target) {
-        return ControlFlow::Break(Some(TerminationReason::NotFound));
+    let mapped = scheduler
+        .with_process(target, |process| {
+            let addr = process
+                .map_foreign(&pages, size)
+                .ok_or(Error::OutOfMemory)?;
+            Ok((addr, cap.map(|v| process.grant_cap(v))))
+        })
+        .unwrap_or(Err(Error::NotFound));
+    let (dst_addr, cap) = match mapped {
+        Ok(v) => v,
+        Err(e) => {
+            if let Some(cap) = cap {
+                scheduler.with_current_process(|process| process.caps.insert(cap_handle, cap));
+            }
+            return Err(e.into());
+        }
+    };
+
+    let msg = Message::new(
+        scheduler.msg_id_gen.lock().next(),
+        src,
+        unsafe { core::slice::from_raw_parts(dst_addr as *const _, size as _) },
+        cap,
+    );
+    let mut routes = scheduler.message_routes.lock();
+    routes.insert(
+        msg.id,
+        MessageRoute {
+            src,
+            dst: target,
+            addr: dst_addr,
+            size,
+        },
+    );
+    scheduler.with_current_process(|cur| cur.track_msg(msg.id, addr));
+    drop(routes);
+    Ok(msg)
+}
+
+pub fn send(
+    scheduler: &Scheduler,
+    state: &mut RegisterState,
+) -> ControlFlow<Option<TerminationReason>> {
+    let msg = resolve(scheduler, state.rsi).and_then(|target| {
+        new_message(scheduler, target, state.rdx, state.rcx, state.r8).map(|msg| (target, msg))
+    });
+    match msg {
+        Ok((target, msg)) => handle_new(scheduler, target, msg),
+        Err(failure) => failure.report(state),
     }
+}
 
-    let msg = Message::new(scheduler.msg_id_gen.next(), src, unsafe {
-        core::slice::from_raw_parts(addr as *const _, size as _)
+pub fn call(
+    scheduler: &Scheduler,
+    state: &mut RegisterState,
+) -> ControlFlow<Option<TerminationReason>> {
+    let msg = resolve(scheduler, state.rsi).and_then(|target| {
+        new_message(scheduler, target, state.rdx, state.rcx, state.r8).map(|msg| (target, msg))
     });
-    scheduler.message_sources.insert(msg.id, src);
+    let (target, msg) = match msg {
+        Ok(v) => v,
+        Err(failure) => return failure.report(state),
+    };
 
-    let cur = scheduler.current_process_mut().unwrap();
+    let tid = scheduler.current_tid().unwrap();
+    let pid = scheduler.current_pid().unwrap();
+    scheduler.pending_calls.lock().insert(
+        msg.id,
+        PendingCall {
+            tid,
+            pid,
+            target,
+            acked: false,
+            cancelled: false,
+        },
+    );
+    // The caller must be blocked before the callee can see the message, otherwise the reply could be lost.
+    scheduler.with_thread(tid, |thread| {
+        thread.block(state, ThreadState::Calling(msg.id));
+    });
+    let _ = handle_new(scheduler, target, msg);
 
-    cur.track_msg(msg.id, addr);
+    ControlFlow::Break(None)
+}
 
-    let process = scheduler.processes.get(&target).unwrap();
-    unsafe {
-        process.cr3.lock().map(
-            addr,
-            addr - fireworkkit::USER_VIRT_OFFSET,
-            (size + (PAGE_SIZE - 1)) / PAGE_SIZE,
-            PageTableFlags::new_present().with_user(true),
-        );
+pub fn reply(
+    scheduler: &Scheduler,
+    state: &mut RegisterState,
+) -> ControlFlow<Option<TerminationReason>> {
+    let call_id = state.rsi;
+    let cur_pid = scheduler.current_pid().unwrap();
+    let Some((caller_pid, cancelled)) = scheduler
+        .pending_calls
+        .lock()
+        .get(&call_id)
+        .filter(|v| v.target == cur_pid)
+        .map(|v| (v.pid, v.cancelled))
+    else {
+        return error(state, Error::NotFound);
+    };
+
+    // The caller is gone, so there is nobody to deliver the reply to.
+    if cancelled {
+        let call = scheduler.pending_calls.lock().remove(&call_id);
+        if call.is_some_and(|v| v.acked) {
+            scheduler.msg_id_gen.lock().free(call_id);
+        }
+        return ControlFlow::Continue(());
+    }
+
+    let msg = match new_message(scheduler, caller_pid, state.rdx, state.rcx, state.r8) {
+        Ok(v) => v,
+        Err(failure) => return failure.report(state),
+    };
+
+    let Some(call) = scheduler.pending_calls.lock().remove(&call_id) else {
+        return handle_new(scheduler, caller_pid, msg);
+    };
+    if call.acked {
+        scheduler.msg_id_gen.lock().free(call_id);
     }
-    let tids = process.thread_ids.clone();
-    handle_new(scheduler, target, tids, msg)
+
+    let woken = scheduler
+        .with_thread(call.tid, |thread| {
+            if thread.state != ThreadState::Calling(call_id) {
+                return None;
+            }
+            thread.state = ThreadState::Inactive;
+            deliver(&mut thread.regs, &msg);
+            Some(thread.priority)
+        })
+        .flatten();
+    let Some(priority) = woken else {
+        return handle_new(scheduler, caller_pid, msg);
+    };
+    scheduler.run_queue().lock().push(call.tid, priority);
+
+    ControlFlow::Continue(())
+}
+
+enum Wait {
+    Never,
+    Forever,
+    Until(u64),
+}
+
+fn receive(
+    scheduler: &Scheduler,
+    state: &mut RegisterState,
+    wait: Wait,
+) -> ControlFlow<Option<TerminationReason>> {
+    let tid = scheduler.current_tid().unwrap();
+    scheduler.with_current_process(|process| {
+        let Some(msg) = process.messages.pop_back() else {
+            let deadline = match wait {
+                Wait::Never => {
+                    state.rax = 0;
+                    return ControlFlow::Continue(());
+                }
+                Wait::Forever => None,
+                Wait::Until(v) => Some(v),
+            };
+            scheduler.with_thread(tid, |thread| {
+                thread.block(state, ThreadState::Suspended);
+                thread.deadline = deadline;
+            });
+            if let Some(deadline) = deadline {
+                scheduler.sleep_until(tid, deadline);
+            }
+            return ControlFlow::Break(None);
+        };
+
+        deliver(state, &msg);
+        ControlFlow::Continue(())
+    })
 }
 
 pub fn recv(
-    scheduler: &mut Scheduler,
+    scheduler: &Scheduler,
     state: &mut RegisterState,
 ) -> ControlFlow<Option<TerminationReason>> {
-    let process = scheduler.current_process_mut().unwrap();
-    let Some(msg) = process.messages.pop_back() else {
-        scheduler.current_thread_mut().unwrap().state = ThreadState::Suspended;
-        return ControlFlow::Break(None);
+    receive(scheduler, state, Wait::Forever)
+}
+
+pub fn recv_timeout(
+    scheduler: &Scheduler,
+    state: &mut RegisterState,
+) -> ControlFlow<Option<TerminationReason>> {
+    let wait = match state.rsi {
+        0 => Wait::Never,
+        v => Wait::Until(scheduler.ticks().saturating_add(v)),
     };
+    receive(scheduler, state, wait)
+}
 
-    state.rax = msg.id;
-    state.rdi = msg.pid;
-    state.rsi = msg.data.as_ptr() as u64;
-    state.rdx = msg.data.len() as u64;
-    ControlFlow::Continue(())
+pub fn try_recv(
+    scheduler: &Scheduler,
+    state: &mut RegisterState,
+) -> ControlFlow<Option<TerminationReason>> {
+    receive(scheduler, state, Wait::Never)
 }
 
 pub fn ack(
-    scheduler: &mut Scheduler,
-    state: &RegisterState,
+    scheduler: &Scheduler,
+    state: &mut RegisterState,
 ) -> ControlFlow<Option<TerminationReason>> {
     let msg_id = state.rsi;
 
-    let Some(src_pid) = scheduler.message_sources.remove(&msg_id) else {
-        return ControlFlow::Break(Some(TerminationReason::NotFound));
+    let cur_pid = scheduler.current_pid().unwrap();
+    let mut routes = scheduler.message_routes.lock();
+    let Some(route) = routes.get(&msg_id).filter(|v| v.dst == cur_pid) else {
+        drop(routes);
+        return error(state, Error::NotFound);
     };
+    let (src_pid, addr, size) = (route.src, route.addr, route.size);
+    routes.remove(&msg_id);
 
-    let cur_pid = scheduler.current_pid.unwrap();
     let pid = if src_pid == 0 { cur_pid } else { src_pid };
-    let process = scheduler.processes.get_mut(&pid).unwrap();
-    let addr = *process.msg_id_to_addr.get(&msg_id).unwrap();
-    let size = process.allocations.get(&addr).copied().unwrap().0;
-    if src_pid == 0 {
-        let msg: KernelMessage = unsafe {
-            postcard::from_bytes(core::slice::from_raw_parts(addr as *const _, size as _)).unwrap()
+    // A sender that is being torn down still owns the buffer, which is freed along with it.
+    let irq = scheduler.with_process(pid, |process| {
+        let irq = if src_pid == 0 {
+            let msg: KernelMessage = unsafe {
+                postcard::from_bytes(core::slice::from_raw_parts(addr as *const _, size as _))
+                    .unwrap()
+            };
+            match msg {
+                KernelMessage::IRQFired(irq) => Some(irq),
+                _ => None,
+            }
+        } else {
+            None
         };
-        let KernelMessage::IRQFired(irq) = msg;
-        crate::acpi::ioapic::set_irq_mask(irq, false);
+        process.free_msg(msg_id);
+        irq
+    });
+    drop(routes);
+    if let Some(irq) = irq.flatten() {
+        scheduler.ack_irq(irq, cur_pid);
+    }
+    // Calls keep their ID reserved until they are replied to.
+    if let Some(call) = scheduler.pending_calls.lock().get_mut(&msg_id) {
+        call.acked = true;
+    } else {
+        scheduler.msg_id_gen.lock().free(msg_id);
     }
-    process.free_msg(msg_id);
-    scheduler.msg_id_gen.free(msg_id);
     if pid != cur_pid {
-        let process = scheduler.current_process().unwrap();
-        unsafe {
-            process.cr3.lock().unmap(addr, (size + 0xFFF) / 0x1000);
-        }
+        scheduler.with_current_process(|process| process.unmap_foreign(addr, size));
     }
 
     ControlFlow::Continue(())
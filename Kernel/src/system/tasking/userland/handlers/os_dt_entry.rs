@@ -4,10 +4,16 @@ use core::ops::ControlFlow;
 
 use fireworkkit::{
     osdtentry::{OSDTEntryInfo, OSDTEntryProp},
-    TerminationReason,
+    Error, TerminationReason,
 };
 
-use crate::system::{tasking::scheduler::Scheduler, RegisterState};
+use crate::system::{
+    tasking::{
+        scheduler::Scheduler,
+        userland::{error, user_buffer},
+    },
+    RegisterState,
+};
 
 pub fn new_entry(state: &mut RegisterState) -> ControlFlow<Option<TerminationReason>> {
     let sys_state = unsafe { &mut *crate::system::state::SYS_STATE.get() };
@@ -15,7 +21,7 @@ pub fn new_entry(state: &mut RegisterState) -> ControlFlow<Option<TerminationRea
     let new = {
         let dt_index = dt_index.read();
         let Some(parent) = dt_index.get(&state.rsi) else {
-            return ControlFlow::Break(Some(TerminationReason::NotFound));
+            return error(state, Error::NotFound);
         };
         let v = crate::system::state::OSDTEntry {
             id: sys_state.dt_id_gen.as_ref().unwrap().lock().next(),
@@ -32,66 +38,73 @@ pub fn new_entry(state: &mut RegisterState) -> ControlFlow<Option<TerminationRea
 }
 
 pub fn get_info(
-    scheduler: &mut Scheduler,
+    scheduler: &Scheduler,
     state: &mut RegisterState,
 ) -> ControlFlow<Option<TerminationReason>> {
     let sys_state = unsafe { &mut *crate::system::state::SYS_STATE.get() };
     let Ok(info_type) = OSDTEntryInfo::try_from(state.rdx) else {
-        return ControlFlow::Break(Some(TerminationReason::MalformedArgument));
+        return error(state, Error::InvalidArgument);
     };
     let dt_index = sys_state.dt_index.as_ref().unwrap().read();
     let Some(ent) = dt_index.get(&state.rsi) else {
-        return ControlFlow::Break(Some(TerminationReason::NotFound));
+        return error(state, Error::NotFound);
     };
     let data = match info_type {
         OSDTEntryInfo::Parent => postcard::to_allocvec(&ent.lock().parent),
         OSDTEntryInfo::Children => postcard::to_allocvec(&ent.lock().children),
         OSDTEntryInfo::Properties => postcard::to_allocvec(&ent.lock().properties),
         OSDTEntryInfo::Property => {
-            let Ok(k) = core::str::from_utf8(unsafe {
-                core::slice::from_raw_parts(state.rcx as *const _, state.r8 as _)
-            }) else {
-                return ControlFlow::Break(Some(TerminationReason::MalformedAddress));
+            let (addr, size) = (state.rcx, state.r8);
+            let k = match user_buffer(scheduler, addr, size) {
+                Ok(v) => v,
+                Err(failure) => return failure.report(state),
+            };
+            let Ok(k) = core::str::from_utf8(k) else {
+                return error(state, Error::InvalidData);
             };
             postcard::to_allocvec(&ent.lock().properties.get(k))
         }
     }
-    .unwrap()
-    .leak();
+    .unwrap();
 
-    state.rax = scheduler
-        .current_process_mut()
-        .unwrap()
-        .track_kernelside_alloc(data.as_ptr() as _, data.len() as _);
-    state.rdi = data.len() as _;
+    let Some(addr) = scheduler.with_current_process(|process| {
+        process.track_kernelside_alloc(data.as_ptr() as _, data.len() as _)
+    }) else {
+        return error(state, Error::OutOfMemory);
+    };
+    state.rax = addr;
+    state.rdi = data.leak().len() as _;
 
     ControlFlow::Continue(())
 }
 
 pub fn set_prop(
-    scheduler: &mut Scheduler,
-    state: &RegisterState,
+    scheduler: &Scheduler,
+    state: &mut RegisterState,
 ) -> ControlFlow<Option<TerminationReason>> {
     let addr = state.rdx;
     let size = state.rcx;
 
-    if !scheduler
-        .current_process()
-        .unwrap()
-        .region_is_valid(addr, size)
-    {
-        return ControlFlow::Break(Some(TerminationReason::MalformedAddress));
-    }
+    let data = match user_buffer(scheduler, addr, size) {
+        Ok(v) => v,
+        Err(failure) => return failure.report(state),
+    };
 
     let sys_state = unsafe { &mut *crate::system::state::SYS_STATE.get() };
     let dt_index = sys_state.dt_index.as_ref().unwrap().read();
     let Some(ent) = dt_index.get(&state.rsi) else {
-        return ControlFlow::Break(Some(TerminationReason::NotFound));
+        return error(state, Error::NotFound);
     };
-    let data = unsafe { core::slice::from_raw_parts(addr as *const _, size as _) };
     let Ok(v) = postcard::from_bytes::<OSDTEntryProp>(data) else {
-        return ControlFlow::Break(Some(TerminationReason::MalformedAddress));
+        return error(state, Error::InvalidData);
     };
+    // Reserved keys describe resources the kernel hands out, so only the owner may set them.
+    if v.0.starts_with('_')
+        && crate::system::fkext::find_owner(&dt_index, Some(state.rsi.into()))
+            != scheduler.current_pid()
+    {
+        return error(state, Error::InsufficientPermissions);
+    }
     ent.lock().properties.insert(v.0, v.1);
     drop(dt_index);
     crate::system::fkext::handle_change(scheduler, state.rsi.into());
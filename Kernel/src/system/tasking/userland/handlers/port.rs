@@ -3,15 +3,67 @@
 use core::ops::ControlFlow;
 
 use amd64::io::port::PortIO;
-use fireworkkit::{syscall::AccessSize, TerminationReason};
+use fireworkkit::{syscall::AccessSize, Error, TerminationReason};
 
-use crate::system::RegisterState;
+use crate::system::{
+    pci,
+    tasking::{scheduler::Scheduler, userland::error},
+    RegisterState,
+};
 
-pub fn port_in(state: &mut RegisterState) -> ControlFlow<Option<TerminationReason>> {
+const fn width(access_size: AccessSize) -> u64 {
+    match access_size {
+        AccessSize::Byte => 1,
+        AccessSize::Word => 2,
+        AccessSize::DWord => 4,
+    }
+}
+
+fn is_granted(scheduler: &Scheduler, port: u16, access_size: AccessSize) -> bool {
+    scheduler.with_current_process(|process| process.may_access_port(port, width(access_size)))
+}
+
+// The kernel uses the PCI configuration space too, so the address a process writes is kept aside and only put on
+// the bus together with the data access, under the same lock.
+fn config_access(
+    scheduler: &Scheduler,
+    port: u16,
+    access_size: AccessSize,
+    v: Option<u64>,
+) -> Option<u64> {
+    match (port, access_size) {
+        (pci::CONFIG_ADDRESS, AccessSize::DWord) => {
+            Some(scheduler.with_current_process(|process| {
+                if let Some(v) = v {
+                    process.pci_config_addr = v as u32;
+                }
+                process.pci_config_addr.into()
+            }))
+        }
+        (pci::CONFIG_DATA..=0xCFF, _) => {
+            let addr = scheduler.with_current_process(|process| process.pci_config_addr);
+            let addr = (addr & !3) | u32::from(port & 3);
+            Some(unsafe { pci::config_access(addr, width(access_size), v) })
+        }
+        _ => None,
+    }
+}
+
+pub fn port_in(
+    scheduler: &Scheduler,
+    state: &mut RegisterState,
+) -> ControlFlow<Option<TerminationReason>> {
     let port = state.rsi as u16;
     let Ok(access_size) = AccessSize::try_from(state.rdx) else {
-        return ControlFlow::Break(Some(TerminationReason::MalformedArgument));
+        return error(state, Error::InvalidArgument);
     };
+    if !is_granted(scheduler, port, access_size) {
+        return ControlFlow::Break(Some(TerminationReason::InsufficientPermissions));
+    }
+    if let Some(v) = config_access(scheduler, port, access_size, None) {
+        state.rax = v;
+        return ControlFlow::Continue(());
+    }
     unsafe {
         state.rax = match access_size {
             AccessSize::Byte => u64::from(u8::read(port)),
@@ -22,11 +74,20 @@ pub fn port_in(state: &mut RegisterState) -> ControlFlow<Option<TerminationReaso
     ControlFlow::Continue(())
 }
 
-pub fn port_out(state: &RegisterState) -> ControlFlow<Option<TerminationReason>> {
+pub fn port_out(
+    scheduler: &Scheduler,
+    state: &mut RegisterState,
+) -> ControlFlow<Option<TerminationReason>> {
     let port = state.rsi as u16;
     let Ok(access_size) = AccessSize::try_from(state.rdx) else {
-        return ControlFlow::Break(Some(TerminationReason::MalformedArgument));
+        return error(state, Error::InvalidArgument);
     };
+    if !is_granted(scheduler, port, access_size) {
+        return ControlFlow::Break(Some(TerminationReason::InsufficientPermissions));
+    }
+    if config_access(scheduler, port, access_size, Some(state.rcx)).is_some() {
+        return ControlFlow::Continue(());
+    }
     unsafe {
         match access_size {
             AccessSize::Byte => u8::write(port, state.rcx as u8),
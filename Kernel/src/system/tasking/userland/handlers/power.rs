@@ -0,0 +1,32 @@
+// Copyright (c) ChefKiss Inc 2021-2023. Licensed under the Thou Shalt Not Profit License version 1.5. See LICENSE for details.
+
+use core::ops::ControlFlow;
+
+use fireworkkit::{syscall::PowerAction, Error, TerminationReason};
+
+use crate::system::{
+    tasking::{scheduler::Scheduler, userland::error},
+    RegisterState,
+};
+
+pub fn power(
+    scheduler: &Scheduler,
+    state: &mut RegisterState,
+) -> ControlFlow<Option<TerminationReason>> {
+    let Ok(action) = PowerAction::try_from(state.rsi) else {
+        return error(state, Error::InvalidArgument);
+    };
+    if !scheduler.with_current_process(|process| process.privileged) {
+        return error(state, Error::InsufficientPermissions);
+    }
+
+    debug!(
+        "PID {} requested {action:?}",
+        scheduler.current_pid().unwrap()
+    );
+    match action {
+        PowerAction::Shutdown => crate::acpi::power::shutdown(),
+        PowerAction::Reboot => crate::acpi::power::reboot(),
+    }
+    error(state, Error::Unsupported)
+}
@@ -0,0 +1,194 @@
+// Copyright (c) ChefKiss Inc 2021-2023. Licensed under the Thou Shalt Not Profit License version 1.5. See LICENSE for details.
+
+use core::ops::ControlFlow;
+
+use fireworkkit::{Error, TerminationReason};
+
+use crate::system::{
+    tasking::{
+        capability::{Capability, Object, Rights, SharedRegion},
+        scheduler::Scheduler,
+        userland::error,
+        AllocationType, USER_VIRT_BASE, USER_VIRT_END,
+    },
+    RegisterState,
+};
+
+fn get_cap(scheduler: &Scheduler, handle: u64) -> Option<(u64, Rights)> {
+    scheduler.with_current_process(|process| match process.caps.get(&handle) {
+        Some(Capability {
+            object: Object::SharedMemory(id),
+            rights,
+        }) => Some((*id, *rights)),
+        _ => None,
+    })
+}
+
+pub fn create(
+    scheduler: &Scheduler,
+    state: &mut RegisterState,
+) -> ControlFlow<Option<TerminationReason>> {
+    let size = state.rsi;
+    // Anything larger could never be mapped.
+    if size == 0 || size > USER_VIRT_END - USER_VIRT_BASE {
+        return error(state, Error::InvalidArgument);
+    }
+
+    let page_count = size.div_ceil(0x1000);
+    let phys = unsafe {
+        let Some(phys) = (*crate::system::state::SYS_STATE.get())
+            .pmm
+            .as_ref()
+            .unwrap()
+            .lock()
+            .alloc(page_count)
+        else {
+            return error(state, Error::OutOfMemory);
+        };
+        let phys = phys as u64;
+        core::ptr::write_bytes(
+            (phys + amd64::paging::PHYS_VIRT_OFFSET) as *mut u8,
+            0,
+            (page_count * 0x1000) as _,
+        );
+        phys
+    };
+
+    let id = scheduler.shm_id_gen.lock().next();
+    scheduler.shm_regions.lock().insert(
+        id,
+        SharedRegion {
+            phys,
+            size,
+            refs: 1,
+        },
+    );
+
+    state.rax = scheduler.with_current_process(|process| {
+        process.grant_cap(Capability {
+            object: Object::SharedMemory(id),
+            rights: Rights::new().with_grant(true).with_write(true),
+        })
+    });
+
+    ControlFlow::Continue(())
+}
+
+pub fn map(
+    scheduler: &Scheduler,
+    state: &mut RegisterState,
+) -> ControlFlow<Option<TerminationReason>> {
+    let Some((id, rights)) = get_cap(scheduler, state.rsi) else {
+        return error(state, Error::InsufficientPermissions);
+    };
+    let Some((phys, size)) = scheduler
+        .shm_regions
+        .lock()
+        .get(&id)
+        .map(|v| (v.phys, v.size))
+    else {
+        return error(state, Error::NotFound);
+    };
+
+    let addr = scheduler.with_current_process(|process| {
+        // Every mapping holds a reference of its own, the capability may be moved away while it is mapped.
+        let prev = process.shm_mappings.get(&id).copied();
+        if prev.is_none() {
+            scheduler
+                .shm_regions
+                .lock()
+                .get_mut(&id)
+                .ok_or(Error::NotFound)?
+                .refs += 1;
+        }
+        let writable = rights.write()
+            || prev.is_some_and(|addr| {
+                matches!(
+                    process.allocations.get(&addr),
+                    Some((_, AllocationType::Shared { writable: true }))
+                )
+            });
+        // The previous mapping stays in place if there is no room for the new one.
+        let Some(addr) = process.track_alloc(phys, size, AllocationType::Shared { writable })
+        else {
+            if prev.is_none() {
+                scheduler.release_shm(id);
+            }
+            return Err(Error::OutOfMemory);
+        };
+        if let Some(prev) = prev {
+            process.free_alloc(prev);
+        }
+        process.shm_mappings.insert(id, addr);
+        Ok(addr)
+    });
+    let addr = match addr {
+        Ok(v) => v,
+        Err(e) => return error(state, e),
+    };
+    state.rax = addr;
+    state.rdx = size;
+    ControlFlow::Continue(())
+}
+
+pub fn unmap(
+    scheduler: &Scheduler,
+    state: &mut RegisterState,
+) -> ControlFlow<Option<TerminationReason>> {
+    let handle = state.rsi;
+    let Some((id, _)) = get_cap(scheduler, handle) else {
+        return error(state, Error::InsufficientPermissions);
+    };
+    if !scheduler.shm_regions.lock().contains_key(&id) {
+        return error(state, Error::NotFound);
+    }
+
+    let unmapped = scheduler.with_current_process(|process| {
+        process.caps.remove(&handle);
+        let still_held = process
+            .caps
+            .values()
+            .any(|v| v.object == Object::SharedMemory(id));
+        if still_held {
+            return false;
+        }
+        let Some(addr) = process.shm_mappings.remove(&id) else {
+            return false;
+        };
+        process.free_alloc(addr);
+        true
+    });
+    scheduler.release_shm(id);
+    if unmapped {
+        scheduler.release_shm(id);
+    }
+
+    ControlFlow::Continue(())
+}
+
+pub fn share(
+    scheduler: &Scheduler,
+    state: &mut RegisterState,
+) -> ControlFlow<Option<TerminationReason>> {
+    let writable = state.rdx != 0;
+    let Some((id, rights)) = get_cap(scheduler, state.rsi) else {
+        return error(state, Error::InsufficientPermissions);
+    };
+    if !rights.grant() || (writable && !rights.write()) {
+        return error(state, Error::InsufficientPermissions);
+    }
+
+    match scheduler.shm_regions.lock().get_mut(&id) {
+        Some(region) => region.refs += 1,
+        None => return error(state, Error::NotFound),
+    }
+
+    state.rax = scheduler.with_current_process(|process| {
+        process.grant_cap(Capability {
+            object: Object::SharedMemory(id),
+            rights: Rights::new().with_grant(true).with_write(writable),
+        })
+    });
+
+    ControlFlow::Continue(())
+}
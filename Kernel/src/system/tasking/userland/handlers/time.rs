@@ -0,0 +1,40 @@
+// Copyright (c) ChefKiss Inc 2021-2023. Licensed under the Thou Shalt Not Profit License version 1.5. See LICENSE for details.
+
+use core::ops::ControlFlow;
+
+use fireworkkit::TerminationReason;
+
+use crate::system::{
+    tasking::{
+        scheduler::{Scheduler, TICK_NS},
+        ThreadState,
+    },
+    RegisterState,
+};
+
+pub fn sleep(
+    scheduler: &Scheduler,
+    state: &RegisterState,
+) -> ControlFlow<Option<TerminationReason>> {
+    let ticks = state.rsi.div_ceil(TICK_NS);
+    if ticks == 0 {
+        return ControlFlow::Break(None);
+    }
+
+    let tid = scheduler.current_tid().unwrap();
+    let deadline = scheduler.ticks().saturating_add(ticks);
+    scheduler.with_thread(tid, |thread| {
+        thread.block(state, ThreadState::Sleeping);
+        thread.deadline = Some(deadline);
+    });
+    scheduler.sleep_until(tid, deadline);
+
+    ControlFlow::Break(None)
+}
+
+pub fn get_time(state: &mut RegisterState) -> ControlFlow<Option<TerminationReason>> {
+    let sys_state = unsafe { &*crate::system::state::SYS_STATE.get() };
+    state.rax = sys_state.hpet.as_ref().unwrap().time_ns();
+
+    ControlFlow::Continue(())
+}
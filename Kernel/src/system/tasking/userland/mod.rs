@@ -2,37 +2,107 @@
 
 use core::ops::ControlFlow;
 
-use fireworkkit::{syscall::SystemCall, TerminationReason};
+use fireworkkit::{syscall::SystemCall, Error, TerminationReason};
 
-use crate::system::{gdt::PrivilegeLevel, RegisterState};
+use crate::system::{gdt::PrivilegeLevel, tasking::scheduler::Scheduler, RegisterState};
 
 pub mod handlers;
 pub mod page_table;
+pub mod syscall;
+
+pub enum Failure {
+    Error(Error),
+    Fatal(TerminationReason),
+}
+
+impl Failure {
+    pub const fn report(self, state: &mut RegisterState) -> ControlFlow<Option<TerminationReason>> {
+        match self {
+            Self::Error(err) => {
+                state.rax = err.into_status();
+                ControlFlow::Continue(())
+            }
+            Self::Fatal(reason) => ControlFlow::Break(Some(reason)),
+        }
+    }
+}
+
+impl From<Error> for Failure {
+    fn from(value: Error) -> Self {
+        Self::Error(value)
+    }
+}
+
+impl From<TerminationReason> for Failure {
+    fn from(value: TerminationReason) -> Self {
+        Self::Fatal(value)
+    }
+}
+
+pub const fn error(
+    state: &mut RegisterState,
+    err: Error,
+) -> ControlFlow<Option<TerminationReason>> {
+    Failure::Error(err).report(state)
+}
+
+// Handlers may read user buffers while holding locks, so they are backed up front instead of faulting in.
+pub fn user_buffer(scheduler: &Scheduler, addr: u64, size: u64) -> Result<&'static [u8], Failure> {
+    scheduler.with_current_process(|process| {
+        if !process.region_is_valid(addr, size) {
+            return Err(TerminationReason::MalformedAddress.into());
+        }
+        process.populate(addr, size).ok_or(Error::OutOfMemory)?;
+        Ok(unsafe { core::slice::from_raw_parts(addr as *const u8, size as _) })
+    })
+}
 
 unsafe extern "sysv64" fn syscall_handler(state: &mut RegisterState) {
     let sys_state = &mut *crate::system::state::SYS_STATE.get();
-    let mut scheduler = sys_state.scheduler.as_ref().unwrap().lock();
+    let scheduler = sys_state.scheduler.as_ref().unwrap();
 
+    // Handlers only write rax when they have something to return.
+    state.rax = 0;
     let flow = 'flow: {
         let Ok(v) = SystemCall::try_from(state.rdi) else {
-            break 'flow ControlFlow::Break(Some(TerminationReason::MalformedArgument));
+            break 'flow error(state, Error::Unsupported);
         };
 
         match v {
-            SystemCall::KPrint => handlers::kprint(&scheduler, state),
-            SystemCall::MsgRecv => handlers::msg::recv(&mut scheduler, state),
-            SystemCall::MsgSend => handlers::msg::send(&mut scheduler, state),
+            SystemCall::KPrint => handlers::kprint(scheduler, state),
+            SystemCall::MsgRecv => handlers::msg::recv(scheduler, state),
+            SystemCall::MsgSend => handlers::msg::send(scheduler, state),
             SystemCall::Quit => scheduler.thread_teardown(),
             SystemCall::Yield => ControlFlow::Break(None),
-            SystemCall::PortIn => handlers::port::port_in(state),
-            SystemCall::PortOut => handlers::port::port_out(state),
+            SystemCall::PortIn => handlers::port::port_in(scheduler, state),
+            SystemCall::PortOut => handlers::port::port_out(scheduler, state),
             SystemCall::RegisterIRQ => scheduler.register_irq(state),
-            SystemCall::Allocate => handlers::alloc::alloc(&mut scheduler, state),
-            SystemCall::Free => handlers::alloc::free(&mut scheduler, state),
-            SystemCall::MsgAck => handlers::msg::ack(&mut scheduler, state),
+            SystemCall::Allocate => handlers::alloc::alloc(scheduler, state),
+            SystemCall::Free => handlers::alloc::free(scheduler, state),
+            SystemCall::MsgAck => handlers::msg::ack(scheduler, state),
             SystemCall::NewOSDTEntry => handlers::os_dt_entry::new_entry(state),
-            SystemCall::GetOSDTEntryInfo => handlers::os_dt_entry::get_info(&mut scheduler, state),
-            SystemCall::SetOSDTEntryProp => handlers::os_dt_entry::set_prop(&mut scheduler, state),
+            SystemCall::GetOSDTEntryInfo => handlers::os_dt_entry::get_info(scheduler, state),
+            SystemCall::SetOSDTEntryProp => handlers::os_dt_entry::set_prop(scheduler, state),
+            SystemCall::SetPriority => scheduler.set_priority(state),
+            SystemCall::ThreadSpawn => scheduler.spawn_thread(state),
+            SystemCall::ThreadJoin => scheduler.join_thread(state),
+            SystemCall::MsgRecvTimeout => handlers::msg::recv_timeout(scheduler, state),
+            SystemCall::MsgTryRecv => handlers::msg::try_recv(scheduler, state),
+            SystemCall::Sleep => handlers::time::sleep(scheduler, state),
+            SystemCall::GetTime => handlers::time::get_time(state),
+            SystemCall::MsgCall => handlers::msg::call(scheduler, state),
+            SystemCall::MsgReply => handlers::msg::reply(scheduler, state),
+            SystemCall::EndpointCreate => handlers::endpoint::create(scheduler, state),
+            SystemCall::EndpointLookup => handlers::endpoint::lookup(scheduler, state),
+            SystemCall::ShmCreate => handlers::shm::create(scheduler, state),
+            SystemCall::ShmMap => handlers::shm::map(scheduler, state),
+            SystemCall::ShmUnmap => handlers::shm::unmap(scheduler, state),
+            SystemCall::ShmShare => handlers::shm::share(scheduler, state),
+            SystemCall::GetDMAAddress => handlers::alloc::dma_address(scheduler, state),
+            SystemCall::AllocDMA => handlers::alloc::alloc_dma(scheduler, state),
+            SystemCall::MapMMIO => handlers::mmio::map(scheduler, state),
+            SystemCall::RegisterMSI => scheduler.register_msi(state),
+            SystemCall::Power => handlers::power::power(scheduler, state),
         }
     };
 
@@ -43,13 +113,14 @@ unsafe extern "sysv64" fn syscall_handler(state: &mut RegisterState) {
     if let Some(reason) = reason {
         debug!(
             "PID {} performed illegal action (<{reason:?}>). Killing it, good riddance.",
-            scheduler.current_pid.unwrap()
+            scheduler.current_pid().unwrap()
         );
-        scheduler.process_teardown();
+        scheduler.process_teardown(reason);
     }
     scheduler.schedule(state);
 }
 
 pub fn setup() {
-    crate::interrupts::idt::set_handler(249, 1, PrivilegeLevel::User, syscall_handler, false, true);
+    crate::interrupts::idt::set_handler(249, 0, PrivilegeLevel::User, syscall_handler, false, true);
+    syscall::setup_cpu();
 }
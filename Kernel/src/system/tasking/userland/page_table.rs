@@ -1,33 +1,24 @@
 // Copyright (c) ChefKiss Inc 2021-2023. Licensed under the Thou Shalt Not Profit License version 1.5. See LICENSE for details.
 
-use alloc::boxed::Box;
+use alloc::{boxed::Box, vec::Vec};
+use core::cell::RefCell;
 
-use amd64::paging::{PageTable, PageTableFlags, PAGE_SIZE};
-
-use crate::system::tasking::AllocationType;
+use amd64::paging::{PageTable, PageTableFlags};
 
 #[derive(Debug)]
 #[repr(C)]
-pub struct UserPML4(PageTable<{ amd64::paging::PHYS_VIRT_OFFSET }>, u64);
+pub struct UserPML4(PageTable<{ amd64::paging::PHYS_VIRT_OFFSET }>, Vec<u64>);
 
 impl UserPML4 {
     #[inline]
-    pub const fn new(pid: u64) -> Self {
-        Self(amd64::paging::PageTable::new(), pid)
+    pub const fn new() -> Self {
+        Self(amd64::paging::PageTable::new(), Vec::new())
     }
 
-    fn alloc_entry(pid: u64) -> u64 {
-        let sys_state = unsafe { &mut *crate::system::state::SYS_STATE.get() };
+    fn alloc_entry(tables: &RefCell<Vec<u64>>) -> u64 {
         let phys = Box::leak(Box::new(PageTable::<0>::new())) as *mut _ as u64
             - amd64::paging::PHYS_VIRT_OFFSET;
-
-        let scheduler = sys_state.scheduler.as_mut().unwrap().get_mut();
-        scheduler.processes.get_mut(&pid).unwrap().track_alloc(
-            phys + fireworkkit::USER_VIRT_OFFSET,
-            PAGE_SIZE,
-            AllocationType::Kernel,
-        );
-
+        tables.borrow_mut().push(phys);
         phys
     }
 
@@ -38,9 +29,10 @@ impl UserPML4 {
 
     #[inline]
     pub unsafe fn map(&mut self, virt: u64, phys: u64, count: u64, flags: PageTableFlags) {
-        let pid = self.1;
+        let tables = RefCell::new(core::mem::take(&mut self.1));
         self.0
-            .map(&move || Self::alloc_entry(pid), virt, phys, count, flags);
+            .map(&|| Self::alloc_entry(&tables), virt, phys, count, flags);
+        self.1 = tables.into_inner();
     }
 
     #[inline]
@@ -48,9 +40,25 @@ impl UserPML4 {
         self.0.unmap(virt, count);
     }
 
+    #[inline]
+    pub unsafe fn virt_to_phys(&mut self, virt: u64) -> Option<u64> {
+        self.0.virt_to_phys(virt).map(|(phys, _)| phys)
+    }
+
     #[inline]
     pub unsafe fn map_higher_half(&mut self) {
-        let pid = self.1;
-        self.0.map_higher_half(&move || Self::alloc_entry(pid));
+        let tables = RefCell::new(core::mem::take(&mut self.1));
+        self.0.map_higher_half(&|| Self::alloc_entry(&tables));
+        self.1 = tables.into_inner();
+    }
+}
+
+impl Drop for UserPML4 {
+    fn drop(&mut self) {
+        for &phys in &self.1 {
+            drop(unsafe {
+                Box::from_raw((phys + amd64::paging::PHYS_VIRT_OFFSET) as *mut PageTable<0>)
+            });
+        }
     }
 }
@@ -0,0 +1,147 @@
+// Copyright (c) ChefKiss Inc 2021-2023. Licensed under the Thou Shalt Not Profit License version 1.5. See LICENSE for details.
+
+use amd64::msr::{
+    efer::ExtendedFeatureEnableReg,
+    seg_base::KernelGSBase,
+    syscall::{LongModeSystemCallTarget, SystemCallFlagMask, SystemCallTarget},
+    ModelSpecificReg,
+};
+
+use crate::system::{
+    gdt::{PrivilegeLevel, SegmentSelector},
+    smp::SyscallScratch,
+    RegisterState,
+};
+
+// Not a real vector, marks frames that have to be returned from with SYSRET.
+pub const FAST_SYSCALL_NUM: u64 = 0x100;
+
+// TF, IF, DF, NT and AC.
+const FLAG_MASK: u64 = (1 << 8) | (1 << 9) | (1 << 10) | (1 << 14) | (1 << 18);
+
+unsafe extern "sysv64" fn fast_syscall_handler(state: &mut RegisterState) -> *mut RegisterState {
+    super::syscall_handler(state);
+    crate::system::smp::resume_frame(state)
+}
+
+#[naked]
+unsafe extern "sysv64" fn syscall_entry() {
+    core::arch::asm!(
+        "swapgs",
+        "mov gs:[{user_rsp}], rsp",
+        "mov rsp, gs:[{kern_rsp}]",
+        "push {user_ss}",
+        "push qword ptr gs:[{user_rsp}]",
+        "swapgs",
+        "push r11",
+        "push {user_cs}",
+        "push rcx",
+        "push 0",
+        "push {int_num}",
+        "push rax",
+        "push rbx",
+        // RCX holds the return address, so the fourth argument is passed in R10 instead.
+        "push r10",
+        "push rdx",
+        "push rsi",
+        "push rdi",
+        "push rbp",
+        "push r8",
+        "push r9",
+        "push r10",
+        "push r11",
+        "push r12",
+        "push r13",
+        "push r14",
+        "push r15",
+        "mov rdi, rsp",
+        "call {handler}",
+        "cmp rax, rsp",
+        "je 3f",
+        "mov rsp, rax",
+        "call {finish}",
+        "3:",
+        "cmp qword ptr [rsp + {int_num_off}], {int_num}",
+        "jne 2f",
+        "pop r15",
+        "pop r14",
+        "pop r13",
+        "pop r12",
+        "add rsp, 16",
+        "pop r9",
+        "pop r8",
+        "pop rbp",
+        "pop rdi",
+        "pop rsi",
+        "pop rdx",
+        "pop r10",
+        "pop rbx",
+        "pop rax",
+        "add rsp, 16",
+        "pop rcx",
+        "add rsp, 8",
+        "pop r11",
+        "pop rsp",
+        "sysretq",
+        "2:",
+        "pop r15",
+        "pop r14",
+        "pop r13",
+        "pop r12",
+        "pop r11",
+        "pop r10",
+        "pop r9",
+        "pop r8",
+        "pop rbp",
+        "pop rdi",
+        "pop rsi",
+        "pop rdx",
+        "pop rcx",
+        "pop rbx",
+        "pop rax",
+        "add rsp, 16",
+        "iretq",
+        user_rsp = const core::mem::offset_of!(SyscallScratch, user_rsp),
+        kern_rsp = const core::mem::offset_of!(SyscallScratch, kern_rsp),
+        user_ss = const SegmentSelector::new(3, PrivilegeLevel::User).0,
+        user_cs = const SegmentSelector::new(4, PrivilegeLevel::User).0,
+        int_num = const FAST_SYSCALL_NUM,
+        int_num_off = const core::mem::offset_of!(RegisterState, int_num),
+        handler = sym fast_syscall_handler,
+        finish = sym crate::system::smp::finish_switch,
+        options(noreturn),
+    )
+}
+
+// Threads are not necessarily resumed through the same path they entered the kernel with,
+// so frames created by SYSCALL get turned into what SYSRET would have left behind.
+pub const fn leave_fast_frame(state: &mut RegisterState) {
+    if state.int_num != FAST_SYSCALL_NUM {
+        return;
+    }
+    state.r10 = state.rcx;
+    state.rcx = state.rip;
+    state.r11 = state.rflags;
+    state.int_num = 249;
+}
+
+pub fn setup_cpu() {
+    let cpu = crate::system::smp::current();
+    unsafe {
+        KernelGSBase(cpu.syscall.get() as u64).write();
+
+        // SYSCALL is always available in long mode, but check anyway.
+        if core::arch::x86_64::__cpuid(0x8000_0001).edx & (1 << 11) == 0 {
+            return;
+        }
+        ExtendedFeatureEnableReg::read()
+            .with_syscall_ext(true)
+            .write();
+        SystemCallTarget::new()
+            .with_syscall_cs_ss(SegmentSelector::new(1, PrivilegeLevel::Supervisor).0)
+            .with_sysret_cs_ss(SegmentSelector::new(2, PrivilegeLevel::User).0)
+            .write();
+        LongModeSystemCallTarget(syscall_entry as usize as u64).write();
+        SystemCallFlagMask(FLAG_MASK).write();
+    }
+}
@@ -0,0 +1,54 @@
+// Copyright (c) ChefKiss Inc 2021-2023. Licensed under the Thou Shalt Not Profit License version 1.5. See LICENSE for details.
+
+use alloc::collections::BTreeMap;
+
+use amd64::paging::PAGE_SIZE;
+
+// Hands out the virtual address space of a process, independently of the backing frames.
+#[derive(Debug)]
+pub struct VirtualAllocator {
+    free: BTreeMap<u64, u64>,
+}
+
+impl VirtualAllocator {
+    #[inline]
+    pub fn new(base: u64, end: u64) -> Self {
+        Self {
+            free: BTreeMap::from([(base, end - base)]),
+        }
+    }
+
+    // Every range is followed by an unmapped guard page, which also catches stack overflows.
+    #[inline]
+    const fn span(size: u64) -> Option<u64> {
+        match size.div_ceil(PAGE_SIZE).checked_add(1) {
+            Some(v) => v.checked_mul(PAGE_SIZE),
+            None => None,
+        }
+    }
+
+    pub fn alloc(&mut self, size: u64) -> Option<u64> {
+        let len = Self::span(size)?;
+        let (&start, &free) = self.free.iter().find(|(_, &v)| v >= len)?;
+        self.free.remove(&start);
+        if free > len {
+            self.free.insert(start + len, free - len);
+        }
+        Some(start)
+    }
+
+    pub fn free(&mut self, addr: u64, size: u64) {
+        let (mut start, mut len) = (addr, Self::span(size).unwrap());
+        if let Some((&prev, &prev_len)) = self.free.range(..start).next_back() {
+            if prev + prev_len == start {
+                self.free.remove(&prev);
+                start = prev;
+                len += prev_len;
+            }
+        }
+        if let Some(next_len) = self.free.remove(&(start + len)) {
+            len += next_len;
+        }
+        self.free.insert(start, len);
+    }
+}
@@ -1,5 +1,8 @@
 // Copyright (c) ChefKiss Inc 2021-2023. Licensed under the Thou Shalt Not Profit License version 1.5. See LICENSE for details.
 
+pub const DOUBLE_FAULT_IST: u8 = 1;
+pub const NMI_IST: u8 = 2;
+
 #[derive(Debug, Copy, Clone)]
 #[repr(C, packed)]
 pub struct TaskSegmentSelector {
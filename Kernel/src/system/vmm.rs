@@ -36,15 +36,12 @@ impl PageTableLvl4 {
         self.0.map_higher_half(&Self::alloc_entry);
     }
 
-    pub unsafe fn init(&mut self) {
-        // Fix performance by utilising the PAT mechanism
-        PageAttributeTable::new()
-            .with_pat0(PATEntry::WriteBack)
-            .with_pat1(PATEntry::WriteThrough)
-            .with_pat2(PATEntry::WriteCombining)
-            .with_pat3(PATEntry::WriteProtected)
-            .write();
+    pub unsafe fn unmap(&mut self, virt: u64, count: u64) {
+        self.0.unmap(virt, count);
+    }
 
+    pub unsafe fn init(&mut self) {
+        init_pat();
         self.map_higher_half();
         self.set_cr3();
     }
@@ -53,3 +50,13 @@ impl PageTableLvl4 {
         self.map(virt, phys, count, flags.with_pat_entry(1));
     }
 }
+
+pub unsafe fn init_pat() {
+    // Fix performance by utilising the PAT mechanism
+    PageAttributeTable::new()
+        .with_pat0(PATEntry::WriteBack)
+        .with_pat1(PATEntry::WriteThrough)
+        .with_pat2(PATEntry::WriteCombining)
+        .with_pat3(PATEntry::WriteProtected)
+        .write();
+}
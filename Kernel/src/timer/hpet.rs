@@ -16,6 +16,10 @@ impl Hpet {
         hpet.set_config(GeneralConfiguration::new().with_main_cnt_enable(true));
         Self { inner: hpet, clk }
     }
+
+    pub fn time_ns(&self) -> u64 {
+        (u128::from(self.inner.counter_value()) * u128::from(self.clk) / 1_000_000) as u64
+    }
 }
 
 impl super::Timer for Hpet {
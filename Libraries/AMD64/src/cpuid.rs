@@ -111,3 +111,30 @@ impl CPUIdentification {
         }
     }
 }
+
+#[derive(Debug, Clone, Copy)]
+pub struct ExtendedStateInfo {
+    pub supported: u64,
+    pub enabled_size: u32,
+    pub max_size: u32,
+}
+
+impl Default for ExtendedStateInfo {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ExtendedStateInfo {
+    // Only meaningful if XSAVE is supported, the size depends on what is currently enabled in XCR0.
+    #[inline]
+    #[must_use]
+    pub fn new() -> Self {
+        let res = unsafe { core::arch::x86_64::__cpuid_count(0xD, 0) };
+        Self {
+            supported: u64::from(res.eax) | (u64::from(res.edx) << 32),
+            enabled_size: res.ebx,
+            max_size: res.ecx,
+        }
+    }
+}
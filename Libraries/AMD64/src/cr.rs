@@ -0,0 +1,114 @@
+// Copyright (c) ChefKiss Inc 2021-2023. Licensed under the Thou Shalt Not Profit License version 1.5. See LICENSE for details.
+
+#[bitfield(u64)]
+pub struct ControlReg0 {
+    pub protected_mode: bool,
+    pub monitor_coproc: bool,
+    pub emulation: bool,
+    pub task_switched: bool,
+    pub extension_type: bool,
+    pub numeric_error: bool,
+    #[bits(10)]
+    __: u16,
+    pub write_protect: bool,
+    __: bool,
+    pub alignment_mask: bool,
+    #[bits(10)]
+    __: u16,
+    pub not_write_through: bool,
+    pub cache_disable: bool,
+    pub paging: bool,
+    __: u32,
+}
+
+impl ControlReg0 {
+    #[inline]
+    #[must_use]
+    pub unsafe fn read() -> Self {
+        let value: u64;
+        core::arch::asm!("mov {}, cr0", out(reg) value, options(nomem, nostack, preserves_flags));
+        value.into()
+    }
+
+    #[inline]
+    pub unsafe fn write(self) {
+        core::arch::asm!("mov cr0, {}", in(reg) u64::from(self), options(nostack, preserves_flags));
+    }
+}
+
+#[bitfield(u64)]
+pub struct ControlReg4 {
+    pub virtual_8086_ext: bool,
+    pub protected_virtual_ints: bool,
+    pub time_stamp_disable: bool,
+    pub debugging_ext: bool,
+    pub page_size_ext: bool,
+    pub physical_addr_ext: bool,
+    pub machine_check: bool,
+    pub page_global: bool,
+    pub perf_counter: bool,
+    pub os_fxsave: bool,
+    pub os_xmm_exceptions: bool,
+    pub user_mode_ins_prevention: bool,
+    pub five_level_paging: bool,
+    #[bits(3)]
+    __: u8,
+    pub fs_gs_base: bool,
+    pub pcid: bool,
+    pub os_xsave: bool,
+    __: bool,
+    pub supervisor_exec_prevention: bool,
+    pub supervisor_access_prevention: bool,
+    pub protection_keys: bool,
+    pub control_flow_enforcement: bool,
+    #[bits(40)]
+    __: u64,
+}
+
+impl ControlReg4 {
+    #[inline]
+    #[must_use]
+    pub unsafe fn read() -> Self {
+        let value: u64;
+        core::arch::asm!("mov {}, cr4", out(reg) value, options(nomem, nostack, preserves_flags));
+        value.into()
+    }
+
+    #[inline]
+    pub unsafe fn write(self) {
+        core::arch::asm!("mov cr4, {}", in(reg) u64::from(self), options(nostack, preserves_flags));
+    }
+}
+
+#[bitfield(u64)]
+pub struct ExtendedControlReg0 {
+    pub x87: bool,
+    pub sse: bool,
+    pub avx: bool,
+    pub mpx_bounds: bool,
+    pub mpx_csr: bool,
+    pub avx512_opmask: bool,
+    pub avx512_zmm_hi256: bool,
+    pub avx512_hi16_zmm: bool,
+    __: bool,
+    pub pkru: bool,
+    #[bits(54)]
+    __: u64,
+}
+
+impl ExtendedControlReg0 {
+    #[inline]
+    #[must_use]
+    pub unsafe fn read() -> Self {
+        let (low, high): (u32, u32);
+        core::arch::asm!("xgetbv", in("ecx") 0, out("eax") low, out("edx") high, options(nomem, nostack, preserves_flags));
+        ((u64::from(high) << 32) | u64::from(low)).into()
+    }
+
+    #[inline]
+    pub unsafe fn write(self) {
+        let value = u64::from(self);
+        let (low, high): (u32, u32) = (value as u32, (value >> 32) as u32);
+        core::arch::asm!("xsetbv", in("ecx") 0, in("eax") low, in("edx") high, options(nostack, preserves_flags));
+    }
+}
@@ -5,6 +5,7 @@
 #![allow(clippy::missing_safety_doc)]
 
 pub mod cpuid;
+pub mod cr;
 pub mod io;
 pub mod msr;
 pub mod paging;
@@ -3,6 +3,8 @@
 pub mod apic;
 pub mod efer;
 pub mod pat;
+pub mod seg_base;
+pub mod syscall;
 pub mod vm_cr;
 
 pub trait ModelSpecificReg: Sized + From<u64> {
@@ -0,0 +1,61 @@
+// Copyright (c) ChefKiss Inc 2021-2023. Licensed under the Thou Shalt Not Profit License version 1.5. See LICENSE for details.
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[repr(transparent)]
+pub struct FSBase(pub u64);
+
+impl From<u64> for FSBase {
+    fn from(value: u64) -> Self {
+        Self(value)
+    }
+}
+
+impl From<FSBase> for u64 {
+    fn from(value: FSBase) -> Self {
+        value.0
+    }
+}
+
+impl super::ModelSpecificReg for FSBase {
+    const MSR_NUM: u32 = 0xC000_0100;
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[repr(transparent)]
+pub struct GSBase(pub u64);
+
+impl From<u64> for GSBase {
+    fn from(value: u64) -> Self {
+        Self(value)
+    }
+}
+
+impl From<GSBase> for u64 {
+    fn from(value: GSBase) -> Self {
+        value.0
+    }
+}
+
+impl super::ModelSpecificReg for GSBase {
+    const MSR_NUM: u32 = 0xC000_0101;
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[repr(transparent)]
+pub struct KernelGSBase(pub u64);
+
+impl From<u64> for KernelGSBase {
+    fn from(value: u64) -> Self {
+        Self(value)
+    }
+}
+
+impl From<KernelGSBase> for u64 {
+    fn from(value: KernelGSBase) -> Self {
+        value.0
+    }
+}
+
+impl super::ModelSpecificReg for KernelGSBase {
+    const MSR_NUM: u32 = 0xC000_0102;
+}
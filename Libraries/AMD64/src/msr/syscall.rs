@@ -0,0 +1,52 @@
+// Copyright (c) ChefKiss Inc 2021-2023. Licensed under the Thou Shalt Not Profit License version 1.5. See LICENSE for details.
+
+#[bitfield(u64)]
+pub struct SystemCallTarget {
+    pub legacy_eip: u32,
+    pub syscall_cs_ss: u16,
+    pub sysret_cs_ss: u16,
+}
+
+impl super::ModelSpecificReg for SystemCallTarget {
+    const MSR_NUM: u32 = 0xC000_0081;
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[repr(transparent)]
+pub struct LongModeSystemCallTarget(pub u64);
+
+impl From<u64> for LongModeSystemCallTarget {
+    fn from(value: u64) -> Self {
+        Self(value)
+    }
+}
+
+impl From<LongModeSystemCallTarget> for u64 {
+    fn from(value: LongModeSystemCallTarget) -> Self {
+        value.0
+    }
+}
+
+impl super::ModelSpecificReg for LongModeSystemCallTarget {
+    const MSR_NUM: u32 = 0xC000_0082;
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[repr(transparent)]
+pub struct SystemCallFlagMask(pub u64);
+
+impl From<u64> for SystemCallFlagMask {
+    fn from(value: u64) -> Self {
+        Self(value)
+    }
+}
+
+impl From<SystemCallFlagMask> for u64 {
+    fn from(value: SystemCallFlagMask) -> Self {
+        value.0
+    }
+}
+
+impl super::ModelSpecificReg for SystemCallFlagMask {
+    const MSR_NUM: u32 = 0xC000_0084;
+}
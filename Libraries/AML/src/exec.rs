@@ -0,0 +1,1234 @@
+// Copyright (c) ChefKiss Inc 2021-2023. Licensed under the Thou Shalt Not Profit License version 1.5. See LICENSE for details.
+
+use alloc::{boxed::Box, string::String, vec::Vec};
+
+use super::{
+    name::{self, AMLName},
+    value::{
+        BufferField, BufferSource, FieldKind, FieldUnit, Method, MethodBody, Object, OpRegion,
+        Value,
+    },
+    AMLError, Interpreter,
+};
+
+const MAX_DEPTH: usize = 64;
+const MAX_LOOPS: usize = 0x10_0000;
+
+pub struct Stream {
+    data: &'static [u8],
+    pub pos: usize,
+}
+
+impl Stream {
+    pub const fn new(data: &'static [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    fn peek(&self) -> Result<u8, AMLError> {
+        self.data
+            .get(self.pos)
+            .copied()
+            .ok_or(AMLError::UnexpectedEnd)
+    }
+
+    fn peek_at(&self, off: usize) -> Result<u8, AMLError> {
+        self.data
+            .get(self.pos + off)
+            .copied()
+            .ok_or(AMLError::UnexpectedEnd)
+    }
+
+    fn next(&mut self) -> Result<u8, AMLError> {
+        let v = self.peek()?;
+        self.pos += 1;
+        Ok(v)
+    }
+
+    fn bytes(&mut self, n: usize) -> Result<&'static [u8], AMLError> {
+        let data = self.data;
+        let v = data
+            .get(self.pos..self.pos + n)
+            .ok_or(AMLError::UnexpectedEnd)?;
+        self.pos += n;
+        Ok(v)
+    }
+
+    fn le(&mut self, n: usize) -> Result<u64, AMLError> {
+        self.bytes(n).map(super::le_integer)
+    }
+
+    fn pkg_len(&mut self) -> Result<usize, AMLError> {
+        let lead = self.next()?;
+        let count = usize::from(lead >> 6);
+        Ok(if count == 0 {
+            usize::from(lead & 0x3F)
+        } else {
+            ((self.le(count)? as usize) << 4) | usize::from(lead & 0xF)
+        })
+    }
+
+    // The encoded length covers itself, so the end is relative to where it starts.
+    fn pkg_end(&mut self) -> Result<usize, AMLError> {
+        let start = self.pos;
+        let end = start + self.pkg_len()?;
+        if end > self.data.len() {
+            return Err(AMLError::UnexpectedEnd);
+        }
+        Ok(end)
+    }
+
+    fn name_seg(&mut self) -> Result<[u8; 4], AMLError> {
+        let seg: [u8; 4] = self.bytes(4)?.try_into().unwrap();
+        if !seg.iter().all(|&c| name::is_name_char(c)) || seg[0].is_ascii_digit() {
+            return Err(AMLError::InvalidName);
+        }
+        Ok(seg)
+    }
+
+    fn name_string(&mut self) -> Result<AMLName, AMLError> {
+        let mut name = AMLName::default();
+        if self.peek()? == b'\\' {
+            self.pos += 1;
+            name.root = true;
+        }
+        while self.peek()? == b'^' {
+            self.pos += 1;
+            name.up += 1;
+        }
+        let count = match self.peek()? {
+            0x00 => {
+                self.pos += 1;
+                0
+            }
+            0x2E => {
+                self.pos += 1;
+                2
+            }
+            0x2F => {
+                self.pos += 1;
+                usize::from(self.next()?)
+            }
+            _ => 1,
+        };
+        for _ in 0..count {
+            name.segs.push(self.name_seg()?);
+        }
+        Ok(name)
+    }
+
+    const fn is_name_start(c: u8) -> bool {
+        matches!(c, b'A'..=b'Z' | b'_' | b'\\' | b'^' | 0x2E | 0x2F)
+    }
+
+    fn is_next(&self, c: u8) -> bool {
+        self.data.get(self.pos) == Some(&c)
+    }
+}
+
+pub struct Frame {
+    pub scope: String,
+    pub args: Vec<Value>,
+    pub locals: [Value; 8],
+    // Objects created while a method runs are removed again once it returns.
+    pub created: Option<Vec<String>>,
+}
+
+impl Frame {
+    pub fn new(scope: String) -> Self {
+        Self {
+            scope,
+            args: Vec::new(),
+            locals: Default::default(),
+            created: None,
+        }
+    }
+}
+
+pub enum Flow {
+    Normal,
+    Return(Value),
+    Break,
+    Continue,
+}
+
+enum Target {
+    None,
+    Debug,
+    Local(usize),
+    Arg(usize),
+    Name(String),
+    Index(Box<Self>, usize),
+    Value(Value),
+}
+
+impl Interpreter {
+    pub(super) fn insert(&mut self, frame: &mut Frame, path: String, obj: Object) {
+        if let Some(created) = frame.created.as_mut() {
+            created.push(path.clone());
+        } else if self.namespace.contains_key(&path) {
+            debug!("AML: {path} redefined");
+        }
+        self.namespace.insert(path, obj);
+    }
+
+    fn follow(&self, mut path: String) -> String {
+        for _ in 0..8 {
+            let Some(Object::Alias(target)) = self.namespace.get(&path) else {
+                break;
+            };
+            path = target.clone();
+        }
+        path
+    }
+
+    pub(super) fn lookup(&self, scope: &str, name: &AMLName) -> Option<String> {
+        if !name.is_searchable() {
+            let path = name.resolve(scope).ok()?;
+            return self
+                .namespace
+                .contains_key(&path)
+                .then(|| self.follow(path));
+        }
+
+        let mut scope = Some(scope);
+        while let Some(v) = scope {
+            let path = name::child(v, &name.segs[0]);
+            if self.namespace.contains_key(&path) {
+                return Some(self.follow(path));
+            }
+            scope = name::parent(v);
+        }
+        None
+    }
+
+    pub(super) fn invoke(&mut self, path: &str, args: Vec<Value>) -> Result<Value, AMLError> {
+        let Some(&Object::Method(method)) = self.namespace.get(path) else {
+            return Err(AMLError::InvalidType);
+        };
+        let body = match method.body {
+            MethodBody::Native(f) => return Ok(f(&args)),
+            MethodBody::Aml(v) => v,
+        };
+        if self.depth == MAX_DEPTH {
+            return Err(AMLError::TooDeep);
+        }
+
+        self.depth += 1;
+        let mut frame = Frame {
+            args,
+            created: Some(Vec::new()),
+            ..Frame::new(path.into())
+        };
+        let ret = self.exec_block(&mut Stream::new(body), body.len(), &mut frame);
+        self.depth -= 1;
+        for path in frame.created.unwrap() {
+            self.namespace.remove(&path);
+        }
+
+        match ret? {
+            Flow::Return(v) => Ok(v),
+            _ => Ok(Value::Integer(0)),
+        }
+    }
+
+    pub(super) fn exec_block(
+        &mut self,
+        s: &mut Stream,
+        end: usize,
+        frame: &mut Frame,
+    ) -> Result<Flow, AMLError> {
+        while s.pos < end {
+            let flow = self.exec_term(s, frame)?;
+            if !matches!(flow, Flow::Normal) {
+                return Ok(flow);
+            }
+        }
+        Ok(Flow::Normal)
+    }
+
+    pub(super) fn exec_scope(
+        &mut self,
+        s: &mut Stream,
+        end: usize,
+        frame: &mut Frame,
+        scope: String,
+    ) -> Result<Flow, AMLError> {
+        let outer = core::mem::replace(&mut frame.scope, scope);
+        let ret = self.exec_block(s, end, frame);
+        let scope = core::mem::replace(&mut frame.scope, outer);
+        match ret {
+            // While loading, a bad object only costs the rest of its scope instead of the whole table.
+            Err(e) if frame.created.is_none() => {
+                warn!("AML: Skipping rest of {scope}: {e:?}");
+                s.pos = end;
+                Ok(Flow::Normal)
+            }
+            v => v,
+        }
+    }
+
+    fn exec_term(&mut self, s: &mut Stream, frame: &mut Frame) -> Result<Flow, AMLError> {
+        match s.peek()? {
+            0x06 => {
+                s.pos += 1;
+                let source = s.name_string()?;
+                let alias = s.name_string()?.resolve(&frame.scope)?;
+                let source = match self.lookup(&frame.scope, &source) {
+                    Some(v) => v,
+                    None => source.resolve(&frame.scope)?,
+                };
+                self.insert(frame, alias, Object::Alias(source));
+            }
+            0x08 => {
+                s.pos += 1;
+                let path = s.name_string()?.resolve(&frame.scope)?;
+                let value = self.eval_term(s, frame)?;
+                self.insert(frame, path, Object::Value(value));
+            }
+            0x10 => {
+                s.pos += 1;
+                let end = s.pkg_end()?;
+                let name = s.name_string()?;
+                let path = match self.lookup(&frame.scope, &name) {
+                    Some(v) => v,
+                    None => {
+                        let path = name.resolve(&frame.scope)?;
+                        self.insert(frame, path.clone(), Object::Scope);
+                        path
+                    }
+                };
+                return self.exec_scope(s, end, frame, path);
+            }
+            0x14 => {
+                s.pos += 1;
+                let end = s.pkg_end()?;
+                let path = s.name_string()?.resolve(&frame.scope)?;
+                let flags = s.next()?;
+                let body = s.bytes(end - s.pos)?;
+                self.insert(
+                    frame,
+                    path,
+                    Object::Method(Method {
+                        body: MethodBody::Aml(body),
+                        arg_count: flags & 7,
+                    }),
+                );
+            }
+            0x15 => {
+                s.pos += 1;
+                s.name_string()?;
+                s.bytes(2)?;
+            }
+            0x5B => return self.exec_ext_term(s, frame),
+            0x86 => {
+                // Nothing listens to notifications yet.
+                s.pos += 1;
+                self.parse_target(s, frame)?;
+                self.eval_term(s, frame)?;
+            }
+            0x8A | 0x8B | 0x8C | 0x8D | 0x8F => {
+                let op = s.next()?;
+                let source = self.buffer_source(s, frame)?;
+                let index = self.eval_integer(s, frame)?;
+                let path = s.name_string()?.resolve(&frame.scope)?;
+                let (bit_offset, bit_len) = match op {
+                    0x8A => (index * 8, 32),
+                    0x8B => (index * 8, 16),
+                    0x8C => (index * 8, 8),
+                    0x8D => (index, 1),
+                    _ => (index * 8, 64),
+                };
+                self.insert(
+                    frame,
+                    path,
+                    Object::BufferField(BufferField {
+                        source,
+                        bit_offset,
+                        bit_len,
+                    }),
+                );
+            }
+            0x9F => {
+                s.pos += 1;
+                return Ok(Flow::Continue);
+            }
+            0xA0 => {
+                s.pos += 1;
+                let end = s.pkg_end()?;
+                let predicate = self.eval_integer(s, frame)? != 0;
+                if predicate {
+                    let flow = self.exec_block(s, end, frame)?;
+                    if !matches!(flow, Flow::Normal) {
+                        return Ok(flow);
+                    }
+                }
+                s.pos = end;
+                if s.is_next(0xA1) {
+                    s.pos += 1;
+                    let end = s.pkg_end()?;
+                    if !predicate {
+                        let flow = self.exec_block(s, end, frame)?;
+                        if !matches!(flow, Flow::Normal) {
+                            return Ok(flow);
+                        }
+                    }
+                    s.pos = end;
+                }
+            }
+            0xA1 => {
+                s.pos += 1;
+                s.pos = s.pkg_end()?;
+            }
+            0xA2 => {
+                s.pos += 1;
+                let end = s.pkg_end()?;
+                let predicate = s.pos;
+                let mut iterations = 0;
+                loop {
+                    s.pos = predicate;
+                    if self.eval_integer(s, frame)? == 0 {
+                        break;
+                    }
+                    iterations += 1;
+                    if iterations == MAX_LOOPS {
+                        return Err(AMLError::LoopLimit);
+                    }
+                    match self.exec_block(s, end, frame)? {
+                        Flow::Break => break,
+                        Flow::Return(v) => return Ok(Flow::Return(v)),
+                        Flow::Normal | Flow::Continue => {}
+                    }
+                }
+                s.pos = end;
+            }
+            0xA3 | 0xCC => s.pos += 1,
+            0xA4 => {
+                s.pos += 1;
+                return Ok(Flow::Return(self.eval_term(s, frame)?));
+            }
+            0xA5 => {
+                s.pos += 1;
+                return Ok(Flow::Break);
+            }
+            _ => {
+                self.eval_term(s, frame)?;
+            }
+        }
+        Ok(Flow::Normal)
+    }
+
+    fn exec_ext_term(&mut self, s: &mut Stream, frame: &mut Frame) -> Result<Flow, AMLError> {
+        let op = s.peek_at(1)?;
+        match op {
+            0x01 => {
+                s.pos += 2;
+                let path = s.name_string()?.resolve(&frame.scope)?;
+                s.next()?;
+                self.insert(frame, path, Object::Mutex);
+            }
+            0x02 => {
+                s.pos += 2;
+                let path = s.name_string()?.resolve(&frame.scope)?;
+                self.insert(frame, path, Object::Event);
+            }
+            0x13 => {
+                s.pos += 2;
+                let source = self.buffer_source(s, frame)?;
+                let bit_offset = self.eval_integer(s, frame)?;
+                let bit_len = self.eval_integer(s, frame)?;
+                let path = s.name_string()?.resolve(&frame.scope)?;
+                self.insert(
+                    frame,
+                    path,
+                    Object::BufferField(BufferField {
+                        source,
+                        bit_offset,
+                        bit_len,
+                    }),
+                );
+            }
+            0x1F | 0x20 | 0x2A | 0x88 => return Err(AMLError::Unsupported(op)),
+            0x21 => {
+                s.pos += 2;
+                let us = self.eval_integer(s, frame)?;
+                self.handler.stall(us);
+            }
+            0x22 => {
+                s.pos += 2;
+                let ms = self.eval_integer(s, frame)?;
+                self.handler.sleep(ms);
+            }
+            0x24 | 0x26 | 0x27 => {
+                s.pos += 2;
+                self.parse_target(s, frame)?;
+            }
+            0x32 => {
+                s.pos += 2;
+                let ty = s.next()?;
+                let code = s.le(4)?;
+                let arg = self.eval_integer(s, frame)?;
+                error!("AML: Fatal error {ty:#X}, code {code:#X}, argument {arg:#X}");
+                return Err(AMLError::Fatal);
+            }
+            0x80 => {
+                s.pos += 2;
+                let path = s.name_string()?.resolve(&frame.scope)?;
+                let space = s.next()?;
+                let offset = self.eval_integer(s, frame)?;
+                let len = self.eval_integer(s, frame)?;
+                self.insert(
+                    frame,
+                    path,
+                    Object::OpRegion(OpRegion { space, offset, len }),
+                );
+            }
+            0x81 => {
+                s.pos += 2;
+                let end = s.pkg_end()?;
+                let region = self.resolve(s, frame)?;
+                let flags = s.next()?;
+                self.parse_fields(s, end, frame, flags, &FieldKind::Region(region))?;
+            }
+            0x82..=0x85 => {
+                s.pos += 2;
+                let end = s.pkg_end()?;
+                let path = s.name_string()?.resolve(&frame.scope)?;
+                let obj = match op {
+                    0x82 => Object::Device,
+                    0x83 => {
+                        s.bytes(6)?;
+                        Object::Processor
+                    }
+                    0x84 => {
+                        s.bytes(3)?;
+                        Object::PowerResource
+                    }
+                    _ => Object::ThermalZone,
+                };
+                self.insert(frame, path.clone(), obj);
+                return self.exec_scope(s, end, frame, path);
+            }
+            0x86 => {
+                s.pos += 2;
+                let end = s.pkg_end()?;
+                let index = self.resolve(s, frame)?;
+                let data = self.resolve(s, frame)?;
+                let flags = s.next()?;
+                self.parse_fields(s, end, frame, flags, &FieldKind::Index { index, data })?;
+            }
+            0x87 => {
+                s.pos += 2;
+                let end = s.pkg_end()?;
+                let region = self.resolve(s, frame)?;
+                let bank = self.resolve(s, frame)?;
+                let value = self.eval_integer(s, frame)?;
+                let flags = s.next()?;
+                self.parse_fields(
+                    s,
+                    end,
+                    frame,
+                    flags,
+                    &FieldKind::Bank {
+                        region,
+                        bank,
+                        value,
+                    },
+                )?;
+            }
+            _ => {
+                self.eval_term(s, frame)?;
+            }
+        }
+        Ok(Flow::Normal)
+    }
+
+    fn resolve(&self, s: &mut Stream, frame: &Frame) -> Result<String, AMLError> {
+        let name = s.name_string()?;
+        match self.lookup(&frame.scope, &name) {
+            Some(v) => Ok(v),
+            None => Err(AMLError::NotFound(name.resolve(&frame.scope)?)),
+        }
+    }
+
+    fn parse_fields(
+        &mut self,
+        s: &mut Stream,
+        end: usize,
+        frame: &mut Frame,
+        mut flags: u8,
+        kind: &FieldKind,
+    ) -> Result<(), AMLError> {
+        let mut bit_offset = 0;
+        while s.pos < end {
+            match s.peek()? {
+                0x00 => {
+                    s.pos += 1;
+                    bit_offset += s.pkg_len()? as u64;
+                }
+                0x01 | 0x03 => {
+                    let extended = s.next()? == 0x03;
+                    flags = (flags & !0xF) | (s.next()? & 0xF);
+                    s.bytes(if extended { 2 } else { 1 })?;
+                }
+                0x02 => {
+                    s.pos += 1;
+                    if s.is_next(0x11) {
+                        self.eval_term(s, frame)?;
+                    } else {
+                        s.name_string()?;
+                    }
+                }
+                _ => {
+                    let seg = s.name_seg()?;
+                    let bit_len = s.pkg_len()? as u64;
+                    let path = name::child(&frame.scope, &seg);
+                    self.insert(
+                        frame,
+                        path,
+                        Object::Field(FieldUnit {
+                            kind: kind.clone(),
+                            bit_offset,
+                            bit_len,
+                            flags,
+                        }),
+                    );
+                    bit_offset += bit_len;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn buffer_source(
+        &mut self,
+        s: &mut Stream,
+        frame: &mut Frame,
+    ) -> Result<BufferSource, AMLError> {
+        if Stream::is_name_start(s.peek()?) {
+            let start = s.pos;
+            let path = self.resolve(s, frame)?;
+            if matches!(
+                self.namespace.get(&path),
+                Some(Object::Value(Value::Buffer(_)))
+            ) {
+                return Ok(BufferSource::Named(path));
+            }
+            s.pos = start;
+        }
+        Ok(BufferSource::Owned(self.eval_term(s, frame)?.as_buffer()?))
+    }
+
+    fn eval_integer(&mut self, s: &mut Stream, frame: &mut Frame) -> Result<u64, AMLError> {
+        self.eval_term(s, frame)?.as_integer()
+    }
+
+    fn package_element(&mut self, s: &mut Stream, frame: &mut Frame) -> Result<Value, AMLError> {
+        if !Stream::is_name_start(s.peek()?) {
+            return self.eval_term(s, frame);
+        }
+        // Names in packages are references, they may point to objects that are only declared later on.
+        let name = s.name_string()?;
+        Ok(Value::Reference(match self.lookup(&frame.scope, &name) {
+            Some(v) => v,
+            None => name.resolve(&frame.scope)?,
+        }))
+    }
+
+    fn call(&mut self, s: &mut Stream, frame: &mut Frame, path: &str) -> Result<Value, AMLError> {
+        let Some(&Object::Method(method)) = self.namespace.get(path) else {
+            return self.read_named(path);
+        };
+        let args = (0..method.arg_count)
+            .map(|_| self.eval_term(s, frame))
+            .collect::<Result<_, _>>()?;
+        self.invoke(path, args)
+    }
+
+    pub(super) fn read_named(&mut self, path: &str) -> Result<Value, AMLError> {
+        match self.namespace.get(path) {
+            None => Err(AMLError::NotFound(path.into())),
+            Some(Object::Value(v)) => Ok(v.clone()),
+            Some(Object::Field(field)) => {
+                let field = field.clone();
+                self.read_field(&field)
+            }
+            Some(Object::BufferField(field)) => {
+                let bits = match &field.source {
+                    BufferSource::Owned(v) => {
+                        super::value::read_bits(v, field.bit_offset, field.bit_len)
+                    }
+                    BufferSource::Named(source) => match self.namespace.get(source) {
+                        Some(Object::Value(Value::Buffer(v))) => {
+                            super::value::read_bits(v, field.bit_offset, field.bit_len)
+                        }
+                        _ => return Err(AMLError::InvalidType),
+                    },
+                };
+                Ok(super::value::from_bits(bits, field.bit_len))
+            }
+            Some(Object::Method(_)) => self.invoke(path, Vec::new()),
+            Some(_) => Ok(Value::Reference(path.into())),
+        }
+    }
+
+    fn store_named(&mut self, path: &str, v: Value) -> Result<(), AMLError> {
+        match self.namespace.get_mut(path) {
+            None => Err(AMLError::NotFound(path.into())),
+            Some(Object::Field(field)) => {
+                let field = field.clone();
+                self.write_field(&field, &v)
+            }
+            Some(Object::BufferField(field)) => {
+                let (offset, len) = (field.bit_offset, field.bit_len);
+                let source = match &field.source {
+                    BufferSource::Named(source) => source.clone(),
+                    BufferSource::Owned(_) => path.into(),
+                };
+                let data = v.as_buffer()?;
+                match self.namespace.get_mut(&source) {
+                    Some(
+                        Object::Value(Value::Buffer(buf))
+                        | Object::BufferField(BufferField {
+                            source: BufferSource::Owned(buf),
+                            ..
+                        }),
+                    ) => {
+                        super::value::write_bits(buf, offset, len, &data);
+                        Ok(())
+                    }
+                    _ => Err(AMLError::InvalidType),
+                }
+            }
+            // Stores into named data convert the value to the type the object already has.
+            Some(Object::Value(old)) => {
+                *old = match old {
+                    Value::Integer(_) => Value::Integer(v.as_integer()?),
+                    Value::Buffer(old) => {
+                        let mut new = v.as_buffer()?;
+                        new.resize(old.len(), 0);
+                        Value::Buffer(new)
+                    }
+                    Value::String(_) => Value::String(v.as_string()?),
+                    _ => v,
+                };
+                Ok(())
+            }
+            Some(_) => Err(AMLError::InvalidType),
+        }
+    }
+
+    fn parse_target(&mut self, s: &mut Stream, frame: &mut Frame) -> Result<Target, AMLError> {
+        let op = s.peek()?;
+        Ok(match op {
+            0x00 => {
+                s.pos += 1;
+                Target::None
+            }
+            0x60..=0x67 => {
+                s.pos += 1;
+                Target::Local(usize::from(op - 0x60))
+            }
+            0x68..=0x6E => {
+                s.pos += 1;
+                Target::Arg(usize::from(op - 0x68))
+            }
+            0x5B if s.peek_at(1)? == 0x31 => {
+                s.pos += 2;
+                Target::Debug
+            }
+            0x88 => {
+                s.pos += 1;
+                let source = self.parse_target(s, frame)?;
+                let index = self.eval_integer(s, frame)? as usize;
+                self.parse_target(s, frame)?;
+                Target::Index(source.into(), index)
+            }
+            c if Stream::is_name_start(c) => {
+                let name = s.name_string()?;
+                match self.lookup(&frame.scope, &name) {
+                    Some(path) if matches!(self.namespace.get(&path), Some(Object::Method(_))) => {
+                        Target::Value(self.call(s, frame, &path)?)
+                    }
+                    Some(path) => Target::Name(path),
+                    None => Target::Name(name.resolve(&frame.scope)?),
+                }
+            }
+            _ => match self.eval_term(s, frame)? {
+                Value::Reference(path) => Target::Name(path),
+                v => Target::Value(v),
+            },
+        })
+    }
+
+    fn read_target(&mut self, frame: &Frame, target: &Target) -> Result<Value, AMLError> {
+        match target {
+            Target::None | Target::Debug => Ok(Value::Uninitialized),
+            Target::Local(i) => Ok(frame.locals[*i].clone()),
+            Target::Arg(i) => Ok(frame.args.get(*i).cloned().unwrap_or_default()),
+            Target::Name(path) => self.read_named(path),
+            Target::Index(source, index) => {
+                let source = self.read_target(frame, source)?;
+                element(&source, *index)
+            }
+            Target::Value(v) => Ok(v.clone()),
+        }
+    }
+
+    fn store(&mut self, frame: &mut Frame, target: &Target, v: Value) -> Result<(), AMLError> {
+        match target {
+            Target::None | Target::Value(_) => Ok(()),
+            Target::Debug => {
+                debug!("AML: {v:X?}");
+                Ok(())
+            }
+            Target::Local(i) => {
+                frame.locals[*i] = v;
+                Ok(())
+            }
+            Target::Arg(i) => match frame.args.get(*i) {
+                Some(Value::Reference(path)) => {
+                    let path = path.clone();
+                    self.store_named(&path, v)
+                }
+                _ => {
+                    if frame.args.len() <= *i {
+                        frame.args.resize(*i + 1, Value::Uninitialized);
+                    }
+                    frame.args[*i] = v;
+                    Ok(())
+                }
+            },
+            Target::Name(path) => self.store_named(path, v),
+            Target::Index(source, index) => {
+                let mut container = self.read_target(frame, source)?;
+                match &mut container {
+                    Value::Buffer(buf) => {
+                        *buf.get_mut(*index).ok_or(AMLError::InvalidArgument)? =
+                            v.as_integer()? as u8;
+                    }
+                    Value::Package(elems) => {
+                        *elems.get_mut(*index).ok_or(AMLError::InvalidArgument)? = v;
+                    }
+                    _ => return Err(AMLError::InvalidType),
+                }
+                self.store(frame, source, container)
+            }
+        }
+    }
+
+    fn binary(
+        &mut self,
+        s: &mut Stream,
+        frame: &mut Frame,
+        f: impl FnOnce(u64, u64) -> Result<u64, AMLError>,
+    ) -> Result<Value, AMLError> {
+        let a = self.eval_integer(s, frame)?;
+        let b = self.eval_integer(s, frame)?;
+        let v = Value::Integer(f(a, b)? & self.int_mask);
+        let target = self.parse_target(s, frame)?;
+        self.store(frame, &target, v.clone())?;
+        Ok(v)
+    }
+
+    fn unary(
+        &mut self,
+        s: &mut Stream,
+        frame: &mut Frame,
+        f: impl FnOnce(Value) -> Result<Value, AMLError>,
+    ) -> Result<Value, AMLError> {
+        let v = f(self.eval_term(s, frame)?)?;
+        let target = self.parse_target(s, frame)?;
+        self.store(frame, &target, v.clone())?;
+        Ok(v)
+    }
+
+    fn compare(
+        &mut self,
+        s: &mut Stream,
+        frame: &mut Frame,
+    ) -> Result<core::cmp::Ordering, AMLError> {
+        let a = self.eval_term(s, frame)?;
+        let b = self.eval_term(s, frame)?;
+        Ok(match a {
+            Value::String(a) => a.as_str().cmp(b.as_string()?.as_str()),
+            Value::Buffer(a) => a.cmp(&b.as_buffer()?),
+            a => a.as_integer()?.cmp(&b.as_integer()?),
+        })
+    }
+
+    const fn boolean(&self, v: bool) -> Value {
+        Value::Integer(if v { self.int_mask } else { 0 })
+    }
+
+    #[allow(clippy::too_many_lines)]
+    pub(super) fn eval_term(
+        &mut self,
+        s: &mut Stream,
+        frame: &mut Frame,
+    ) -> Result<Value, AMLError> {
+        let op = s.next()?;
+        Ok(match op {
+            0x00 => Value::Integer(0),
+            0x01 => Value::Integer(1),
+            0xFF => Value::Integer(self.int_mask),
+            0x0A => Value::Integer(s.le(1)?),
+            0x0B => Value::Integer(s.le(2)?),
+            0x0C => Value::Integer(s.le(4)?),
+            0x0E => Value::Integer(s.le(8)?),
+            0x0D => {
+                let start = s.pos;
+                while s.next()? != 0 {}
+                Value::String(String::from_utf8_lossy(&s.data[start..s.pos - 1]).into())
+            }
+            0x11 => {
+                let end = s.pkg_end()?;
+                let size = self.eval_integer(s, frame)? as usize;
+                let mut buf = s.bytes(end - s.pos)?.to_vec();
+                buf.resize(size.max(buf.len()), 0);
+                Value::Buffer(buf)
+            }
+            0x12 | 0x13 => {
+                let end = s.pkg_end()?;
+                let count = if op == 0x12 {
+                    usize::from(s.next()?)
+                } else {
+                    self.eval_integer(s, frame)? as usize
+                };
+                let mut elems = Vec::new();
+                while s.pos < end {
+                    elems.push(self.package_element(s, frame)?);
+                }
+                if elems.len() < count {
+                    elems.resize(count, Value::Uninitialized);
+                }
+                Value::Package(elems)
+            }
+            0x60..=0x67 => frame.locals[usize::from(op - 0x60)].clone(),
+            0x68..=0x6E => frame
+                .args
+                .get(usize::from(op - 0x68))
+                .cloned()
+                .unwrap_or_default(),
+            0x70 | 0x9D => {
+                let v = self.eval_term(s, frame)?;
+                let target = self.parse_target(s, frame)?;
+                self.store(frame, &target, v.clone())?;
+                v
+            }
+            0x71 => match self.parse_target(s, frame)? {
+                Target::Name(path) => Value::Reference(path),
+                _ => return Err(AMLError::Unsupported(op)),
+            },
+            0x72 => self.binary(s, frame, |a, b| Ok(a.wrapping_add(b)))?,
+            0x73 => {
+                let a = self.eval_term(s, frame)?;
+                let b = self.eval_term(s, frame)?;
+                let v = match a {
+                    Value::String(a) => Value::String(a + &b.as_string()?),
+                    a => {
+                        let mut a = a.as_buffer()?;
+                        a.extend(b.as_buffer()?);
+                        Value::Buffer(a)
+                    }
+                };
+                let target = self.parse_target(s, frame)?;
+                self.store(frame, &target, v.clone())?;
+                v
+            }
+            0x74 => self.binary(s, frame, |a, b| Ok(a.wrapping_sub(b)))?,
+            0x75 | 0x76 => {
+                let target = self.parse_target(s, frame)?;
+                let v = self.read_target(frame, &target)?.as_integer()?;
+                let v = if op == 0x75 {
+                    v.wrapping_add(1)
+                } else {
+                    v.wrapping_sub(1)
+                };
+                let v = Value::Integer(v & self.int_mask);
+                self.store(frame, &target, v.clone())?;
+                v
+            }
+            0x77 => self.binary(s, frame, |a, b| Ok(a.wrapping_mul(b)))?,
+            0x78 => {
+                let a = self.eval_integer(s, frame)?;
+                let b = self.eval_integer(s, frame)?;
+                if b == 0 {
+                    return Err(AMLError::DivideByZero);
+                }
+                let remainder = self.parse_target(s, frame)?;
+                self.store(frame, &remainder, Value::Integer(a % b))?;
+                let quotient = self.parse_target(s, frame)?;
+                self.store(frame, &quotient, Value::Integer(a / b))?;
+                Value::Integer(a / b)
+            }
+            0x79 => self.binary(s, frame, |a, b| {
+                Ok(a.checked_shl(b.try_into().unwrap_or(u32::MAX)).unwrap_or(0))
+            })?,
+            0x7A => self.binary(s, frame, |a, b| {
+                Ok(a.checked_shr(b.try_into().unwrap_or(u32::MAX)).unwrap_or(0))
+            })?,
+            0x7B => self.binary(s, frame, |a, b| Ok(a & b))?,
+            0x7C => self.binary(s, frame, |a, b| Ok(!(a & b)))?,
+            0x7D => self.binary(s, frame, |a, b| Ok(a | b))?,
+            0x7E => self.binary(s, frame, |a, b| Ok(!(a | b)))?,
+            0x7F => self.binary(s, frame, |a, b| Ok(a ^ b))?,
+            0x80 => {
+                let mask = self.int_mask;
+                self.unary(s, frame, |v| Ok(Value::Integer(!v.as_integer()? & mask)))?
+            }
+            0x81 => self.unary(s, frame, |v| {
+                let v = v.as_integer()?;
+                Ok(Value::Integer(if v == 0 {
+                    0
+                } else {
+                    64 - u64::from(v.leading_zeros())
+                }))
+            })?,
+            0x82 => self.unary(s, frame, |v| {
+                let v = v.as_integer()?;
+                Ok(Value::Integer(if v == 0 {
+                    0
+                } else {
+                    u64::from(v.trailing_zeros()) + 1
+                }))
+            })?,
+            0x83 => match self.eval_term(s, frame)? {
+                Value::Reference(path) => self.read_named(&path)?,
+                Value::String(path) => {
+                    let path = name::parse_path(&path)?;
+                    self.read_named(&path)?
+                }
+                v => v,
+            },
+            0x84 => {
+                let strip = |mut v: Vec<u8>| {
+                    if v.len() >= 2 && v[v.len() - 2] == 0x79 {
+                        v.truncate(v.len() - 2);
+                    }
+                    v
+                };
+                let mut a = strip(self.eval_term(s, frame)?.as_buffer()?);
+                a.extend(strip(self.eval_term(s, frame)?.as_buffer()?));
+                a.extend([0x79, 0]);
+                let v = Value::Buffer(a);
+                let target = self.parse_target(s, frame)?;
+                self.store(frame, &target, v.clone())?;
+                v
+            }
+            0x85 => self.binary(s, frame, |a, b| {
+                a.checked_rem(b).ok_or(AMLError::DivideByZero)
+            })?,
+            0x87 => {
+                let target = self.parse_target(s, frame)?;
+                Value::Integer(match self.read_target(frame, &target)? {
+                    Value::String(v) => v.len() as u64,
+                    Value::Buffer(v) => v.len() as u64,
+                    Value::Package(v) => v.len() as u64,
+                    _ => return Err(AMLError::InvalidType),
+                })
+            }
+            0x88 => {
+                let source = self.eval_term(s, frame)?;
+                let index = self.eval_integer(s, frame)? as usize;
+                let v = element(&source, index)?;
+                let target = self.parse_target(s, frame)?;
+                self.store(frame, &target, v.clone())?;
+                v
+            }
+            0x89 => {
+                let Value::Package(elems) = self.eval_term(s, frame)? else {
+                    return Err(AMLError::InvalidType);
+                };
+                let op1 = s.next()?;
+                let a = self.eval_integer(s, frame)?;
+                let op2 = s.next()?;
+                let b = self.eval_integer(s, frame)?;
+                let start = self.eval_integer(s, frame)? as usize;
+                let matches = |op, v: u64, operand| match op {
+                    0 => true,
+                    1 => v == operand,
+                    2 => v <= operand,
+                    3 => v < operand,
+                    4 => v >= operand,
+                    5 => v > operand,
+                    _ => false,
+                };
+                elems
+                    .iter()
+                    .enumerate()
+                    .skip(start)
+                    .find(|(_, v)| {
+                        v.as_integer()
+                            .is_ok_and(|v| matches(op1, v, a) && matches(op2, v, b))
+                    })
+                    .map_or(Value::Integer(self.int_mask), |(i, _)| {
+                        Value::Integer(i as u64)
+                    })
+            }
+            0x8E => {
+                let target = self.parse_target(s, frame)?;
+                Value::Integer(match &target {
+                    Target::Name(path) => self
+                        .namespace
+                        .get(path)
+                        .ok_or_else(|| AMLError::NotFound(path.clone()))?
+                        .type_id(),
+                    Target::Debug => 16,
+                    target => self.read_target(frame, target)?.type_id(),
+                })
+            }
+            0x90 => {
+                let a = self.eval_integer(s, frame)?;
+                let b = self.eval_integer(s, frame)?;
+                self.boolean(a != 0 && b != 0)
+            }
+            0x91 => {
+                let a = self.eval_integer(s, frame)?;
+                let b = self.eval_integer(s, frame)?;
+                self.boolean(a != 0 || b != 0)
+            }
+            0x92 => {
+                let v = self.eval_integer(s, frame)?;
+                self.boolean(v == 0)
+            }
+            0x93 => {
+                let v = self.compare(s, frame)?;
+                self.boolean(v.is_eq())
+            }
+            0x94 => {
+                let v = self.compare(s, frame)?;
+                self.boolean(v.is_gt())
+            }
+            0x95 => {
+                let v = self.compare(s, frame)?;
+                self.boolean(v.is_lt())
+            }
+            0x96 => self.unary(s, frame, |v| v.as_buffer().map(Value::Buffer))?,
+            0x97 => self.unary(s, frame, |v| {
+                Ok(Value::String(match v {
+                    Value::Buffer(v) => v
+                        .iter()
+                        .map(|b| format!("{b}"))
+                        .collect::<Vec<_>>()
+                        .join(","),
+                    v => format!("{}", v.as_integer()?),
+                }))
+            })?,
+            0x98 => self.unary(s, frame, |v| {
+                Ok(Value::String(match v {
+                    Value::Buffer(v) => v
+                        .iter()
+                        .map(|b| format!("0x{b:02X}"))
+                        .collect::<Vec<_>>()
+                        .join(","),
+                    v => format!("0x{:X}", v.as_integer()?),
+                }))
+            })?,
+            0x99 => self.unary(s, frame, |v| {
+                Ok(Value::Integer(match v {
+                    Value::String(v) => super::value::parse_integer(&v, 10),
+                    v => v.as_integer()?,
+                }))
+            })?,
+            0x9C => {
+                let buf = self.eval_term(s, frame)?.as_buffer()?;
+                let len = self.eval_integer(s, frame)? as usize;
+                let v = Value::String(
+                    buf.iter()
+                        .take(len)
+                        .take_while(|&&b| b != 0)
+                        .map(|&b| char::from(b))
+                        .collect(),
+                );
+                let target = self.parse_target(s, frame)?;
+                self.store(frame, &target, v.clone())?;
+                v
+            }
+            0x9E => {
+                let source = self.eval_term(s, frame)?;
+                let index = self.eval_integer(s, frame)? as usize;
+                let len = self.eval_integer(s, frame)? as usize;
+                let v = match source {
+                    Value::String(v) => Value::String(v.chars().skip(index).take(len).collect()),
+                    v => Value::Buffer(v.as_buffer()?.into_iter().skip(index).take(len).collect()),
+                };
+                let target = self.parse_target(s, frame)?;
+                self.store(frame, &target, v.clone())?;
+                v
+            }
+            0x5B => self.eval_ext_term(s, frame)?,
+            c if Stream::is_name_start(c) => {
+                s.pos -= 1;
+                let name = s.name_string()?;
+                let Some(path) = self.lookup(&frame.scope, &name) else {
+                    return Err(AMLError::NotFound(name.resolve(&frame.scope)?));
+                };
+                self.call(s, frame, &path)?
+            }
+            op => return Err(AMLError::InvalidOpcode(op)),
+        })
+    }
+
+    fn eval_ext_term(&mut self, s: &mut Stream, frame: &mut Frame) -> Result<Value, AMLError> {
+        let op = s.next()?;
+        Ok(match op {
+            0x12 => {
+                let path = if Stream::is_name_start(s.peek()?) {
+                    let name = s.name_string()?;
+                    self.lookup(&frame.scope, &name)
+                } else {
+                    match self.parse_target(s, frame)? {
+                        Target::Name(path) => Some(path),
+                        _ => None,
+                    }
+                };
+                let target = self.parse_target(s, frame)?;
+                if let Some(path) = &path {
+                    self.store(frame, &target, Value::Reference(path.clone()))?;
+                }
+                self.boolean(path.is_some())
+            }
+            0x23 => {
+                self.parse_target(s, frame)?;
+                s.bytes(2)?;
+                Value::Integer(0)
+            }
+            0x25 => {
+                self.parse_target(s, frame)?;
+                self.eval_term(s, frame)?;
+                Value::Integer(0)
+            }
+            0x28 => self.unary(s, frame, |v| {
+                let v = v.as_integer()?;
+                Ok(Value::Integer(
+                    (0..16)
+                        .rev()
+                        .fold(0, |acc, i| acc * 10 + ((v >> (i * 4)) & 0xF)),
+                ))
+            })?,
+            0x29 => self.unary(s, frame, |v| {
+                let mut v = v.as_integer()?;
+                let mut bcd = 0;
+                for i in 0..16 {
+                    bcd |= (v % 10) << (i * 4);
+                    v /= 10;
+                }
+                Ok(Value::Integer(bcd))
+            })?,
+            0x30 => Value::Integer(2),
+            0x31 => Value::Uninitialized,
+            0x33 => Value::Integer(self.handler.time_ns() / 100),
+            op => return Err(AMLError::InvalidOpcode(op)),
+        })
+    }
+}
+
+fn element(source: &Value, index: usize) -> Result<Value, AMLError> {
+    match source {
+        Value::Buffer(v) => v.get(index).map(|&b| Value::Integer(b.into())),
+        Value::String(v) => v.as_bytes().get(index).map(|&b| Value::Integer(b.into())),
+        Value::Package(v) => v.get(index).cloned(),
+        _ => return Err(AMLError::InvalidType),
+    }
+    .ok_or(AMLError::InvalidArgument)
+}
@@ -0,0 +1,346 @@
+// Copyright (c) ChefKiss Inc 2021-2023. Licensed under the Thou Shalt Not Profit License version 1.5. See LICENSE for details.
+
+#![no_std]
+#![deny(warnings, clippy::cargo, clippy::nursery, unused_extern_crates)]
+
+use alloc::{boxed::Box, collections::BTreeMap, string::String, vec::Vec};
+
+use self::{
+    exec::{Frame, Stream},
+    name::ROOT,
+    resource::Resource,
+    value::{Method, MethodBody, Object, Value},
+};
+
+#[macro_use]
+extern crate alloc;
+#[macro_use]
+extern crate log;
+
+mod exec;
+pub mod name;
+mod region;
+pub mod resource;
+pub mod value;
+
+#[derive(Debug)]
+pub enum AMLError {
+    UnexpectedEnd,
+    InvalidOpcode(u8),
+    InvalidName,
+    InvalidType,
+    InvalidArgument,
+    NotFound(String),
+    DivideByZero,
+    LoopLimit,
+    TooDeep,
+    Unsupported(u8),
+    Fatal,
+}
+
+pub fn le_integer(bytes: &[u8]) -> u64 {
+    bytes
+        .iter()
+        .take(8)
+        .rev()
+        .fold(0, |acc, &b| (acc << 8) | u64::from(b))
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct PCIRoute {
+    pub slot: u8,
+    pub pin: u8,
+    pub line: u32,
+    // ISA lines still have to go through the interrupt source overrides, the rest are GSIs.
+    pub isa: bool,
+    pub level_triggered: bool,
+    pub active_low: bool,
+}
+
+// Everything the interpreter needs from the machine it runs on. Accesses write when `v` is set, otherwise read.
+pub trait Handler: Send {
+    fn memory_access(&mut self, addr: u64, width: u64, v: Option<u64>) -> u64;
+    fn port_access(&mut self, port: u16, width: u64, v: Option<u64>) -> u64;
+    fn pci_access(
+        &mut self,
+        bus: u8,
+        slot: u8,
+        func: u8,
+        offset: u8,
+        width: u64,
+        v: Option<u64>,
+    ) -> u64;
+    fn sleep(&mut self, ms: u64);
+    fn stall(&mut self, us: u64);
+    fn time_ns(&self) -> u64;
+}
+
+// Firmware picks its code paths by the OS it detects, claim to be what it was tested against.
+const OSI_STRINGS: &[&str] = &[
+    "Windows 2000",
+    "Windows 2001",
+    "Windows 2001 SP1",
+    "Windows 2001.1",
+    "Windows 2001 SP2",
+    "Windows 2001.1 SP1",
+    "Windows 2006",
+    "Windows 2006.1",
+    "Windows 2006 SP1",
+    "Windows 2006 SP2",
+    "Windows 2009",
+    "Windows 2012",
+    "Windows 2013",
+    "Windows 2015",
+    "Module Device",
+    "Processor Device",
+    "3.0 Thermal Model",
+    "Extended Address Space Descriptor",
+];
+
+fn osi(args: &[Value]) -> Value {
+    let supported =
+        matches!(args.first(), Some(Value::String(v)) if OSI_STRINGS.contains(&v.as_str()));
+    Value::Integer(if supported { u64::MAX } else { 0 })
+}
+
+fn eisa_id(id: u64) -> String {
+    let id = (id as u32).swap_bytes();
+    let c = |shift: u32| char::from(((id >> shift) & 0x1F) as u8 + 0x40);
+    format!("{}{}{}{:04X}", c(26), c(21), c(16), id & 0xFFFF)
+}
+
+fn device_id(v: &Value) -> Option<String> {
+    match v {
+        Value::Integer(v) => Some(eisa_id(*v)),
+        Value::String(v) => Some(v.clone()),
+        _ => None,
+    }
+}
+
+pub struct Interpreter {
+    handler: Box<dyn Handler>,
+    namespace: BTreeMap<String, Object>,
+    int_mask: u64,
+    depth: usize,
+}
+
+impl Interpreter {
+    pub fn new(handler: Box<dyn Handler>) -> Self {
+        let mut namespace = BTreeMap::from([(ROOT.into(), Object::Scope)]);
+        for v in ["\\_GPE", "\\_PR_", "\\_SB_", "\\_SI_", "\\_TZ_"] {
+            namespace.insert(v.into(), Object::Scope);
+        }
+        namespace.insert("\\_GL_".into(), Object::Mutex);
+        namespace.insert(
+            "\\_OS_".into(),
+            Object::Value(Value::String("Microsoft Windows NT".into())),
+        );
+        namespace.insert("\\_REV".into(), Object::Value(Value::Integer(2)));
+        namespace.insert(
+            "\\_OSI".into(),
+            Object::Method(Method {
+                body: MethodBody::Native(osi),
+                arg_count: 1,
+            }),
+        );
+        Self {
+            handler,
+            namespace,
+            int_mask: u64::MAX,
+            depth: 0,
+        }
+    }
+
+    // Integers are 32-bit wide for DSDTs older than revision 2.
+    pub fn load_table(
+        &mut self,
+        signature: &str,
+        revision: u8,
+        data: &'static [u8],
+    ) -> Result<(), AMLError> {
+        if signature == "DSDT" && revision < 2 {
+            self.int_mask = u32::MAX.into();
+        }
+        let mut s = Stream::new(data);
+        self.exec_scope(
+            &mut s,
+            data.len(),
+            &mut Frame::new(ROOT.into()),
+            ROOT.into(),
+        )
+        .map(|_| ())
+    }
+
+    pub fn evaluate(&mut self, path: &str, args: Vec<Value>) -> Result<Value, AMLError> {
+        let path = name::parse_path(path)?;
+        match self.namespace.get(&path) {
+            None => Err(AMLError::NotFound(path)),
+            Some(Object::Method(_)) => self.invoke(&path, args),
+            Some(_) => self.read_named(&path),
+        }
+    }
+
+    // Evaluates an optional object below a device, not finding it is not an error.
+    pub fn evaluate_child(&mut self, device: &str, child: &str) -> Result<Option<Value>, AMLError> {
+        match self.evaluate(&format!("{device}.{child}"), Vec::new()) {
+            Err(AMLError::NotFound(_)) => Ok(None),
+            v => v.map(Some),
+        }
+    }
+
+    fn evaluate_integer(&mut self, device: &str, child: &str) -> Result<u64, AMLError> {
+        self.evaluate_child(device, child)?
+            .ok_or_else(|| AMLError::NotFound(format!("{device}.{child}")))?
+            .as_integer()
+    }
+
+    pub fn devices(&self) -> Vec<String> {
+        self.namespace
+            .iter()
+            .filter(|(_, v)| matches!(v, Object::Device))
+            .map(|(k, _)| k.clone())
+            .collect()
+    }
+
+    // Devices without `_STA` are present and functioning.
+    pub fn status(&mut self, device: &str) -> u64 {
+        self.evaluate_integer(device, "_STA").unwrap_or(0xF)
+    }
+
+    pub fn hardware_id(&mut self, device: &str) -> Option<String> {
+        self.evaluate_child(device, "_HID")
+            .ok()
+            .flatten()
+            .as_ref()
+            .and_then(device_id)
+    }
+
+    pub fn compatible_ids(&mut self, device: &str) -> Vec<String> {
+        match self.evaluate_child(device, "_CID") {
+            Ok(Some(Value::Package(v))) => v.iter().filter_map(device_id).collect(),
+            Ok(Some(v)) => device_id(&v).into_iter().collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    pub fn is_root_bridge(&mut self, device: &str) -> bool {
+        let ids = ["PNP0A03", "PNP0A08"];
+        self.hardware_id(device)
+            .into_iter()
+            .chain(self.compatible_ids(device))
+            .any(|v| ids.contains(&v.as_str()))
+    }
+
+    pub fn resources(&mut self, device: &str) -> Result<Vec<Resource>, AMLError> {
+        match self.evaluate_child(device, "_CRS")? {
+            None => Ok(Vec::new()),
+            Some(Value::Buffer(v)) => resource::parse(&v),
+            Some(_) => Err(AMLError::InvalidType),
+        }
+    }
+
+    // Entries either name a GSI directly, or a link device whose current resources hold the interrupt.
+    pub fn pci_routes(&mut self, bridge: &str) -> Result<Vec<PCIRoute>, AMLError> {
+        let Some(Value::Package(entries)) = self.evaluate_child(bridge, "_PRT")? else {
+            return Ok(Vec::new());
+        };
+
+        let mut routes = Vec::new();
+        for entry in entries {
+            let Value::Package(entry) = entry else {
+                return Err(AMLError::InvalidType);
+            };
+            let [addr, pin, source, index] = entry.as_slice() else {
+                return Err(AMLError::InvalidArgument);
+            };
+            let (slot, pin, index) = (
+                (addr.as_integer()? >> 16) as u8,
+                pin.as_integer()? as u8,
+                index.as_integer()?,
+            );
+            let link = match source {
+                Value::Reference(v) => Some(v.clone()),
+                Value::String(v) => Some(name::parse_path(v)?),
+                _ => None,
+            };
+            let Some(link) = link else {
+                routes.push(PCIRoute {
+                    slot,
+                    pin,
+                    line: index as u32,
+                    isa: false,
+                    level_triggered: true,
+                    active_low: true,
+                });
+                continue;
+            };
+
+            // The index picks the interrupt descriptor of the link device, whose current line is its first.
+            let irq = self
+                .resources(&link)?
+                .into_iter()
+                .filter_map(|v| match v {
+                    Resource::Irq {
+                        lines,
+                        isa,
+                        level_triggered,
+                        active_low,
+                    } => Some((lines, isa, level_triggered, active_low)),
+                    _ => None,
+                })
+                .nth(index as usize)
+                .and_then(|(lines, isa, level_triggered, active_low)| {
+                    lines
+                        .first()
+                        .map(|&v| (v, isa, level_triggered, active_low))
+                });
+            let Some((line, isa, level_triggered, active_low)) = irq else {
+                warn!("AML: {link} has no interrupt for slot {slot} pin {pin}");
+                continue;
+            };
+            routes.push(PCIRoute {
+                slot,
+                pin,
+                line,
+                isa,
+                level_triggered,
+                active_low,
+            });
+        }
+        Ok(routes)
+    }
+
+    // Tells the firmware interrupts go through the I/O APIC, then runs the initialisation methods of present devices.
+    pub fn initialise(&mut self) {
+        match self.evaluate("\\_PIC", vec![Value::Integer(1)]) {
+            Ok(_) | Err(AMLError::NotFound(_)) => {}
+            Err(e) => warn!("AML: \\_PIC failed: {e:?}"),
+        }
+        if let Err(e) = self.evaluate_child("\\_SB_", "_INI") {
+            warn!("AML: \\_SB._INI failed: {e:?}");
+        }
+        for device in self.devices() {
+            if self.status(&device) & 1 == 0 {
+                continue;
+            }
+            if let Err(e) = self.evaluate_child(&device, "_INI") {
+                warn!("AML: {device}._INI failed: {e:?}");
+            }
+        }
+    }
+
+    // Runs `_PTS` and returns the SLP_TYPa and SLP_TYPb values for the sleep state.
+    pub fn prepare_sleep(&mut self, state: u8) -> Result<(u16, u16), AMLError> {
+        let Value::Package(types) = self.evaluate(&format!("\\_S{state}"), Vec::new())? else {
+            return Err(AMLError::InvalidType);
+        };
+        let slp_typ = |i: usize| -> Result<u16, AMLError> {
+            Ok(types.get(i).map_or(Ok(0), Value::as_integer)? as u16)
+        };
+        let (a, b) = (slp_typ(0)?, slp_typ(1)?);
+        match self.evaluate("\\_PTS", vec![Value::Integer(state.into())]) {
+            Ok(_) | Err(AMLError::NotFound(_)) => Ok((a, b)),
+            Err(e) => Err(e),
+        }
+    }
+}
@@ -0,0 +1,71 @@
+// Copyright (c) ChefKiss Inc 2021-2023. Licensed under the Thou Shalt Not Profit License version 1.5. See LICENSE for details.
+
+use alloc::{format, string::String, vec::Vec};
+
+use crate::AMLError;
+
+pub const ROOT: &str = "\\";
+
+#[derive(Debug, Default, Clone)]
+pub struct AMLName {
+    pub root: bool,
+    pub up: usize,
+    pub segs: Vec<[u8; 4]>,
+}
+
+impl AMLName {
+    // Only bare single segment names are looked up through the enclosing scopes.
+    pub const fn is_searchable(&self) -> bool {
+        !self.root && self.up == 0 && self.segs.len() == 1
+    }
+
+    pub fn resolve(&self, scope: &str) -> Result<String, AMLError> {
+        let mut path = if self.root { ROOT } else { scope };
+        for _ in 0..self.up {
+            path = parent(path).ok_or(AMLError::InvalidName)?;
+        }
+        Ok(self
+            .segs
+            .iter()
+            .fold(path.into(), |path, seg| child(&path, seg)))
+    }
+}
+
+pub fn child(path: &str, seg: &[u8; 4]) -> String {
+    let seg = core::str::from_utf8(seg).unwrap();
+    if path == ROOT {
+        format!("{ROOT}{seg}")
+    } else {
+        format!("{path}.{seg}")
+    }
+}
+
+pub fn parent(path: &str) -> Option<&str> {
+    if path == ROOT {
+        return None;
+    }
+    Some(path.rfind('.').map_or(ROOT, |i| &path[..i]))
+}
+
+pub fn last_seg(path: &str) -> &str {
+    path.rsplit(['.', '\\']).next().unwrap()
+}
+
+// Accepts paths the way ASL writes them, e.g. `\_SB.PCI0`, and pads the segments to their encoded form.
+pub fn parse_path(path: &str) -> Result<String, AMLError> {
+    let path = path.strip_prefix('\\').ok_or(AMLError::InvalidName)?;
+    path.split('.')
+        .filter(|v| !v.is_empty())
+        .try_fold(String::from(ROOT), |path, seg| {
+            let mut padded = [b'_'; 4];
+            if seg.len() > 4 || !seg.bytes().all(is_name_char) {
+                return Err(AMLError::InvalidName);
+            }
+            padded[..seg.len()].copy_from_slice(seg.as_bytes());
+            Ok(child(&path, &padded))
+        })
+}
+
+pub const fn is_name_char(c: u8) -> bool {
+    matches!(c, b'A'..=b'Z' | b'0'..=b'9' | b'_')
+}
@@ -0,0 +1,179 @@
+// Copyright (c) ChefKiss Inc 2021-2023. Licensed under the Thou Shalt Not Profit License version 1.5. See LICENSE for details.
+
+use alloc::{string::String, vec::Vec};
+
+use super::{
+    name,
+    value::{FieldKind, FieldUnit, Object, OpRegion, Value},
+    AMLError, Interpreter,
+};
+
+const SYSTEM_MEMORY: u8 = 0;
+const SYSTEM_IO: u8 = 1;
+const PCI_CONFIG: u8 = 2;
+
+impl Interpreter {
+    fn field(&self, path: &str) -> Result<FieldUnit, AMLError> {
+        match self.namespace.get(path) {
+            Some(Object::Field(v)) => Ok(v.clone()),
+            _ => Err(AMLError::NotFound(path.into())),
+        }
+    }
+
+    pub(super) fn read_field(&mut self, field: &FieldUnit) -> Result<Value, AMLError> {
+        let width = field.access_bytes();
+        let mut bits = Vec::new();
+        for unit in
+            field.bit_offset / (width * 8)..(field.bit_offset + field.bit_len).div_ceil(width * 8)
+        {
+            let raw = self.read_unit(field, unit * width, width)?.to_le_bytes();
+            bits.extend_from_slice(&raw[..width as usize]);
+        }
+        let shift = field.bit_offset % (width * 8);
+        Ok(super::value::from_bits(
+            super::value::read_bits(&bits, shift, field.bit_len),
+            field.bit_len,
+        ))
+    }
+
+    pub(super) fn write_field(&mut self, field: &FieldUnit, v: &Value) -> Result<(), AMLError> {
+        let width = field.access_bytes();
+        let data = v.as_buffer()?;
+        let (start, end) = (field.bit_offset, field.bit_offset + field.bit_len);
+        for unit in start / (width * 8)..end.div_ceil(width * 8) {
+            let (unit_start, unit_end) = (unit * width * 8, (unit + 1) * width * 8);
+            let (lo, hi) = (start.max(unit_start), end.min(unit_end));
+            let raw = if lo == unit_start && hi == unit_end {
+                0
+            } else {
+                match field.update_rule() {
+                    0 => self.read_unit(field, unit * width, width)?,
+                    1 => !0,
+                    _ => 0,
+                }
+            };
+            let mut raw = raw.to_le_bytes();
+            let bits = super::value::read_bits(&data, lo - start, hi - lo);
+            super::value::write_bits(&mut raw, lo - unit_start, hi - lo, &bits);
+            self.write_unit(field, unit * width, width, u64::from_le_bytes(raw))?;
+        }
+        Ok(())
+    }
+
+    fn read_unit(&mut self, field: &FieldUnit, offset: u64, width: u64) -> Result<u64, AMLError> {
+        match &field.kind {
+            FieldKind::Region(region) => self.region_access(region, offset, width, None),
+            FieldKind::Index { index, data } => {
+                let (index, data) = (self.field(index)?, self.field(data)?);
+                self.write_field(&index, &Value::Integer(offset))?;
+                self.read_field(&data)?.as_integer()
+            }
+            FieldKind::Bank {
+                region,
+                bank,
+                value,
+            } => {
+                let bank = self.field(bank)?;
+                self.write_field(&bank, &Value::Integer(*value))?;
+                self.region_access(region, offset, width, None)
+            }
+        }
+    }
+
+    fn write_unit(
+        &mut self,
+        field: &FieldUnit,
+        offset: u64,
+        width: u64,
+        v: u64,
+    ) -> Result<(), AMLError> {
+        match &field.kind {
+            FieldKind::Region(region) => self
+                .region_access(region, offset, width, Some(v))
+                .map(|_| ()),
+            FieldKind::Index { index, data } => {
+                let (index, data) = (self.field(index)?, self.field(data)?);
+                self.write_field(&index, &Value::Integer(offset))?;
+                self.write_field(&data, &Value::Integer(v))
+            }
+            FieldKind::Bank {
+                region,
+                bank,
+                value,
+            } => {
+                let bank = self.field(bank)?;
+                self.write_field(&bank, &Value::Integer(*value))?;
+                self.region_access(region, offset, width, Some(v))
+                    .map(|_| ())
+            }
+        }
+    }
+
+    // Writes when `v` is set, otherwise reads.
+    fn region_access(
+        &mut self,
+        path: &str,
+        offset: u64,
+        width: u64,
+        v: Option<u64>,
+    ) -> Result<u64, AMLError> {
+        let Some(&Object::OpRegion(OpRegion {
+            space,
+            offset: base,
+            len,
+        })) = self.namespace.get(path)
+        else {
+            return Err(AMLError::NotFound(path.into()));
+        };
+        if !offset.checked_add(width).is_some_and(|end| end <= len) {
+            return Err(AMLError::InvalidArgument);
+        }
+
+        let addr = base.checked_add(offset).ok_or(AMLError::InvalidArgument)?;
+        Ok(match space {
+            SYSTEM_MEMORY => self.handler.memory_access(addr, width, v),
+            SYSTEM_IO => {
+                let port = u16::try_from(addr).map_err(|_| AMLError::InvalidArgument)?;
+                self.handler.port_access(port, width, v)
+            }
+            PCI_CONFIG => {
+                let (bus, slot, func) = self.pci_address(path)?;
+                let offset = u8::try_from(addr).map_err(|_| AMLError::InvalidArgument)?;
+                self.handler.pci_access(bus, slot, func, offset, width, v)
+            }
+            _ => {
+                warn!("AML: Region {path} in unsupported address space {space:#X}");
+                0
+            }
+        })
+    }
+
+    // Regions in PCI configuration space belong to the device declaring them, its bus is found by walking down from
+    // the root bridge through the bridges in between.
+    fn pci_address(&mut self, region: &str) -> Result<(u8, u8, u8), AMLError> {
+        let mut devices = Vec::new();
+        let mut path = name::parent(region);
+        while let Some(v) = path {
+            if matches!(self.namespace.get(v), Some(Object::Device)) {
+                devices.push(String::from(v));
+                if self.is_root_bridge(v) {
+                    break;
+                }
+            }
+            path = name::parent(v);
+        }
+
+        let root = devices.pop().ok_or(AMLError::InvalidArgument)?;
+        let mut bus = self.evaluate_integer(&root, "_BBN").unwrap_or(0) as u8;
+        let adr = self.evaluate_integer(&root, "_ADR").unwrap_or(0);
+        let mut dev = ((adr >> 16) as u8, adr as u8);
+        for (i, v) in devices.iter().rev().enumerate() {
+            if i != 0 {
+                bus = self.handler.pci_access(bus, dev.0, dev.1, 0x19, 1, None) as u8;
+            }
+            let adr = self.evaluate_integer(v, "_ADR")?;
+            dev = ((adr >> 16) as u8, adr as u8);
+        }
+        Ok((bus, dev.0, dev.1))
+    }
+}
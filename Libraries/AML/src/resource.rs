@@ -0,0 +1,120 @@
+// Copyright (c) ChefKiss Inc 2021-2023. Licensed under the Thou Shalt Not Profit License version 1.5. See LICENSE for details.
+
+use alloc::vec::Vec;
+
+use crate::{le_integer, AMLError};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Resource {
+    // ISA IRQ numbers from the small descriptor, GSIs from the extended one.
+    Irq {
+        lines: Vec<u32>,
+        isa: bool,
+        level_triggered: bool,
+        active_low: bool,
+    },
+    Io {
+        base: u64,
+        len: u64,
+    },
+    Memory {
+        base: u64,
+        len: u64,
+    },
+}
+
+fn field(data: &[u8], offset: usize, size: usize) -> Result<u64, AMLError> {
+    data.get(offset..offset + size)
+        .map(le_integer)
+        .ok_or(AMLError::UnexpectedEnd)
+}
+
+// Word, DWord, QWord and Extended address space descriptors share the layout, only the field size differs.
+fn address_space(data: &[u8], size: usize, offset: usize) -> Result<Option<Resource>, AMLError> {
+    let base = field(data, offset + size, size)?;
+    let len = field(data, offset + size * 4, size)?;
+    if len == 0 {
+        return Ok(None);
+    }
+    Ok(match data.first() {
+        Some(0) => Some(Resource::Memory { base, len }),
+        Some(1) => Some(Resource::Io { base, len }),
+        _ => None,
+    })
+}
+
+pub fn parse(buf: &[u8]) -> Result<Vec<Resource>, AMLError> {
+    let mut resources = Vec::new();
+    let mut pos = 0;
+    while let Some(&tag) = buf.get(pos) {
+        if tag & 0x80 == 0 {
+            let len = usize::from(tag & 7);
+            let data = buf
+                .get(pos + 1..pos + 1 + len)
+                .ok_or(AMLError::UnexpectedEnd)?;
+            pos += 1 + len;
+            match tag >> 3 {
+                0x04 => {
+                    let mask = field(data, 0, 2)? as u16;
+                    let flags = data.get(2).copied().unwrap_or(1);
+                    resources.push(Resource::Irq {
+                        lines: (0..16).filter(|i| mask & (1 << i) != 0).collect(),
+                        isa: true,
+                        level_triggered: flags & 1 == 0,
+                        active_low: flags & 8 != 0,
+                    });
+                }
+                0x08 => resources.push(Resource::Io {
+                    base: field(data, 1, 2)?,
+                    len: field(data, 6, 1)?,
+                }),
+                0x09 => resources.push(Resource::Io {
+                    base: field(data, 0, 2)? & 0x3FF,
+                    len: field(data, 2, 1)?,
+                }),
+                0x0F => break,
+                _ => {}
+            }
+            continue;
+        }
+
+        let len = field(buf, pos + 1, 2)? as usize;
+        let data = buf
+            .get(pos + 3..pos + 3 + len)
+            .ok_or(AMLError::UnexpectedEnd)?;
+        pos += 3 + len;
+        let resource = match tag & 0x7F {
+            0x01 => Some(Resource::Memory {
+                base: field(data, 1, 2)? << 8,
+                len: field(data, 7, 2)? << 8,
+            }),
+            0x05 => Some(Resource::Memory {
+                base: field(data, 1, 4)?,
+                len: field(data, 13, 4)?,
+            }),
+            0x06 => Some(Resource::Memory {
+                base: field(data, 1, 4)?,
+                len: field(data, 5, 4)?,
+            }),
+            0x07 => address_space(data, 4, 3)?,
+            0x08 => address_space(data, 2, 3)?,
+            0x09 => {
+                let flags = *data.first().ok_or(AMLError::UnexpectedEnd)?;
+                let count = usize::from(*data.get(1).ok_or(AMLError::UnexpectedEnd)?);
+                Some(Resource::Irq {
+                    lines: (0..count)
+                        .map(|i| field(data, 2 + i * 4, 4).map(|v| v as u32))
+                        .collect::<Result<_, _>>()?,
+                    isa: false,
+                    level_triggered: flags & 2 == 0,
+                    active_low: flags & 4 != 0,
+                })
+            }
+            0x0A => address_space(data, 8, 3)?,
+            0x0B => address_space(data, 8, 5)?,
+            _ => None,
+        };
+        resources.extend(resource);
+    }
+    Ok(resources)
+}
@@ -0,0 +1,224 @@
+// Copyright (c) ChefKiss Inc 2021-2023. Licensed under the Thou Shalt Not Profit License version 1.5. See LICENSE for details.
+
+use alloc::{string::String, vec::Vec};
+
+use super::{le_integer, AMLError};
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub enum Value {
+    #[default]
+    Uninitialized,
+    Integer(u64),
+    String(String),
+    Buffer(Vec<u8>),
+    Package(Vec<Self>),
+    Reference(String),
+}
+
+impl Value {
+    pub fn as_integer(&self) -> Result<u64, AMLError> {
+        match self {
+            Self::Integer(v) => Ok(*v),
+            Self::Buffer(v) => Ok(le_integer(v)),
+            // Implicit conversions read strings as hexadecimal.
+            Self::String(v) => Ok(parse_integer(v, 16)),
+            _ => Err(AMLError::InvalidType),
+        }
+    }
+
+    pub fn as_buffer(&self) -> Result<Vec<u8>, AMLError> {
+        match self {
+            Self::Integer(v) => Ok(v.to_le_bytes().to_vec()),
+            Self::String(v) => Ok(v.bytes().chain([0]).collect()),
+            Self::Buffer(v) => Ok(v.clone()),
+            _ => Err(AMLError::InvalidType),
+        }
+    }
+
+    pub fn as_string(&self) -> Result<String, AMLError> {
+        match self {
+            Self::Integer(v) => Ok(format!("{v:016X}")),
+            Self::String(v) => Ok(v.clone()),
+            Self::Buffer(v) => Ok(v
+                .iter()
+                .map(|b| format!("{b:02X}"))
+                .collect::<Vec<_>>()
+                .join(" ")),
+            _ => Err(AMLError::InvalidType),
+        }
+    }
+
+    pub const fn type_id(&self) -> u64 {
+        match self {
+            Self::Uninitialized | Self::Reference(_) => 0,
+            Self::Integer(_) => 1,
+            Self::String(_) => 2,
+            Self::Buffer(_) => 3,
+            Self::Package(_) => 4,
+        }
+    }
+}
+
+pub fn parse_integer(s: &str, radix: u32) -> u64 {
+    let (s, radix) = s
+        .strip_prefix("0x")
+        .or_else(|| s.strip_prefix("0X"))
+        .map_or((s, radix), |s| (s, 16));
+    s.chars()
+        .map_while(|c| c.to_digit(radix))
+        .fold(0u64, |acc, d| {
+            acc.wrapping_mul(radix.into()).wrapping_add(d.into())
+        })
+}
+
+pub fn read_bits(buf: &[u8], offset: u64, len: u64) -> Vec<u8> {
+    let mut out = vec![0u8; len.div_ceil(8) as usize];
+    for i in 0..len {
+        let bit = offset + i;
+        if buf
+            .get((bit / 8) as usize)
+            .is_some_and(|b| b >> (bit % 8) & 1 != 0)
+        {
+            out[(i / 8) as usize] |= 1 << (i % 8);
+        }
+    }
+    out
+}
+
+pub fn write_bits(buf: &mut [u8], offset: u64, len: u64, data: &[u8]) {
+    for i in 0..len {
+        let bit = offset + i;
+        let set = data
+            .get((i / 8) as usize)
+            .is_some_and(|b| b >> (i % 8) & 1 != 0);
+        if let Some(b) = buf.get_mut((bit / 8) as usize) {
+            if set {
+                *b |= 1 << (bit % 8);
+            } else {
+                *b &= !(1 << (bit % 8));
+            }
+        }
+    }
+}
+
+// Fields of up to 64 bits read back as integers, wider ones as buffers.
+pub fn from_bits(bits: Vec<u8>, len: u64) -> Value {
+    if len <= 64 {
+        Value::Integer(le_integer(&bits))
+    } else {
+        Value::Buffer(bits)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum MethodBody {
+    Aml(&'static [u8]),
+    Native(fn(&[Value]) -> Value),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Method {
+    pub body: MethodBody,
+    pub arg_count: u8,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct OpRegion {
+    pub space: u8,
+    pub offset: u64,
+    pub len: u64,
+}
+
+#[derive(Debug, Clone)]
+pub enum FieldKind {
+    Region(String),
+    Index {
+        index: String,
+        data: String,
+    },
+    Bank {
+        region: String,
+        bank: String,
+        value: u64,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub struct FieldUnit {
+    pub kind: FieldKind,
+    pub bit_offset: u64,
+    pub bit_len: u64,
+    pub flags: u8,
+}
+
+impl FieldUnit {
+    pub fn access_bytes(&self) -> u64 {
+        match self.flags & 0xF {
+            1 | 5 => 1,
+            2 => 2,
+            3 => 4,
+            4 => 8,
+            // AnyAcc, use the smallest naturally aligned access that covers the field.
+            _ => [1, 2, 4, 8]
+                .into_iter()
+                .find(|w| {
+                    self.bit_offset / (w * 8) == (self.bit_offset + self.bit_len - 1) / (w * 8)
+                })
+                .unwrap_or(1),
+        }
+    }
+
+    pub const fn update_rule(&self) -> u8 {
+        (self.flags >> 5) & 3
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum BufferSource {
+    Named(String),
+    // Fields over locals and arguments work on a copy, writes to them are only visible through the field.
+    Owned(Vec<u8>),
+}
+
+#[derive(Debug, Clone)]
+pub struct BufferField {
+    pub source: BufferSource,
+    pub bit_offset: u64,
+    pub bit_len: u64,
+}
+
+#[derive(Debug, Clone)]
+pub enum Object {
+    Value(Value),
+    Scope,
+    Device,
+    Processor,
+    PowerResource,
+    ThermalZone,
+    Method(Method),
+    OpRegion(OpRegion),
+    Field(FieldUnit),
+    BufferField(BufferField),
+    Mutex,
+    Event,
+    Alias(String),
+}
+
+impl Object {
+    pub const fn type_id(&self) -> u64 {
+        match self {
+            Self::Value(v) => v.type_id(),
+            Self::Scope | Self::Alias(_) => 0,
+            Self::Field(_) => 5,
+            Self::Device => 6,
+            Self::Event => 7,
+            Self::Method(_) => 8,
+            Self::Mutex => 9,
+            Self::OpRegion(_) => 10,
+            Self::PowerResource => 11,
+            Self::Processor => 12,
+            Self::ThermalZone => 13,
+            Self::BufferField(_) => 14,
+        }
+    }
+}
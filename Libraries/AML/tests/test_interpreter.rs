@@ -0,0 +1,188 @@
+// Copyright (c) ChefKiss Inc 2021-2023. Licensed under the Thou Shalt Not Profit License version 1.5. See LICENSE for details.
+
+#![deny(warnings, clippy::cargo, clippy::nursery, unused_extern_crates)]
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use aml::{value::Value, Handler, Interpreter};
+
+#[derive(Default, Clone)]
+struct TestHandler {
+    memory: Arc<Mutex<HashMap<u64, u8>>>,
+}
+
+impl Handler for TestHandler {
+    fn memory_access(&mut self, addr: u64, width: u64, v: Option<u64>) -> u64 {
+        let mut memory = self.memory.lock().unwrap();
+        let Some(v) = v else {
+            return (0..width).rev().fold(0, |acc, i| {
+                (acc << 8) | u64::from(memory.get(&(addr + i)).copied().unwrap_or(0))
+            });
+        };
+        for i in 0..width {
+            memory.insert(addr + i, (v >> (i * 8)) as u8);
+        }
+        0
+    }
+
+    fn port_access(&mut self, _: u16, _: u64, _: Option<u64>) -> u64 {
+        0
+    }
+
+    fn pci_access(&mut self, _: u8, _: u8, _: u8, _: u8, _: u64, _: Option<u64>) -> u64 {
+        0
+    }
+
+    fn sleep(&mut self, _: u64) {}
+
+    fn stall(&mut self, _: u64) {}
+
+    fn time_ns(&self) -> u64 {
+        0
+    }
+}
+
+fn load(handler: TestHandler, data: &'static [u8]) -> Interpreter {
+    let mut aml = Interpreter::new(Box::new(handler));
+    aml.load_table("SSDT", 2, data).unwrap();
+    aml
+}
+
+#[test]
+fn test_package() {
+    // Name (PKG, Package () { One, "AB", Package () { 10 }, FOO })
+    let mut aml = load(
+        TestHandler::default(),
+        &[
+            0x08, b'P', b'K', b'G', b'_', 0x12, 0x10, 0x04, // Name, Package
+            0x01, // One
+            0x0D, b'A', b'B', 0x00, // "AB"
+            0x12, 0x04, 0x01, 0x0A, 0x0A, // Package () { 10 }
+            b'F', b'O', b'O', b'_', // Not declared yet
+        ],
+    );
+    assert_eq!(
+        aml.evaluate("\\PKG_", Vec::new()).unwrap(),
+        Value::Package(vec![
+            Value::Integer(1),
+            Value::String("AB".into()),
+            Value::Package(vec![Value::Integer(10)]),
+            Value::Reference("\\FOO_".into()),
+        ])
+    );
+}
+
+#[test]
+fn test_methods() {
+    let mut aml = load(
+        TestHandler::default(),
+        &[
+            // Method (ADD1, 2) { Return (Arg0 + Arg1) }
+            0x14, 0x0B, b'A', b'D', b'D', b'1', 0x02, //
+            0xA4, 0x72, 0x68, 0x69, 0x00, //
+            // Method (LOOP, 1) { Local0 = 0; While (Arg0) { Local0 += 2; Arg0-- }; Return (Local0) }
+            0x14, 0x15, b'L', b'O', b'O', b'P', 0x01, //
+            0x70, 0x00, 0x60, //
+            0xA2, 0x09, 0x68, 0x72, 0x60, 0x0A, 0x02, 0x60, 0x76, 0x68, //
+            0xA4, 0x60, //
+            // Method (CALL) { Return (ADD1 (LOOP (3), 1)) }
+            0x14, 0x12, b'C', b'A', b'L', b'L', 0x00, //
+            0xA4, b'A', b'D', b'D', b'1', b'L', b'O', b'O', b'P', 0x0A, 0x03, 0x01,
+        ],
+    );
+    assert_eq!(
+        aml.evaluate("\\ADD1", vec![Value::Integer(2), Value::Integer(3)])
+            .unwrap(),
+        Value::Integer(5)
+    );
+    assert_eq!(
+        aml.evaluate("\\LOOP", vec![Value::Integer(3)]).unwrap(),
+        Value::Integer(6)
+    );
+    assert_eq!(
+        aml.evaluate("\\CALL", Vec::new()).unwrap(),
+        Value::Integer(7)
+    );
+    assert_eq!(
+        aml.evaluate("\\_OSI", vec![Value::String("Windows 2009".into())])
+            .unwrap(),
+        Value::Integer(u64::MAX)
+    );
+}
+
+#[test]
+fn test_fields() {
+    let handler = TestHandler::default();
+    handler.memory.lock().unwrap().extend([
+        (0x1000, 0xAB),
+        (0x1001, 0xCD),
+        (0x1002, 0x34),
+        (0x1003, 0x12),
+    ]);
+    let mut aml = load(
+        handler.clone(),
+        &[
+            // OperationRegion (GNVS, SystemMemory, 0x1000, 0x10)
+            0x5B, 0x80, b'G', b'N', b'V', b'S', 0x00, 0x0B, 0x00, 0x10, 0x0A, 0x10, //
+            // Field (GNVS, AnyAcc, NoLock, Preserve) { FLD1, 8, , 4, FLD2, 4, FLD3, 16 }
+            0x5B, 0x81, 0x17, b'G', b'N', b'V', b'S', 0x00, //
+            b'F', b'L', b'D', b'1', 0x08, 0x00, 0x04, b'F', b'L', b'D', b'2', 0x04, //
+            b'F', b'L', b'D', b'3', 0x10, //
+            // Method (WRIT) { FLD2 = 5 }
+            0x14, 0x0D, b'W', b'R', b'I', b'T', 0x00, //
+            0x70, 0x0A, 0x05, b'F', b'L', b'D', b'2',
+        ],
+    );
+    assert_eq!(
+        aml.evaluate("\\FLD1", Vec::new()).unwrap(),
+        Value::Integer(0xAB)
+    );
+    assert_eq!(
+        aml.evaluate("\\FLD2", Vec::new()).unwrap(),
+        Value::Integer(0xC)
+    );
+    assert_eq!(
+        aml.evaluate("\\FLD3", Vec::new()).unwrap(),
+        Value::Integer(0x1234)
+    );
+
+    aml.evaluate("\\WRIT", Vec::new()).unwrap();
+    let memory = handler.memory.lock().unwrap().clone();
+    assert_eq!(memory[&0x1001], 0x5D);
+    assert_eq!(memory[&0x1000], 0xAB);
+}
+
+#[test]
+fn test_pci_routes() {
+    let mut aml = load(
+        TestHandler::default(),
+        &[
+            // Device (LNKA) { Name (_CRS, ResourceTemplate () { IRQ () { 5 } IRQ () { 10 } }) }
+            0x5B, 0x82, 0x18, b'L', b'N', b'K', b'A', //
+            0x08, b'_', b'C', b'R', b'S', 0x11, 0x0D, 0x0A, 0x0A, //
+            0x23, 0x20, 0x00, 0x18, 0x23, 0x00, 0x04, 0x18, 0x79, 0x00, //
+            // Device (PCI0) {
+            //     Name (_PRT, Package () {
+            //         Package () { 0x0002FFFF, 0, LNKA, 1 },
+            //         Package () { 0x0003FFFF, 1, 0, 16 },
+            //     })
+            // }
+            0x5B, 0x82, 0x27, b'P', b'C', b'I', b'0', //
+            0x08, b'_', b'P', b'R', b'T', 0x12, 0x1C, 0x02, //
+            0x12, 0x0D, 0x04, 0x0C, 0xFF, 0xFF, 0x02, 0x00, 0x00, b'L', b'N', b'K', b'A',
+            0x01, //
+            0x12, 0x0B, 0x04, 0x0C, 0xFF, 0xFF, 0x03, 0x00, 0x01, 0x00, 0x0A, 0x10,
+        ],
+    );
+    let routes = aml.pci_routes("\\PCI0").unwrap();
+    assert_eq!(routes.len(), 2);
+    // The source index picks the second IRQ descriptor of the link device.
+    assert_eq!((routes[0].slot, routes[0].pin), (2, 0));
+    assert_eq!((routes[0].line, routes[0].isa), (10, true));
+    assert!(routes[0].level_triggered && routes[0].active_low);
+    assert_eq!((routes[1].slot, routes[1].pin), (3, 1));
+    assert_eq!((routes[1].line, routes[1].isa), (16, false));
+}
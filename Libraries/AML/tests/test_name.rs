@@ -0,0 +1,69 @@
+// Copyright (c) ChefKiss Inc 2021-2023. Licensed under the Thou Shalt Not Profit License version 1.5. See LICENSE for details.
+
+#![deny(warnings, clippy::cargo, clippy::nursery, unused_extern_crates)]
+
+use aml::{
+    name::{self, AMLName, ROOT},
+    AMLError,
+};
+
+#[test]
+fn test_child_parent() {
+    assert_eq!(name::child(ROOT, b"_SB_"), "\\_SB_");
+    assert_eq!(name::child("\\_SB_", b"PCI0"), "\\_SB_.PCI0");
+    assert_eq!(name::parent("\\_SB_.PCI0"), Some("\\_SB_"));
+    assert_eq!(name::parent("\\_SB_"), Some(ROOT));
+    assert_eq!(name::parent(ROOT), None);
+}
+
+#[test]
+fn test_last_seg() {
+    assert_eq!(name::last_seg("\\_SB_.PCI0.LPC_"), "LPC_");
+    assert_eq!(name::last_seg("\\_SB_"), "_SB_");
+}
+
+#[test]
+fn test_parse_path() {
+    assert_eq!(name::parse_path("\\").unwrap(), ROOT);
+    assert_eq!(name::parse_path("\\_SB.PCI0").unwrap(), "\\_SB_.PCI0");
+    assert_eq!(name::parse_path("\\_S5").unwrap(), "\\_S5_");
+    assert!(matches!(
+        name::parse_path("_SB.PCI0"),
+        Err(AMLError::InvalidName)
+    ));
+    assert!(matches!(
+        name::parse_path("\\_SB.PCI00"),
+        Err(AMLError::InvalidName)
+    ));
+    assert!(matches!(
+        name::parse_path("\\_sb"),
+        Err(AMLError::InvalidName)
+    ));
+}
+
+#[test]
+fn test_resolve() {
+    let name = AMLName {
+        root: false,
+        up: 1,
+        segs: vec![*b"LNKA"],
+    };
+    assert!(!name.is_searchable());
+    assert_eq!(name.resolve("\\_SB_.PCI0").unwrap(), "\\_SB_.LNKA");
+    assert!(matches!(name.resolve(ROOT), Err(AMLError::InvalidName)));
+
+    let name = AMLName {
+        root: true,
+        up: 0,
+        segs: vec![*b"_SB_", *b"PCI0"],
+    };
+    assert_eq!(name.resolve("\\_GPE").unwrap(), "\\_SB_.PCI0");
+
+    let name = AMLName {
+        root: false,
+        up: 0,
+        segs: vec![*b"_CRS"],
+    };
+    assert!(name.is_searchable());
+    assert_eq!(name.resolve("\\_SB_.PCI0").unwrap(), "\\_SB_.PCI0._CRS");
+}
@@ -0,0 +1,93 @@
+// Copyright (c) ChefKiss Inc 2021-2023. Licensed under the Thou Shalt Not Profit License version 1.5. See LICENSE for details.
+
+#![deny(warnings, clippy::cargo, clippy::nursery, unused_extern_crates)]
+
+use aml::{
+    resource::{self, Resource},
+    AMLError,
+};
+
+#[test]
+fn test_small_descriptors() {
+    let buf = [
+        0x23, 0x00, 0x02, 0x18, // IRQ 9, level-triggered, active-low
+        0x22, 0x01, 0x00, // IRQ 0, flags default to edge-triggered, active-high
+        0x47, 0x01, 0xF8, 0x03, 0xF8, 0x03, 0x01, 0x08, // I/O 0x3F8-0x3FF
+        0x4B, 0x60, 0x00, 0x01, // Fixed I/O 0x60
+        0x79, 0x00, // End tag
+        0x4B, 0x64, 0x00, 0x01,
+    ];
+    assert_eq!(
+        resource::parse(&buf).unwrap(),
+        [
+            Resource::Irq {
+                lines: vec![9],
+                isa: true,
+                level_triggered: true,
+                active_low: true,
+            },
+            Resource::Irq {
+                lines: vec![0],
+                isa: true,
+                level_triggered: false,
+                active_low: false,
+            },
+            Resource::Io {
+                base: 0x3F8,
+                len: 8,
+            },
+            Resource::Io { base: 0x60, len: 1 },
+        ]
+    );
+}
+
+#[test]
+fn test_large_descriptors() {
+    let buf = [
+        // Fixed 32-bit memory
+        0x86, 0x09, 0x00, 0x01, 0x00, 0x00, 0xC0, 0xFE, 0x00, 0x10, 0x00, 0x00,
+        // Extended IRQ 16, level-triggered, active-low
+        0x89, 0x06, 0x00, 0x0D, 0x01, 0x10, 0x00, 0x00, 0x00,
+        // DWord memory 0xA0000-0xBFFFF
+        0x87, 0x17, 0x00, 0x00, 0x0C, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x0A, 0x00, 0xFF,
+        0xFF, 0x0B, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x00,
+        // Empty Word I/O range
+        0x88, 0x0D, 0x00, 0x01, 0x0C, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00,
+    ];
+    assert_eq!(
+        resource::parse(&buf).unwrap(),
+        [
+            Resource::Memory {
+                base: 0xFEC0_0000,
+                len: 0x1000,
+            },
+            Resource::Irq {
+                lines: vec![16],
+                isa: false,
+                level_triggered: true,
+                active_low: true,
+            },
+            Resource::Memory {
+                base: 0xA_0000,
+                len: 0x2_0000,
+            },
+        ]
+    );
+}
+
+#[test]
+fn test_truncated() {
+    assert!(matches!(
+        resource::parse(&[0x47, 0x01, 0xF8]),
+        Err(AMLError::UnexpectedEnd)
+    ));
+    assert!(matches!(
+        resource::parse(&[0x86, 0x09]),
+        Err(AMLError::UnexpectedEnd)
+    ));
+    assert!(matches!(
+        resource::parse(&[0x89, 0x06, 0x00, 0x0D, 0x02, 0x10, 0x00, 0x00, 0x00]),
+        Err(AMLError::UnexpectedEnd)
+    ));
+}
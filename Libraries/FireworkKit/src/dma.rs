@@ -0,0 +1,74 @@
+// Copyright (c) ChefKiss Inc 2021-2023. Licensed under the Thou Shalt Not Profit License version 1.5. See LICENSE for details.
+
+use num_enum::TryFromPrimitive;
+
+#[cfg(feature = "userspace")]
+use super::{syscall::SystemCall, Error};
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, TryFromPrimitive)]
+#[repr(u64)]
+pub enum CacheType {
+    #[default]
+    WriteBack,
+    WriteThrough,
+    WriteCombining,
+    Uncacheable,
+}
+
+#[cfg(feature = "userspace")]
+#[derive(Debug)]
+pub struct DmaBuffer {
+    virt: *mut u8,
+    phys: u64,
+    size: usize,
+}
+
+#[cfg(feature = "userspace")]
+impl DmaBuffer {
+    // The buffer ends at or below `max_phys`, where 0 means anywhere. An `align` of 0 means page-aligned.
+    pub unsafe fn new(
+        size: usize,
+        max_phys: u64,
+        align: u64,
+        cache: CacheType,
+    ) -> Result<Self, Error> {
+        let out = SystemCall::AllocDMA.invoke(size as _, max_phys, align, cache as _);
+        let virt = Error::from_status(out.rax)?;
+        Ok(Self {
+            virt: virt as *mut u8,
+            phys: out.rdi,
+            size,
+        })
+    }
+
+    #[inline]
+    #[must_use]
+    pub const fn phys(&self) -> u64 {
+        self.phys
+    }
+}
+
+#[cfg(feature = "userspace")]
+impl core::ops::Deref for DmaBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { core::slice::from_raw_parts(self.virt, self.size) }
+    }
+}
+
+#[cfg(feature = "userspace")]
+impl core::ops::DerefMut for DmaBuffer {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { core::slice::from_raw_parts_mut(self.virt, self.size) }
+    }
+}
+
+#[cfg(feature = "userspace")]
+impl Drop for DmaBuffer {
+    fn drop(&mut self) {
+        unsafe {
+            SystemCall::Free.invoke(self.virt as _, self.size as _, 0, 0);
+        }
+    }
+}
@@ -0,0 +1,62 @@
+// Copyright (c) ChefKiss Inc 2021-2023. Licensed under the Thou Shalt Not Profit License version 1.5. See LICENSE for details.
+
+use serde::{Deserialize, Serialize};
+
+#[cfg(feature = "userspace")]
+use super::{msg::Message, syscall::SystemCall, Error};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Endpoint(pub u64);
+
+impl Endpoint {
+    #[inline]
+    #[must_use]
+    pub const fn from_raw(handle: u64) -> Option<Self> {
+        if handle == 0 {
+            None
+        } else {
+            Some(Self(handle))
+        }
+    }
+}
+
+#[cfg(feature = "userspace")]
+impl Endpoint {
+    pub unsafe fn create(name: Option<&str>) -> Result<Self, Error> {
+        let name = name.unwrap_or_default();
+        let handle =
+            SystemCall::EndpointCreate.invoke(name.as_ptr() as u64, name.len() as u64, 0, 0);
+        Error::from_status(handle.rax).map(Self)
+    }
+
+    pub unsafe fn lookup(name: &str) -> Result<Self, Error> {
+        let handle =
+            SystemCall::EndpointLookup.invoke(name.as_ptr() as u64, name.len() as u64, 0, 0);
+        Error::from_status(handle.rax).map(Self)
+    }
+
+    pub unsafe fn send(self, data: &'static [u8], cap: Option<u64>) -> Result<(), Error> {
+        let status = SystemCall::MsgSend.invoke(
+            self.0,
+            data.as_ptr() as u64,
+            data.len() as u64,
+            cap.unwrap_or_default(),
+        );
+        Error::from_status(status.rax).map(|_| ())
+    }
+
+    pub unsafe fn call(self, data: &'static [u8], cap: Option<u64>) -> Result<Message, Error> {
+        let out = SystemCall::MsgCall.invoke(
+            self.0,
+            data.as_ptr() as u64,
+            data.len() as u64,
+            cap.unwrap_or_default(),
+        );
+        Ok(Message {
+            id: Error::from_status(out.rax)?,
+            pid: out.rdi,
+            data: core::slice::from_raw_parts(out.rsi as *const u8, out.rdx as _),
+            cap: (out.rcx != 0).then_some(out.rcx),
+        })
+    }
+}
@@ -3,32 +3,50 @@
 #![no_std]
 #![deny(warnings, clippy::cargo, clippy::nursery, unused_extern_crates)]
 #![allow(clippy::missing_safety_doc)]
-#![cfg_attr(feature = "userspace", feature(alloc_error_handler))]
+#![cfg_attr(feature = "userspace", feature(alloc_error_handler, asm_const))]
 
 use alloc::{string::String, vec::Vec};
 
 use hashbrown::HashMap;
+use num_enum::TryFromPrimitive;
 
 extern crate alloc;
 #[cfg(feature = "userspace")]
 #[macro_use]
 extern crate log;
 
+pub mod dma;
+pub mod endpoint;
 pub mod msg;
 pub mod osdtentry;
 pub mod osvalue;
+pub mod shm;
 pub mod syscall;
 #[cfg(feature = "userspace")]
 pub mod userspace;
 
 use serde::{Deserialize, Serialize};
 
-pub const USER_VIRT_OFFSET: u64 = 0xC000_0000;
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RestartPolicy {
+    #[default]
+    Never,
+    OnCrash,
+    Always,
+}
 
 #[derive(Debug, Default, Clone, Serialize, Deserialize)]
 pub struct FKInfo {
     pub identifier: String,
     pub personalities: HashMap<String, HashMap<String, osvalue::OSValue>>,
+    #[serde(default)]
+    pub restart: RestartPolicy,
+    // Fixed ports as (base, count), for devices that aren't found through a bus driver.
+    #[serde(default)]
+    pub io_ports: Vec<(u16, u16)>,
+    // Allows system-wide operations, like powering the machine off.
+    #[serde(default)]
+    pub privileged: bool,
 }
 
 #[derive(Debug, Default, Clone, Serialize, Deserialize)]
@@ -40,6 +58,14 @@ impl FKCache {
     }
 }
 
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct ThreadControlBlock {
+    pub this: u64,
+    pub tid: u64,
+    pub tls: u64,
+}
+
 #[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
 pub enum TerminationReason {
     Unspecified,
@@ -49,4 +75,35 @@ pub enum TerminationReason {
     NotFound,
     AlreadyExists,
     InsufficientPermissions,
+    Fault { vector: u8, addr: u64 },
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, TryFromPrimitive)]
+#[repr(u64)]
+pub enum Error {
+    Unspecified = 1,
+    InvalidArgument,
+    InvalidData,
+    NotFound,
+    AlreadyExists,
+    InsufficientPermissions,
+    Unsupported,
+    OutOfMemory,
+}
+
+impl Error {
+    // Like on Linux, the top 4 KiB of the range are negated error codes, everything else is a value.
+    #[inline]
+    #[must_use]
+    pub const fn into_status(self) -> u64 {
+        (self as u64).wrapping_neg()
+    }
+
+    #[inline]
+    pub fn from_status(status: u64) -> Result<u64, Self> {
+        match status.wrapping_neg() {
+            v @ 1..=0xFFF => Err(Self::try_from(v).unwrap_or(Self::Unspecified)),
+            _ => Ok(status),
+        }
+    }
 }
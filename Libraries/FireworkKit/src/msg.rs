@@ -2,63 +2,66 @@
 
 use serde::{Deserialize, Serialize};
 
+use crate::TerminationReason;
+
 #[cfg(feature = "userspace")]
-use super::syscall::SystemCall;
+use super::{syscall::SystemCall, Error};
 
 #[derive(Debug, Clone)]
 pub struct Message {
     pub id: u64,
     pub pid: u64,
     pub data: &'static [u8],
+    pub cap: Option<u64>,
 }
 
+#[cfg(not(feature = "userspace"))]
 impl Message {
-    #[cfg(not(feature = "userspace"))]
-    #[inline]
-    #[must_use]
-    pub const fn new(id: u64, pid: u64, data: &'static [u8]) -> Self {
-        Self { id, pid, data }
-    }
-
-    #[cfg(feature = "userspace")]
     #[inline]
     #[must_use]
-    pub const fn new(pid: u64, data: &'static [u8]) -> Self {
-        Self { id: 0, pid, data }
+    pub const fn new(id: u64, pid: u64, data: &'static [u8], cap: Option<u64>) -> Self {
+        Self { id, pid, data, cap }
     }
 }
 
 #[cfg(feature = "userspace")]
 impl Message {
+    unsafe fn recv_raw(call: SystemCall, timeout: u64) -> Option<Self> {
+        let out = call.invoke(timeout, 0, 0, 0);
+        if out.rax == 0 {
+            return None;
+        }
+        Some(Self {
+            id: out.rax,
+            pid: out.rdi,
+            data: core::slice::from_raw_parts(out.rsi as *const u8, out.rdx as _),
+            cap: (out.rcx != 0).then_some(out.rcx),
+        })
+    }
+
     #[must_use]
     pub unsafe fn recv() -> Self {
-        let (mut id, mut pid): (u64, u64);
-        let (mut ptr, mut len): (u64, u64);
-        core::arch::asm!(
-            "int 249",
-            in("rdi") SystemCall::MsgRecv as u64,
-            out("rax") id,
-            lateout("rdi") pid,
-            out("rsi") ptr,
-            out("rdx") len,
-            options(nostack),
-        );
-        Self {
-            id,
-            pid,
-            data: core::slice::from_raw_parts(ptr as *const u8, len as _),
-        }
+        Self::recv_raw(SystemCall::MsgRecv, 0).unwrap()
+    }
+
+    #[must_use]
+    pub unsafe fn recv_timeout(ticks: u64) -> Option<Self> {
+        Self::recv_raw(SystemCall::MsgRecvTimeout, ticks)
+    }
+
+    #[must_use]
+    pub unsafe fn try_recv() -> Option<Self> {
+        Self::recv_raw(SystemCall::MsgTryRecv, 0)
     }
 
-    pub unsafe fn send(self) {
-        core::arch::asm!(
-            "int 249",
-            in("rdi") SystemCall::MsgSend as u64,
-            in("rsi") self.pid,
-            in("rdx") self.data.as_ptr() as u64,
-            in("rcx") self.data.len() as u64,
-            options(nostack),
+    pub unsafe fn reply(&self, data: &'static [u8], cap: Option<u64>) -> Result<(), Error> {
+        let status = SystemCall::MsgReply.invoke(
+            self.id,
+            data.as_ptr() as u64,
+            data.len() as u64,
+            cap.unwrap_or_default(),
         );
+        Error::from_status(status.rax).map(|_| ())
     }
 }
 
@@ -69,12 +72,7 @@ impl Drop for Message {
             return;
         }
         unsafe {
-            core::arch::asm!(
-                "int 249",
-                in("rdi") SystemCall::MsgAck as u64,
-                in("rsi") self.id,
-                options(nostack),
-            );
+            SystemCall::MsgAck.invoke(self.id, 0, 0, 0);
         }
     }
 }
@@ -83,4 +81,10 @@ impl Drop for Message {
 #[repr(C)]
 pub enum KernelMessage {
     IRQFired(u8),
+    ProcessExited {
+        pid: u64,
+        reason: Option<TerminationReason>,
+    },
+    // Sent to privileged processes, which decide what to do about it.
+    PowerButton,
 }
@@ -11,11 +11,19 @@ use serde::{Deserialize, Serialize};
 
 use crate::osvalue::OSValue;
 #[cfg(feature = "userspace")]
-use crate::syscall::SystemCall;
+use crate::{syscall::SystemCall, Error};
 
 pub const OSDTENTRY_NAME_KEY: &str = "_Name";
 pub const FKEXT_MATCH_KEY: &str = "_FKExtMatch";
 pub const FKEXT_PROC_KEY: &str = "_FKExtProc";
+pub const FKEXT_RESTARTS_KEY: &str = "_FKExtRestarts";
+// Keys starting with an underscore can only be set by the extension owning the entry.
+pub const MMIO_RANGES_KEY: &str = "_MMIORanges";
+pub const PORT_RANGES_KEY: &str = "_PortRanges";
+// Tuples of interrupt line and `IRQMode`.
+pub const INTERRUPTS_KEY: &str = "_Interrupts";
+// Dictionaries with the `Slot`, `Pin`, `GSI` and `Mode` of each PCI interrupt routed by a root bridge.
+pub const PCI_ROUTES_KEY: &str = "_PRT";
 
 #[derive(Debug, Default, Clone, Copy, Serialize, Deserialize)]
 #[repr(transparent)]
@@ -36,75 +44,58 @@ pub struct OSDTEntryProp(pub String, pub OSValue);
 
 #[cfg(feature = "userspace")]
 impl OSDTEntry {
-    fn get_info(&self, ty: OSDTEntryInfo, k: Option<&str>) -> Vec<u8> {
-        let (mut ptr, mut len): (u64, u64);
+    fn get_info(&self, ty: OSDTEntryInfo, k: Option<&str>) -> Result<Vec<u8>, Error> {
         unsafe {
-            core::arch::asm!(
-                "int 249",
-                in("rdi") SystemCall::GetOSDTEntryInfo as u64,
-                in("rsi") self.0,
-                in("rdx") ty as u64,
-                in("rcx") k.map_or(0, |s| s.as_ptr() as u64),
-                in("r8") k.map_or(0, |s| s.len() as u64),
-                out("rax") ptr,
-                lateout("rdi") len,
-                options(nostack),
+            let out = SystemCall::GetOSDTEntryInfo.invoke(
+                self.0,
+                ty as u64,
+                k.map_or(0, |s| s.as_ptr() as u64),
+                k.map_or(0, |s| s.len() as u64),
             );
-            Vec::from_raw_parts(ptr as *mut u8, len as _, len as _)
+            let ptr = Error::from_status(out.rax)?;
+            Ok(Vec::from_raw_parts(
+                ptr as *mut u8,
+                out.rdi as _,
+                out.rdi as _,
+            ))
         }
     }
 
-    #[must_use]
-    pub fn new_child(&self, name: Option<&str>) -> Self {
-        let mut id: u64;
-        unsafe {
-            core::arch::asm!(
-                "int 249",
-                in("rdi") SystemCall::NewOSDTEntry as u64,
-                in("rsi") self.0,
-                out("rax") id,
-                options(nostack),
-            );
-        }
-        let ret: Self = id.into();
+    pub fn new_child(&self, name: Option<&str>) -> Result<Self, Error> {
+        let id = unsafe { SystemCall::NewOSDTEntry.invoke(self.0, 0, 0, 0) };
+        let ret: Self = Error::from_status(id.rax)?.into();
         if let Some(name) = name {
-            ret.set_property(OSDTENTRY_NAME_KEY, name.into());
+            ret.set_property(OSDTENTRY_NAME_KEY, name.into())?;
         }
-        ret
+        Ok(ret)
     }
 
-    #[must_use]
-    pub fn parent(&self) -> Option<Self> {
-        postcard::from_bytes(&self.get_info(OSDTEntryInfo::Parent, None)).unwrap()
+    pub fn parent(&self) -> Result<Option<Self>, Error> {
+        postcard::from_bytes(&self.get_info(OSDTEntryInfo::Parent, None)?)
+            .map_err(|_| Error::InvalidData)
     }
 
-    #[must_use]
-    pub fn children(&self) -> Vec<Self> {
-        postcard::from_bytes(&self.get_info(OSDTEntryInfo::Children, None)).unwrap()
+    pub fn children(&self) -> Result<Vec<Self>, Error> {
+        postcard::from_bytes(&self.get_info(OSDTEntryInfo::Children, None)?)
+            .map_err(|_| Error::InvalidData)
     }
 
-    #[must_use]
-    pub fn properties(&self) -> HashMap<String, OSValue> {
-        postcard::from_bytes(&self.get_info(OSDTEntryInfo::Properties, None)).unwrap()
+    pub fn properties(&self) -> Result<HashMap<String, OSValue>, Error> {
+        postcard::from_bytes(&self.get_info(OSDTEntryInfo::Properties, None)?)
+            .map_err(|_| Error::InvalidData)
     }
 
-    #[must_use]
-    pub fn get_property(&self, k: &str) -> Option<OSValue> {
-        postcard::from_bytes(&self.get_info(OSDTEntryInfo::Property, Some(k))).unwrap()
+    pub fn get_property(&self, k: &str) -> Result<Option<OSValue>, Error> {
+        postcard::from_bytes(&self.get_info(OSDTEntryInfo::Property, Some(k))?)
+            .map_err(|_| Error::InvalidData)
     }
 
-    pub fn set_property(&self, k: &str, v: OSValue) {
+    pub fn set_property(&self, k: &str, v: OSValue) -> Result<(), Error> {
         let req = postcard::to_allocvec(&OSDTEntryProp(k.to_owned(), v)).unwrap();
-        unsafe {
-            core::arch::asm!(
-                "int 249",
-                in("rdi") SystemCall::SetOSDTEntryProp as u64,
-                in("rsi") self.0,
-                in("rdx") req.as_ptr() as u64,
-                in("rcx") req.len() as u64,
-                options(nostack),
-            );
-        }
+        let status = unsafe {
+            SystemCall::SetOSDTEntryProp.invoke(self.0, req.as_ptr() as u64, req.len() as u64, 0)
+        };
+        Error::from_status(status.rax).map(|_| ())
     }
 }
 
@@ -0,0 +1,34 @@
+// Copyright (c) ChefKiss Inc 2021-2023. Licensed under the Thou Shalt Not Profit License version 1.5. See LICENSE for details.
+
+use serde::{Deserialize, Serialize};
+
+#[cfg(feature = "userspace")]
+use super::{syscall::SystemCall, Error};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SharedMemory(pub u64);
+
+#[cfg(feature = "userspace")]
+impl SharedMemory {
+    pub unsafe fn create(size: u64) -> Result<Self, Error> {
+        Error::from_status(SystemCall::ShmCreate.invoke(size, 0, 0, 0).rax).map(Self)
+    }
+
+    pub unsafe fn share(self, writable: bool) -> Result<Self, Error> {
+        let handle = SystemCall::ShmShare.invoke(self.0, u64::from(writable), 0, 0);
+        Error::from_status(handle.rax).map(Self)
+    }
+
+    pub unsafe fn map(self) -> Result<*mut [u8], Error> {
+        let out = SystemCall::ShmMap.invoke(self.0, 0, 0, 0);
+        let addr = Error::from_status(out.rax)?;
+        Ok(core::ptr::slice_from_raw_parts_mut(
+            addr as *mut u8,
+            out.rdx as _,
+        ))
+    }
+
+    pub unsafe fn unmap(self) -> Result<(), Error> {
+        Error::from_status(SystemCall::ShmUnmap.invoke(self.0, 0, 0, 0).rax).map(|_| ())
+    }
+}
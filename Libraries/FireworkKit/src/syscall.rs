@@ -10,6 +10,18 @@ pub enum AccessSize {
     DWord,
 }
 
+// Legacy lines are ISA IRQ numbers translated through the interrupt source overrides, the rest are GSIs.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, TryFromPrimitive)]
+#[repr(u64)]
+pub enum IRQMode {
+    #[default]
+    Legacy,
+    EdgeHigh,
+    EdgeLow,
+    LevelHigh,
+    LevelLow,
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, TryFromPrimitive)]
 #[repr(u64)]
 pub enum SystemCall {
@@ -27,24 +39,167 @@ pub enum SystemCall {
     NewOSDTEntry,
     GetOSDTEntryInfo,
     SetOSDTEntryProp,
+    SetPriority,
+    ThreadSpawn,
+    ThreadJoin,
+    MsgRecvTimeout,
+    MsgTryRecv,
+    Sleep,
+    GetTime,
+    MsgCall,
+    MsgReply,
+    EndpointCreate,
+    EndpointLookup,
+    ShmCreate,
+    ShmMap,
+    ShmUnmap,
+    ShmShare,
+    GetDMAAddress,
+    AllocDMA,
+    MapMMIO,
+    RegisterMSI,
+    Power,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, TryFromPrimitive)]
+#[repr(u64)]
+pub enum PowerAction {
+    Shutdown,
+    Reboot,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, TryFromPrimitive)]
+#[repr(u64)]
+pub enum ThreadPriority {
+    High,
+    #[default]
+    Normal,
+    Low,
 }
 
+#[cfg(feature = "userspace")]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemCallOutput {
+    pub rax: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+}
+
+// 0 means not probed yet, 1 means int 249 only, 2 means SYSCALL is usable.
+#[cfg(feature = "userspace")]
+static FAST_PATH: core::sync::atomic::AtomicU8 = core::sync::atomic::AtomicU8::new(0);
+
 #[cfg(feature = "userspace")]
 impl SystemCall {
+    unsafe fn has_fast_path() -> bool {
+        use core::sync::atomic::Ordering;
+
+        match FAST_PATH.load(Ordering::Relaxed) {
+            0 => {
+                let supported = core::arch::x86_64::__cpuid(0x8000_0001).edx & (1 << 11) != 0;
+                FAST_PATH.store(if supported { 2 } else { 1 }, Ordering::Relaxed);
+                supported
+            }
+            v => v == 2,
+        }
+    }
+
+    pub unsafe fn invoke(self, rsi: u64, rdx: u64, rcx: u64, r8: u64) -> SystemCallOutput {
+        let mut out = SystemCallOutput::default();
+        if Self::has_fast_path() {
+            // SYSCALL uses RCX and R11 for the return address and flags, the fourth argument goes in R10.
+            core::arch::asm!(
+                "syscall",
+                inout("rdi") self as u64 => out.rdi,
+                inout("rsi") rsi => out.rsi,
+                inout("rdx") rdx => out.rdx,
+                inout("r10") rcx => out.rcx,
+                in("r8") r8,
+                lateout("rax") out.rax,
+                lateout("rcx") _,
+                lateout("r11") _,
+                options(nostack),
+            );
+        } else {
+            core::arch::asm!(
+                "int 249",
+                inout("rdi") self as u64 => out.rdi,
+                inout("rsi") rsi => out.rsi,
+                inout("rdx") rdx => out.rdx,
+                inout("rcx") rcx => out.rcx,
+                in("r8") r8,
+                lateout("rax") out.rax,
+                options(nostack),
+            );
+        }
+        out
+    }
+
     pub unsafe fn quit() -> ! {
-        core::arch::asm!("int 249", in("rdi") Self::Quit as u64, options(nostack, noreturn));
+        Self::Quit.invoke(0, 0, 0, 0);
+        unreachable!();
     }
 
     pub unsafe fn r#yield() {
-        core::arch::asm!("int 249", in("rdi") Self::Yield as u64, options(nostack));
+        Self::Yield.invoke(0, 0, 0, 0);
+    }
+
+    pub unsafe fn sleep(ns: u64) {
+        Self::Sleep.invoke(ns, 0, 0, 0);
+    }
+
+    #[must_use]
+    pub unsafe fn get_time() -> u64 {
+        Self::GetTime.invoke(0, 0, 0, 0).rax
+    }
+
+    pub unsafe fn set_priority(priority: ThreadPriority) {
+        Self::SetPriority.invoke(priority as u64, 0, 0, 0);
+    }
+
+    pub unsafe fn thread_spawn(entry: u64, arg: u64) -> Result<u64, crate::Error> {
+        crate::Error::from_status(Self::ThreadSpawn.invoke(entry, arg, 0, 0).rax)
+    }
+
+    pub unsafe fn thread_join(tid: u64) -> Result<(), crate::Error> {
+        crate::Error::from_status(Self::ThreadJoin.invoke(tid, 0, 0, 0).rax).map(|_| ())
+    }
+
+    pub unsafe fn get_dma_address(addr: u64, size: u64) -> Result<u64, crate::Error> {
+        crate::Error::from_status(Self::GetDMAAddress.invoke(addr, size, 0, 0).rax)
+    }
+
+    // The physical range has to lie within one published by the bus driver for our device.
+    pub unsafe fn map_mmio(phys: u64, size: u64) -> Result<*mut u8, crate::Error> {
+        crate::Error::from_status(Self::MapMMIO.invoke(phys, size, 0, 0).rax).map(|v| v as *mut u8)
+    }
+
+    pub unsafe fn unmap_mmio(addr: *mut u8, size: u64) -> Result<(), crate::Error> {
+        let offset = addr as u64 & 0xFFF;
+        let status = Self::Free.invoke(addr as u64 - offset, size + offset, 0, 0);
+        crate::Error::from_status(status.rax).map(|_| ())
+    }
+
+    pub unsafe fn register_irq_handler(irq: u8) -> Result<(), crate::Error> {
+        Self::register_gsi_handler(irq.into(), IRQMode::Legacy).map(|_| ())
+    }
+
+    // The returned vector is what `KernelMessage::IRQFired` reports for the line.
+    pub unsafe fn register_gsi_handler(gsi: u32, mode: IRQMode) -> Result<u8, crate::Error> {
+        let status = Self::RegisterIRQ.invoke(gsi.into(), mode as u64, 0, 0);
+        crate::Error::from_status(status.rax).map(|v| v as u8)
+    }
+
+    // Returns the message address and data to program into the device's MSI or MSI-X capability.
+    pub unsafe fn register_msi_handler() -> Result<(u64, u32), crate::Error> {
+        let out = Self::RegisterMSI.invoke(0, 0, 0, 0);
+        crate::Error::from_status(out.rax).map(|addr| (addr, out.rdi as u32))
     }
 
-    pub unsafe fn register_irq_handler(irq: u8) {
-        core::arch::asm!(
-            "int 249",
-            in("rdi") Self::RegisterIRQ as u64,
-            in("sil") irq,
-            options(nostack),
-        );
+    // Only returns if the firmware failed to carry out the action.
+    pub unsafe fn power(action: PowerAction) -> Result<(), crate::Error> {
+        crate::Error::from_status(Self::Power.invoke(action as u64, 0, 0, 0).rax).map(|_| ())
     }
 }
@@ -9,15 +9,9 @@ struct Allocator;
 
 unsafe impl core::alloc::GlobalAlloc for Allocator {
     unsafe fn alloc(&self, layout: core::alloc::Layout) -> *mut u8 {
-        let mut ptr: u64;
-        core::arch::asm!(
-            "int 249",
-            in("rdi") SystemCall::Allocate as u64,
-            in("rsi") layout.pad_to_align().size() as u64,
-            out("rax") ptr,
-            options(nostack),
-        );
-        ptr as *mut u8
+        SystemCall::Allocate
+            .invoke(layout.pad_to_align().size() as u64, 0, 0, 0)
+            .rax as *mut u8
     }
 
     unsafe fn alloc_zeroed(&self, layout: core::alloc::Layout) -> *mut u8 {
@@ -25,13 +19,7 @@ unsafe impl core::alloc::GlobalAlloc for Allocator {
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, layout: core::alloc::Layout) {
-        core::arch::asm!(
-            "int 249",
-            in("rdi") SystemCall::Free as u64,
-            in("rsi") ptr as u64,
-            in("rdx") layout.pad_to_align().size() as u64,
-            options(nostack),
-        );
+        SystemCall::Free.invoke(ptr as u64, layout.pad_to_align().size() as u64, 0, 0);
     }
 }
 
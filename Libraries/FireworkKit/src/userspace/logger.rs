@@ -13,13 +13,7 @@ impl Write for KWriter {
         }
 
         unsafe {
-            core::arch::asm!(
-                "int 249",
-                in("rdi") SystemCall::KPrint as u64,
-                in("rsi") s.as_ptr() as u64,
-                in("rdx") s.len() as u64,
-                options(nostack),
-            );
+            SystemCall::KPrint.invoke(s.as_ptr() as u64, s.len() as u64, 0, 0);
         }
         Ok(())
     }
@@ -2,68 +2,30 @@
 
 use crate::syscall::{AccessSize, SystemCall};
 
-macro_rules! PortIOSystemCallIn {
-    ($out:tt, $port:expr, $size:expr) => {{
-        let mut val: Self;
-        core::arch::asm!(
-            "int 249",
-            in("rdi") SystemCall::PortIn as u64,
-            in("rsi") $port,
-            in("rdx") $size as u64,
-            out($out) val,
-            options(nostack),
-        );
-        val
-    }};
-}
-
-macro_rules! PortIOSystemCallOut {
-    ($in_:tt, $port:expr, $value:expr, $size:expr) => {
-        core::arch::asm!(
-            "int 249",
-            in("rdi") SystemCall::PortOut as u64,
-            in("rsi") $port,
-            in("rdx") $size as u64,
-            in($in_) $value,
-            options(nostack),
-        )
-    };
-}
-
 pub trait PortIO: Sized {
     unsafe fn read(port: u16) -> Self;
     unsafe fn write(port: u16, value: Self);
 }
 
-impl PortIO for u8 {
-    unsafe fn read(port: u16) -> Self {
-        PortIOSystemCallIn!("al", port, AccessSize::Byte)
-    }
-
-    unsafe fn write(port: u16, value: Self) {
-        PortIOSystemCallOut!("cl", port, value, AccessSize::Byte);
-    }
-}
-
-impl PortIO for u16 {
-    unsafe fn read(port: u16) -> Self {
-        PortIOSystemCallIn!("ax", port, AccessSize::Word)
-    }
-
-    unsafe fn write(port: u16, value: Self) {
-        PortIOSystemCallOut!("cx", port, value, AccessSize::Word);
-    }
+macro_rules! impl_port_io {
+    ($ty:ty, $size:expr) => {
+        impl PortIO for $ty {
+            unsafe fn read(port: u16) -> Self {
+                SystemCall::PortIn
+                    .invoke(port.into(), $size as u64, 0, 0)
+                    .rax as Self
+            }
+
+            unsafe fn write(port: u16, value: Self) {
+                SystemCall::PortOut.invoke(port.into(), $size as u64, value.into(), 0);
+            }
+        }
+    };
 }
 
-impl PortIO for u32 {
-    unsafe fn read(port: u16) -> Self {
-        PortIOSystemCallIn!("eax", port, AccessSize::DWord)
-    }
-
-    unsafe fn write(port: u16, value: Self) {
-        PortIOSystemCallOut!("ecx", port, value, AccessSize::DWord);
-    }
-}
+impl_port_io!(u8, AccessSize::Byte);
+impl_port_io!(u16, AccessSize::Word);
+impl_port_io!(u32, AccessSize::DWord);
 
 #[derive(Clone, Copy)]
 pub struct Port<T: PortIO, R: From<T> + Into<T>> {
@@ -0,0 +1,82 @@
+// Copyright (c) ChefKiss Inc 2021-2023. Licensed under the Thou Shalt Not Profit License version 1.5. See LICENSE for details.
+
+use alloc::boxed::Box;
+use core::time::Duration;
+
+use crate::{syscall::SystemCall, ThreadControlBlock};
+
+type ThreadMain = Box<dyn FnOnce() + Send>;
+
+#[derive(Debug)]
+pub struct JoinHandle(u64);
+
+impl JoinHandle {
+    #[inline]
+    #[must_use]
+    pub const fn tid(&self) -> u64 {
+        self.0
+    }
+
+    pub fn join(self) {
+        unsafe { SystemCall::thread_join(self.0).unwrap() }
+    }
+}
+
+extern "C" fn thread_start(arg: u64) -> ! {
+    let f = unsafe { Box::from_raw(arg as *mut ThreadMain) };
+    f();
+    exit()
+}
+
+pub fn spawn<F: FnOnce() + Send + 'static>(f: F) -> JoinHandle {
+    let f: Box<ThreadMain> = Box::new(Box::new(f));
+    let arg = Box::into_raw(f) as u64;
+    JoinHandle(unsafe { SystemCall::thread_spawn(thread_start as usize as u64, arg).unwrap() })
+}
+
+pub fn exit() -> ! {
+    unsafe { SystemCall::quit() }
+}
+
+pub fn sleep(duration: Duration) {
+    unsafe { SystemCall::sleep(duration.as_nanos().try_into().unwrap_or(u64::MAX)) }
+}
+
+#[must_use]
+pub fn current_tid() -> u64 {
+    let tid: u64;
+    unsafe {
+        core::arch::asm!(
+            "mov {}, fs:[{}]",
+            out(reg) tid,
+            const core::mem::offset_of!(ThreadControlBlock, tid),
+            options(nostack, readonly, preserves_flags),
+        );
+    }
+    tid
+}
+
+#[must_use]
+pub fn tls() -> *mut () {
+    let tls: u64;
+    unsafe {
+        core::arch::asm!(
+            "mov {}, fs:[{}]",
+            out(reg) tls,
+            const core::mem::offset_of!(ThreadControlBlock, tls),
+            options(nostack, readonly, preserves_flags),
+        );
+    }
+    tls as _
+}
+
+pub fn set_tls(tls: *mut ()) {
+    unsafe {
+        core::arch::asm!(
+            "mov fs:[{}], {}",
+            const core::mem::offset_of!(ThreadControlBlock, tls),
+            in(reg) tls as u64,
+            options(nostack, preserves_flags),
+        );
+    }
+}